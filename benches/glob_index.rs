@@ -0,0 +1,83 @@
+//! Matches build events against many glob subscriptions, with the index and with
+//! a linear scan over all patterns. Run with "cargo bench --bench glob_index".
+
+// Only the matching is measured, its tests don't run without the test harness
+#[allow(dead_code, unused_imports)]
+#[path = "../src/glob.rs"]
+mod glob;
+
+use glob::{glob_match, GlobIndex};
+use std::hint::black_box;
+use std::time::Instant;
+
+const PATTERNS: usize = 10_000;
+// Roughly an hour of build events of a busy OBS instance
+const EVENTS: usize = 100_000;
+
+fn patterns() -> Vec<String> {
+    let mut res = Vec::new();
+    for x in 0..PATTERNS {
+        res.push(match x % 4 {
+            0 => format!("home:user{}:*/python-*", x),
+            1 => format!("home:user{}/*", x),
+            2 => format!("devel:languages:lang{}/*-devel", x),
+            _ => format!("openSUSE:Factory/pkg{}-*", x),
+        });
+    }
+    res
+}
+
+fn events() -> Vec<String> {
+    let mut res = Vec::new();
+    for x in 0..EVENTS {
+        res.push(match x % 3 {
+            0 => format!("openSUSE:Factory/pkg{}-{}", x % 20_000, x),
+            1 => format!("home:user{}:branches/python-foo", x % 20_000),
+            _ => format!("devel:languages:lang{}/bar-devel", x % 20_000),
+        });
+    }
+    res
+}
+
+fn main() {
+    let patterns = patterns();
+    let events = events();
+
+    let mut index = GlobIndex::default();
+    for pattern in &patterns {
+        index.insert(pattern, pattern.clone());
+    }
+
+    let start = Instant::now();
+    let mut found = 0;
+    for event in &events {
+        found += black_box(index.matches(event)).len();
+    }
+    let indexed = start.elapsed();
+
+    let start = Instant::now();
+    let mut found_scan = 0;
+    for event in &events {
+        found_scan += patterns
+            .iter()
+            .filter(|x| glob_match(black_box(x), event))
+            .count();
+    }
+    let scanned = start.elapsed();
+
+    assert_eq!(found, found_scan);
+    println!(
+        "{} events against {} patterns, {} matches",
+        EVENTS, PATTERNS, found
+    );
+    println!(
+        "index:  {:?} ({:?} per event)",
+        indexed,
+        indexed / EVENTS as u32
+    );
+    println!(
+        "linear: {:?} ({:?} per event)",
+        scanned,
+        scanned / EVENTS as u32
+    );
+}
@@ -0,0 +1,196 @@
+use crate::common::{Registry, SubscriptionInfo};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use std::io::Read;
+
+const SUBSCRIPTIONS_PATH: &str = "/api/v1/subscriptions";
+// Requests to the API are tiny, anything larger is rejected
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiDetails {
+    pub listen: String,
+    pub tokens: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubscriptionRequest {
+    room: String,
+    url: String,
+}
+
+/// Token-authenticated REST API to manage subscriptions from scripts and CI jobs.
+/// Operates on the same subscriptions as the chat commands do.
+pub struct Api {
+    details: ApiDetails,
+    registry: Registry,
+}
+
+type ApiResult = Result<(u16, serde_json::Value), (u16, String)>;
+
+// Compares in constant time, to not leak how much of a token was guessed correctly
+fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+impl Api {
+    pub fn new(details: ApiDetails, registry: Registry) -> Api {
+        Api { details, registry }
+    }
+
+    /// Binds the listener and handles requests in a separate thread
+    pub fn run(self) -> Result<()> {
+        if self.details.tokens.is_empty() {
+            return Err(anyhow!("API configured without any tokens"));
+        }
+        let server = Server::http(&self.details.listen)
+            .map_err(|x| anyhow!("Could not listen on {}: {}", self.details.listen, x))?;
+        println!("API LISTENING ON http://{}", self.details.listen);
+
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                self.handle_request(request);
+            }
+        });
+        Ok(())
+    }
+
+    fn handle_request(&self, mut request: Request) {
+        let (status, answer) = match self.process(&mut request) {
+            Ok(x) => x,
+            Err((status, error)) => {
+                println!(
+                    "API request {} {} failed: {} {}",
+                    request.method(),
+                    request.url(),
+                    status,
+                    error
+                );
+                (status, json!({ "error": error }))
+            }
+        };
+
+        // Static header, can't fail
+        let header = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+        let response = Response::from_string(answer.to_string())
+            .with_status_code(status)
+            .with_header(header);
+        let _ = request.respond(response);
+    }
+
+    fn is_authorized(&self, request: &Request) -> bool {
+        let given = request
+            .headers()
+            .iter()
+            .find(|x| x.field.equiv("Authorization"))
+            .and_then(|x| x.value.as_str().strip_prefix("Bearer "))
+            .map(|x| x.trim().to_string());
+
+        match given {
+            Some(given) => self.details.tokens.iter().any(|x| token_matches(x, &given)),
+            None => false,
+        }
+    }
+
+    fn process(&self, request: &mut Request) -> ApiResult {
+        if !self.is_authorized(request) {
+            return Err((401, "Missing or invalid token".to_string()));
+        }
+
+        // Only used to get the query parameters decoded
+        let url = url::Url::parse(&format!("http://localhost{}", request.url()))
+            .map_err(|x| (400, x.to_string()))?;
+        if url.path() != SUBSCRIPTIONS_PATH {
+            return Err((404, "Not found".to_string()));
+        }
+
+        match request.method() {
+            Method::Get => {
+                let param = |name: &str| {
+                    url.query_pairs()
+                        .find(|(key, _)| key == name)
+                        .map(|(_, value)| value.to_string())
+                };
+                let room =
+                    param("room").ok_or_else(|| (400, "Parameter room missing".to_string()))?;
+                self.list(&room, param("backend"), param("type"))
+            }
+            Method::Post => {
+                let body = Self::read_body(request)?;
+                self.change(&body, true)
+            }
+            Method::Delete => {
+                let body = Self::read_body(request)?;
+                self.change(&body, false)
+            }
+            _ => Err((405, "Method not supported".to_string())),
+        }
+    }
+
+    fn read_body(request: &mut Request) -> Result<SubscriptionRequest, (u16, String)> {
+        let mut body = String::new();
+        request
+            .as_reader()
+            .take(MAX_BODY_SIZE)
+            .read_to_string(&mut body)
+            .map_err(|x| (400, format!("Could not read body: {}", x)))?;
+        serde_json::from_str(&body).map_err(|x| (400, format!("Invalid request: {}", x)))
+    }
+
+    fn list(&self, room: &str, backend: Option<String>, event_type: Option<String>) -> ApiResult {
+        let subscriptions: Vec<SubscriptionInfo> = self
+            .registry
+            .with_stores(|stores| {
+                stores
+                    .iter()
+                    .filter(|x| backend.as_deref().is_none_or(|b| x.backend() == b))
+                    .filter(|x| event_type.as_deref().is_none_or(|t| x.event_type() == t))
+                    .flat_map(|x| x.list(room))
+                    .collect()
+            })
+            .ok_or_else(|| (500, "Subscriptions not lockable".to_string()))?;
+
+        Ok((200, json!({ "room": room, "subscriptions": subscriptions })))
+    }
+
+    fn change(&self, request: &SubscriptionRequest, subscribe: bool) -> ApiResult {
+        let results = self
+            .registry
+            .with_stores(|stores| {
+                stores
+                    .iter_mut()
+                    .filter(|x| x.is_url_for_me(&request.url))
+                    .map(|x| {
+                        if subscribe {
+                            x.subscribe_url(&request.url, &request.room)
+                        } else {
+                            x.unsubscribe_url(&request.url, &request.room)
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .ok_or_else(|| (500, "Subscriptions not lockable".to_string()))?;
+
+        if results.is_empty() {
+            return Err((400, format!("No backend handles {}", request.url)));
+        }
+
+        let mut messages = Vec::new();
+        for result in results {
+            messages.push(result.map_err(|x| (400, x))?);
+        }
+        println!("API: {} ({})", messages.join(", "), request.room);
+        Ok((
+            200,
+            json!({ "room": request.room, "message": messages.join(", ") }),
+        ))
+    }
+}
@@ -1,15 +1,21 @@
-use crate::common::{prepend_prefix, ConnectionDetails, MessageParseResult, Subscriber};
+use crate::command::{looks_like_url, message_text, short_package, url_segments};
+use crate::common::{
+    escape_html, prepend_prefix, Activate, ConnectionDetails, Frontends, MessageParseResult,
+    Notifier, RoomSettings, Subscriber, SubscriptionKey,
+};
+use crate::glob::{glob_match, is_pattern, GlobIndex};
+use crate::obs_api::ObsApi;
 use anyhow::{anyhow, Result};
 use lapin::{
     message::{Delivery, DeliveryResult},
-    options::*,
     Connection, ConsumerDelegate,
 };
 use matrix_bot_api::handlers::{HandleResult, MessageHandler};
-use matrix_bot_api::{ActiveBot, MatrixBot, Message, MessageType};
-use serde::Deserialize;
+use matrix_bot_api::{ActiveBot, Message};
+use serde::{Deserialize, Serialize};
 
 use std::collections::hash_map::HashMap;
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::sync::{Arc, Mutex};
 
@@ -21,7 +27,15 @@ pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
     let without_prefix = [
         (
             "OBS_PACKAGE_URL",
-            "Subscribe to a package. Get notification if build-status changes.",
+            "Subscribe to a package. Get notification if build-status changes. Build log and binaries URLs only notify for their repository, project URLs for all packages. Multibuild flavors are included, unless one is pinned as PACKAGE:FLAVOR.",
+        ),
+        (
+            "PROJECT/PACKAGE",
+            "Same as OBS_PACKAGE_URL, e.g. home:me/foo. Uses the backend of the room.",
+        ),
+        (
+            "PROJECT/PATTERN",
+            "Subscribe to all matching packages, e.g. home:me:*/python-*. '*' matches anything but '/', '?' a single character.",
         ),
         (
             "unsub OBS_PACKAGE_URL",
@@ -36,15 +50,132 @@ pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
     prepend_prefix(prefix, &without_prefix)
 }
 
+// Package name of subscriptions to a whole project
+const ALL_PACKAGES: &str = "*";
+
+/// A package, optionally only for one repository (and arch)
 #[derive(Debug, Clone, std::cmp::PartialEq, std::cmp::Eq, Hash)]
 pub struct PackageKey {
     pub project: String,
     pub package: String,
+    pub repository: Option<String>,
+    pub arch: Option<String>,
+}
+
+impl PackageKey {
+    fn new(project: &str, package: &str) -> PackageKey {
+        PackageKey {
+            project: project.to_string(),
+            package: package.to_string(),
+            repository: None,
+            arch: None,
+        }
+    }
+
+    fn with_filter(mut self, repository: &str, arch: Option<&str>) -> PackageKey {
+        self.repository = Some(repository.to_string());
+        self.arch = arch.map(|x| x.to_string());
+        self
+    }
+
+    /// All keys that are interested in a build result, except patterns
+    fn matching(jsondata: &BuildSuccessInfo) -> Vec<PackageKey> {
+        let mut packages = vec![jsondata.package.as_str()];
+        // Subscriptions to a multibuild package include all of its flavors,
+        // "package:flavor" only that one
+        let (base, flavor) = split_flavor(&jsondata.package);
+        if flavor.is_some() {
+            packages.push(base);
+        }
+
+        let mut res = Vec::new();
+        for package in packages {
+            let package = PackageKey::new(&jsondata.project, package);
+            res.push(package.clone().with_filter(&jsondata.repository, None));
+            res.push(
+                package
+                    .clone()
+                    .with_filter(&jsondata.repository, Some(&jsondata.arch)),
+            );
+            res.push(package);
+        }
+        res
+    }
+}
+
+/// Multibuild packages are reported as "package:flavor"
+fn split_flavor(package: &str) -> (&str, Option<&str>) {
+    match package.split_once(':') {
+        Some((base, flavor)) => (base, Some(flavor)),
+        None => (package, None),
+    }
 }
 
 impl std::fmt::Display for PackageKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}/{}", self.project, self.package)
+        write!(f, "{}/{}", self.project, self.package)?;
+        match (&self.repository, &self.arch) {
+            (Some(repository), Some(arch)) => write!(f, " ({}/{})", repository, arch),
+            (Some(repository), None) => write!(f, " ({})", repository),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl SubscriptionKey for PackageKey {
+    fn url(&self, base_url: &str) -> String {
+        if self.package == ALL_PACKAGES {
+            // Projects live next to the packages
+            return format!(
+                "{}/{}",
+                base_url.replace("/package/", "/project/"),
+                self.project
+            );
+        }
+        // Filtered subscriptions point to what they were made from, so the URL gives the same key again
+        let package_url = base_url.trim_end_matches("/show");
+        match (&self.repository, &self.arch) {
+            (Some(repository), Some(arch)) => format!(
+                "{}/live_build_log/{}/{}/{}/{}",
+                package_url, self.project, self.package, repository, arch
+            ),
+            (Some(repository), None) => format!(
+                "{}/binaries/{}/{}/{}",
+                package_url, self.project, self.package, repository
+            ),
+            _ => format!("{}/{}/{}", base_url, self.project, self.package),
+        }
+    }
+
+    /// Whole projects are the pattern "PROJECT/*"
+    fn pattern(&self) -> Option<String> {
+        let pattern = format!("{}/{}", self.project, self.package);
+        if is_pattern(&pattern) {
+            Some(pattern)
+        } else {
+            None
+        }
+    }
+
+    /// Like the routing of build results in delivery_wrapper
+    fn receives(&self, document: &serde_json::Value) -> bool {
+        let jsondata = match BuildSuccessInfo::deserialize(&document["data"]) {
+            Ok(x) => x,
+            Err(_) => return false,
+        };
+        let pattern = match self.pattern() {
+            Some(x) => x,
+            None => return PackageKey::matching(&jsondata).contains(self),
+        };
+        let (base, _) = split_flavor(&jsondata.package);
+        [jsondata.package.as_str(), base]
+            .iter()
+            .any(|package| glob_match(&pattern, &format!("{}/{}", jsondata.project, package)))
+            && self
+                .repository
+                .as_ref()
+                .is_none_or(|repository| *repository == jsondata.repository)
+            && self.arch.as_ref().is_none_or(|arch| *arch == jsondata.arch)
     }
 }
 
@@ -57,21 +188,34 @@ impl TryFrom<String> for PackageKey {
             return Err(());
         }
 
-        let parts: Vec<_> = line.split('/').collect();
-        if parts.len() < 4 {
-            return Err(());
+        if !looks_like_url(line) {
+            let (project, package) = short_package(line).ok_or(())?;
+            return Ok(PackageKey::new(&project, &package));
         }
 
-        let mut iter = parts.iter().rev();
-        // These unwraps cannot fail, as there have to be at least 2 parts
-        let package = iter.next().unwrap().trim().to_string();
-        let project = iter.next().unwrap().trim().to_string();
-
-        Ok(PackageKey { project, package })
+        let key = match url_segments(line).as_slice() {
+            // Build logs and binaries are only about one repository (and arch)
+            ["package", "live_build_log" | "binary", project, package, repository, arch, ..] => {
+                PackageKey::new(project, package).with_filter(repository, Some(arch))
+            }
+            ["package", "binaries", project, package, repository, ..] => {
+                PackageKey::new(project, package).with_filter(repository, None)
+            }
+            // show, view_file, rdiff, revisions, requests, users, ...
+            ["package", _, project, package, ..] => PackageKey::new(project, package),
+            // show, monitor, packages, ...
+            ["project", _, project, ..] => PackageKey::new(project, ALL_PACKAGES),
+            _ => return Err(()),
+        };
+        // Patterns need a literal start, nobody wants to subscribe to everything
+        if key.project.starts_with(['*', '?']) {
+            return Err(());
+        }
+        Ok(key)
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 struct BuildSuccessInfo {
     arch: String,
     repository: String,
@@ -94,47 +238,142 @@ struct BuildSuccessInfo {
 
 impl MessageHandler for Subscriber<PackageKey> {
     /// Will be called for every text message send to a room the bot is in
-    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
-        let res = self.handle_message_helper(bot, &message.body, &message.room);
+    fn handle_message(&mut self, _bot: &ActiveBot, message: &Message) -> HandleResult {
+        let res =
+            self.handle_message_helper(&message_text(message), &message.room, &message.sender);
 
         if res == MessageParseResult::SomethingForMe {
-            match self.register() {
-                Err(x) => {
-                    println!("Error while registering: {:?}", x);
-                }
-                Ok(consumer) => consumer.set_delegate(Box::new(self.clone())),
-            }
+            self.activate();
         }
         HandleResult::ContinueHandling
     }
 }
 
+impl Activate for Subscriber<PackageKey> {
+    fn activate(&self) {
+        self.register_delegate();
+    }
+}
+
+// How much of a failed build log is fetched, and how many of its last lines are shown
+const LOG_TAIL_BYTES: u64 = 64 * 1024;
+const LOG_TAIL_LINES: usize = 20;
+
+/// The first line with "error:" and the last lines of the tail of a build log
+fn log_excerpt(log: &str, truncated: bool) -> (Option<String>, Vec<String>) {
+    let mut lines: Vec<_> = log.lines().collect();
+    // The tail starts somewhere in a line
+    if truncated && !lines.is_empty() {
+        lines.remove(0);
+    }
+    let last = &lines[lines.len().saturating_sub(LOG_TAIL_LINES)..];
+    let first_error = lines
+        .iter()
+        .find(|x| x.to_lowercase().contains("error:"))
+        .map(|x| x.to_string());
+    (first_error, last.iter().map(|x| x.to_string()).collect())
+}
+
+/// The first error and the last lines of the log of a failed build as (plain, html),
+/// sent after the failure. None if the log can't be read.
+fn log_follow_up(api: &ObsApi, jsondata: &BuildSuccessInfo, url: &str) -> Option<(String, String)> {
+    let (log, truncated) = match api.build_log_tail(
+        &jsondata.project,
+        &jsondata.repository,
+        &jsondata.arch,
+        &jsondata.package,
+        LOG_TAIL_BYTES,
+    ) {
+        Ok(x) => x,
+        Err(x) => {
+            println!("Could not get build log {}: {}", url, x);
+            return None;
+        }
+    };
+
+    let (first_error, last) = log_excerpt(&log, truncated);
+    let build = format!(
+        "{}/{} ({} / {})",
+        jsondata.project, jsondata.package, jsondata.arch, jsondata.repository
+    );
+    // IRC only gets one line
+    let line = first_error.clone().or_else(|| last.last().cloned())?;
+    let mut excerpt = Vec::new();
+    if let Some(error) = first_error.filter(|x| !last.contains(x)) {
+        excerpt.push(error);
+        excerpt.push("[...]".to_string());
+    }
+    excerpt.extend(last);
+    let plain = format!("Build log of {}: {}", build, line);
+    let html = format!(
+        "Build log of <a href=\"{}\">{}</a>:<details><summary>Excerpt</summary><pre>{}</pre></details>",
+        escape_html(url),
+        escape_html(&build),
+        escape_html(&excerpt.join("\n"))
+    );
+    Some((plain, html))
+}
+
 impl Subscriber<PackageKey> {
+    fn live_log_url(&self, jsondata: &BuildSuccessInfo) -> String {
+        format!(
+            "{}/{}/{}/{}/{}",
+            self.get_base_url().replace("/show", "/live_build_log"),
+            jsondata.project,
+            jsondata.package,
+            jsondata.repository,
+            jsondata.arch
+        )
+    }
+
     fn generate_messages(&self, jsondata: BuildSuccessInfo, changetype: &str) -> (String, String) {
+        // The package page is the same for all flavors
+        let (package, flavor) = split_flavor(&jsondata.package);
         let plain = format!(
-            "Build {}: {}/{} ({} / {})",
-            changetype, jsondata.project, jsondata.package, jsondata.arch, jsondata.repository,
+            "Build {}: {}/{}{} ({} / {})",
+            changetype,
+            jsondata.project,
+            package,
+            flavor
+                .map(|x| format!(" [flavor {}]", x))
+                .unwrap_or_default(),
+            jsondata.arch,
+            jsondata.repository,
         );
 
         let html = format!(
-            "<strong>Build {}</strong>: <a href={}>{}/{}</a> ({} / {})",
+            "<strong>Build {}</strong>: <a href={}/{}/{}>{}/{}</a>{} ({} / {})",
             if changetype == "succeeded" {
                 changetype.to_string()
             } else {
                 format!("<u>{}</u>", changetype)
             },
-            format!(
-                "{}/{}/{}",
-                self.get_base_url(),
-                jsondata.project,
-                jsondata.package,
-            ),
+            self.get_base_url(),
             jsondata.project,
-            jsondata.package,
+            package,
+            jsondata.project,
+            package,
+            flavor
+                .map(|x| format!(" [flavor <em>{}</em>]", x))
+                .unwrap_or_default(),
             jsondata.arch,
             jsondata.repository,
         );
 
+        if changetype == "failed" {
+            // The excerpt of the log follows, see log_follow_up()
+            let url = self.live_log_url(&jsondata);
+            let mut plain = format!("{}\nBuild log: {}", plain, url);
+            let mut html = format!("{}<br><a href={}>Build log</a>", html, url);
+            // Ping who is responsible for the package
+            let users = self.users();
+            let maintainers = users.maintainers(&jsondata.project, package);
+            if let Some((users_plain, users_html)) = users.mentions(&maintainers) {
+                plain += &format!("\nMaintainers: {}", users_plain);
+                html += &format!("<br>Maintainers: {}", users_html);
+            }
+            return (plain, html);
+        }
         (plain, html)
     }
 
@@ -154,19 +393,29 @@ impl Subscriber<PackageKey> {
             ));
         }
 
-        let key = PackageKey {
-            project: jsondata.project.clone(),
-            package: jsondata.package.clone(),
-        };
-        let rooms;
+        let key = PackageKey::new(&jsondata.project, &jsondata.package);
+        let mut keys = PackageKey::matching(&jsondata);
+        let (base, _) = split_flavor(&jsondata.package);
+        for package in [jsondata.package.as_str(), base] {
+            let name = format!("{}/{}", jsondata.project, package);
+            keys.extend(self.matching_patterns(&name).into_iter().filter(|x| {
+                x.repository
+                    .as_ref()
+                    .is_none_or(|repository| *repository == jsondata.repository)
+                    && x.arch.as_ref().is_none_or(|arch| *arch == jsondata.arch)
+            }));
+        }
+
+        let mut rooms = HashSet::new();
         if let Ok(subscriptions) = self.subscriptions.lock() {
-            // This is a message we are not subscribed to
-            if !subscriptions.contains_key(&key) {
-                return Ok(());
+            for key in keys {
+                if let Some(subscribed) = subscriptions.get(&key) {
+                    rooms.extend(subscribed.iter().cloned());
+                }
             }
-
-            rooms = subscriptions[&key].clone();
-        } else {
+        }
+        // This is a message we are not subscribed to
+        if rooms.is_empty() {
             return Ok(());
         }
 
@@ -175,11 +424,14 @@ impl Subscriber<PackageKey> {
             build_res, jsondata.project, jsondata.package, jsondata.arch
         );
 
-        if let Ok(bot) = self.bot.lock() {
-            let (plain, html) = self.generate_messages(jsondata, build_res);
-            for room in &rooms {
-                bot.send_html_message(&plain, &html, room, MessageType::TextMessage);
-            }
+        let document = self.event_document(delivery.routing_key.as_str(), &key, &jsondata)?;
+        let (plain, html) = self.generate_messages(jsondata.clone(), build_res);
+        for room in &rooms {
+            self.notify(room, &plain, &html, &document);
+        }
+        if let (Some(api), "failed") = (self.api.clone(), build_res) {
+            let url = self.live_log_url(&jsondata);
+            self.follow_up(&rooms, move || log_follow_up(&api, &jsondata, &url));
         }
 
         Ok(())
@@ -189,11 +441,7 @@ impl Subscriber<PackageKey> {
 impl ConsumerDelegate for Subscriber<PackageKey> {
     fn on_new_delivery(&self, delivery: DeliveryResult) {
         if let Ok(Some(delivery)) = delivery {
-            if let Some(channel) = &self.channel {
-                let _ = channel
-                    .basic_ack(delivery.delivery_tag, BasicAckOptions::default())
-                    .wait();
-            }
+            self.ack_delivery(delivery.delivery_tag);
             match self.delivery_wrapper(delivery) {
                 Ok(_) => {}
                 Err(x) => println!("Error while getting Event: {:?}. Skipping to continue", x),
@@ -207,23 +455,129 @@ impl ConsumerDelegate for Subscriber<PackageKey> {
     }
 }
 
+/// Current build results of a package (or project) URL, as (plain, html)
+pub fn status(api: &ObsApi, url: &str) -> Result<(String, String)> {
+    let key = PackageKey::try_from(url.to_string()).map_err(|_| anyhow!("could not parse it"))?;
+    if key.package == ALL_PACKAGES {
+        return project_status(api, &key);
+    }
+    if key.pattern().is_some() {
+        return Err(anyhow!("there is no status for patterns"));
+    }
+
+    let results = api.build_results(
+        &key.project,
+        &key.package,
+        key.repository.as_deref(),
+        key.arch.as_deref(),
+    )?;
+    if results.is_empty() {
+        return Err(anyhow!("there are no build results"));
+    }
+    // Only name packages, if there are multibuild flavors
+    let flavors = results.iter().any(|x| x.package != key.package);
+
+    let mut plain = vec![format!("Status of {} on {}:", key, api.backend())];
+    let mut html = format!(
+        "<strong>Status of {} on {}</strong><table><tr><th>Repository</th><th>Arch</th>{}<th>Status</th></tr>",
+        escape_html(&key.to_string()),
+        escape_html(api.backend()),
+        if flavors { "<th>Package</th>" } else { "" }
+    );
+    for result in &results {
+        let code = match &result.details {
+            Some(details) => format!("{} ({})", result.code, details),
+            None => result.code.clone(),
+        };
+        let package = if flavors {
+            format!("{} ", result.package)
+        } else {
+            String::new()
+        };
+        plain.push(format!(
+            "{}/{} {}- {}",
+            result.repository, result.arch, package, code
+        ));
+        html += &format!(
+            "<tr><td>{}</td><td>{}</td>{}<td>{}</td></tr>",
+            escape_html(&result.repository),
+            escape_html(&result.arch),
+            if flavors {
+                format!("<td>{}</td>", escape_html(&result.package))
+            } else {
+                String::new()
+            },
+            if result.code == "failed" || result.code == "unresolvable" {
+                format!("<u>{}</u>", escape_html(&code))
+            } else {
+                escape_html(&code)
+            }
+        );
+    }
+    html += "</table>";
+    Ok((plain.join("\n"), html))
+}
+
+/// Number of packages per status for each repository of a project
+fn project_status(api: &ObsApi, key: &PackageKey) -> Result<(String, String)> {
+    let summaries = api.build_summary(&key.project)?;
+    if summaries.is_empty() {
+        return Err(anyhow!("there are no build results"));
+    }
+
+    let mut plain = vec![format!("Status of {} on {}:", key.project, api.backend())];
+    let mut html = format!(
+        "<strong>Status of {} on {}</strong><table><tr><th>Repository</th><th>Arch</th><th>State</th><th>Packages</th></tr>",
+        escape_html(&key.project),
+        escape_html(api.backend())
+    );
+    for summary in &summaries {
+        let counts = summary
+            .counts
+            .iter()
+            .map(|(code, count)| format!("{} {}", count, code))
+            .collect::<Vec<_>>()
+            .join(", ");
+        plain.push(format!(
+            "{}/{} ({}) - {}",
+            summary.repository, summary.arch, summary.state, counts
+        ));
+        html += &format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape_html(&summary.repository),
+            escape_html(&summary.arch),
+            escape_html(&summary.state),
+            escape_html(&counts)
+        );
+    }
+    html += "</table>";
+    Ok((plain.join("\n"), html))
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn init(
-    bot: &mut MatrixBot,
+    frontends: &mut Frontends,
+    notifier: &Notifier,
+    rooms: &RoomSettings,
     details: &ConnectionDetails,
     conn: Connection,
+    api: Option<ObsApi>,
     prefix: Option<String>,
     default_subs: &Option<Vec<(String, String)>>,
 ) -> Result<()> {
-    let activebot = bot.get_activebot_clone();
     let mut sub: Subscriber<PackageKey> = Subscriber {
         subtype: "package".to_string(),
         server_details: *details,
-        connection: conn,
-        channel: None,
+        connection: Some(conn),
+        channel: Arc::new(Mutex::new(None)),
         subnames: SUBNAMES.to_vec(),
-        bot: Arc::new(Mutex::new(activebot)),
+        notifier: Arc::new(Mutex::new(notifier.clone())),
         subscriptions: Arc::new(Mutex::new(HashMap::new())),
+        patterns: Arc::new(Mutex::new(GlobIndex::default())),
         prefix,
+        rooms: rooms.clone(),
+        api,
+        openqa_api: None,
     };
 
     match default_subs {
@@ -233,12 +587,179 @@ pub fn init(
             Ok(consumer) => {
                 consumer.set_delegate(Box::new(sub.clone()));
                 for (room, url) in subs {
-                    sub.subscribe_to_defaults(&url, &room);
+                    sub.subscribe_to_defaults(url, room);
                 }
             }
         },
     }
-    bot.add_handler(sub);
+    frontends.add_subscriber(sub);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn package_urls() {
+        let base = "https://build.opensuse.org/package";
+        let filtered = |repository, arch| {
+            Some(PackageKey::new("home:me", "foo").with_filter(repository, arch))
+        };
+        for (url, key) in [
+            (
+                "https://build.opensuse.org/package/live_build_log/home:me/foo/openSUSE_Tumbleweed/x86_64",
+                filtered("openSUSE_Tumbleweed", Some("x86_64")),
+            ),
+            (
+                "https://build.opensuse.org/package/binary/home:me/foo/openSUSE_Tumbleweed/x86_64/foo-1.0-1.1.x86_64.rpm",
+                filtered("openSUSE_Tumbleweed", Some("x86_64")),
+            ),
+            (
+                "https://build.opensuse.org/package/binaries/home:me/foo/openSUSE_Tumbleweed",
+                filtered("openSUSE_Tumbleweed", None),
+            ),
+            (
+                "https://build.opensuse.org/package/show/home:me/foo",
+                Some(PackageKey::new("home:me", "foo")),
+            ),
+            (
+                "https://build.opensuse.org/package/view_file/home:me/foo/foo.spec?expand=1",
+                Some(PackageKey::new("home:me", "foo")),
+            ),
+            (
+                "https://build.opensuse.org/package/rdiff/home:me/foo?linkrev=base",
+                Some(PackageKey::new("home:me", "foo")),
+            ),
+            (
+                "https://build.opensuse.org/project/show/home:me",
+                Some(PackageKey::new("home:me", ALL_PACKAGES)),
+            ),
+            (
+                "https://build.opensuse.org/project/monitor/home:me?failed=1",
+                Some(PackageKey::new("home:me", ALL_PACKAGES)),
+            ),
+            ("home:me/foo", Some(PackageKey::new("home:me", "foo"))),
+            // Not enough of a package
+            ("https://build.opensuse.org/package/show/home:me", None),
+            ("https://build.opensuse.org/project/show", None),
+            ("https://build.opensuse.org/request/show/1", None),
+            // Patterns need a literal start
+            ("https://build.opensuse.org/project/show/*", None),
+        ] {
+            let parsed = PackageKey::try_from(url.to_string()).ok();
+            assert_eq!(parsed, key, "{}", url);
+            // The URL of a subscription gives it back
+            if let Some(key) = key {
+                let url = key.url(&format!("{}/show", base));
+                assert_eq!(PackageKey::try_from(url.clone()), Ok(key), "{}", url);
+            }
+        }
+    }
+
+    #[test]
+    fn multibuild_flavors() {
+        for (package, split) in [
+            ("foo", ("foo", None)),
+            ("foo:test", ("foo", Some("test"))),
+            ("foo:", ("foo", Some(""))),
+            // Only the first colon separates the flavor
+            ("foo:test:x", ("foo", Some("test:x"))),
+        ] {
+            assert_eq!(split_flavor(package), split, "{}", package);
+        }
+
+        // Pinned flavors are kept in every URL shape
+        for (url, key) in [
+            ("home:me/foo:test", PackageKey::new("home:me", "foo:test")),
+            (
+                "https://build.opensuse.org/package/show/home:me/foo:test",
+                PackageKey::new("home:me", "foo:test"),
+            ),
+            (
+                "https://build.opensuse.org/package/live_build_log/home:me/foo:docs/openSUSE_Tumbleweed/x86_64",
+                PackageKey::new("home:me", "foo:docs")
+                    .with_filter("openSUSE_Tumbleweed", Some("x86_64")),
+            ),
+        ] {
+            assert_eq!(PackageKey::try_from(url.to_string()), Ok(key), "{}", url);
+        }
+
+        // A flavor's results go to its package and to subscriptions pinning the flavor
+        let result = |package: &str| BuildSuccessInfo {
+            package: package.to_string(),
+            ..serde_json::from_value(serde_json::json!({
+                "project": "home:me",
+                "package": "",
+                "repository": "openSUSE_Tumbleweed",
+                "arch": "x86_64"
+            }))
+            .unwrap()
+        };
+        let keys = PackageKey::matching(&result("foo:test"));
+        assert!(keys.contains(&PackageKey::new("home:me", "foo")));
+        assert!(keys.contains(&PackageKey::new("home:me", "foo:test")));
+        assert!(!keys.contains(&PackageKey::new("home:me", "foo:docs")));
+        let keys = PackageKey::matching(&result("foo"));
+        assert!(keys.contains(&PackageKey::new("home:me", "foo")));
+        assert!(!keys.contains(&PackageKey::new("home:me", "foo:test")));
+    }
+
+    #[test]
+    fn excerpt_of_log_tail() {
+        let log: Vec<String> = (0..30).map(|x| format!("[  {}s] line {}", x, x)).collect();
+        let mut log = log.join("\n");
+        log.insert_str(0, "ror: cut in the middle\n");
+        log += "\n[ 31s] error: Bad exit status from /var/tmp/rpm-tmp.1 (%build)";
+
+        let (first_error, last) = log_excerpt(&log, true);
+        assert_eq!(
+            first_error.as_deref(),
+            Some("[ 31s] error: Bad exit status from /var/tmp/rpm-tmp.1 (%build)")
+        );
+        assert_eq!(last.len(), LOG_TAIL_LINES);
+        assert_eq!(last[0], "[  11s] line 11");
+
+        // The whole log starts with a whole line
+        let (first_error, _) = log_excerpt("error: first\nok", false);
+        assert_eq!(first_error.as_deref(), Some("error: first"));
+        let (first_error, last) = log_excerpt("error: first\nok", true);
+        assert_eq!(first_error, None);
+        assert_eq!(last, vec!["ok"]);
+    }
+
+    #[test]
+    fn keys_receiving_build_results() {
+        let document = serde_json::json!({
+            "event": "obs.package.build_fail",
+            "data": {
+                "project": "home:me",
+                "package": "python-foo:test",
+                "repository": "openSUSE_Tumbleweed",
+                "arch": "x86_64"
+            }
+        });
+        let package = PackageKey::new("home:me", "python-foo");
+        assert!(package.receives(&document));
+        assert!(PackageKey::new("home:me", "python-foo:test").receives(&document));
+        assert!(!PackageKey::new("home:me", "python-foo:docs").receives(&document));
+        assert!(package
+            .clone()
+            .with_filter("openSUSE_Tumbleweed", Some("x86_64"))
+            .receives(&document));
+        assert!(!package
+            .with_filter("openSUSE_Leap", None)
+            .receives(&document));
+
+        assert!(PackageKey::new("home:me", "python-*").receives(&document));
+        assert!(PackageKey::new("home:me", ALL_PACKAGES).receives(&document));
+        assert!(!PackageKey::new("home:me", ALL_PACKAGES)
+            .with_filter("openSUSE_Tumbleweed", Some("aarch64"))
+            .receives(&document));
+        assert!(!PackageKey::new("home:you", ALL_PACKAGES).receives(&document));
+
+        let request = serde_json::json!({"event": "obs.request.create", "data": {"number": 1}});
+        assert!(!PackageKey::new("home:me", ALL_PACKAGES).receives(&request));
+    }
+}
@@ -0,0 +1,1277 @@
+use crate::common::is_event_only_target;
+use crate::digest::is_digest;
+use crate::dm::{is_user_target, ME};
+use crate::export::parse_document;
+use crate::glob::is_pattern;
+use matrix_bot_api::Message;
+
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Names the bot is addressed with ("obsbot: status URL") if there is no prefix
+static NAMES: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Without a prefix, only the commands of old are understood in lines that are not addressed
+/// to the bot by one of these names. Set once at startup.
+pub fn set_names(names: Vec<String>) {
+    let _ = NAMES.set(names);
+}
+
+/// The rest of the line, if it starts with one of the names and ':' or ','
+fn strip_name(line: &str) -> Option<&str> {
+    NAMES.get()?.iter().find_map(|name| {
+        let head = line.get(..name.len())?;
+        if !head.eq_ignore_ascii_case(name) {
+            return None;
+        }
+        line[name.len()..]
+            .strip_prefix([':', ','])
+            .filter(|x| x.is_empty() || x.starts_with(char::is_whitespace))
+    })
+}
+
+/// Strips the prefix, or without one the name of the bot. None if the line is not
+/// addressed to the bot, Some(false) if that doesn't matter.
+fn strip_address<'a>(line: &'a str, prefix: Option<&str>) -> Option<(&'a str, bool)> {
+    let line = line.trim_start();
+    match prefix {
+        Some(prefix) => line.strip_prefix(prefix).map(|x| (x, true)),
+        None => Some(strip_name(line).map_or((line, false), |x| (x, true))),
+    }
+}
+
+/// Verbs understood in lines without prefix that are not addressed to the bot
+const UNADDRESSED_VERBS: [&str; 8] = [
+    "help",
+    "leave",
+    "shutdown",
+    "list",
+    "sub",
+    "subscribe",
+    "unsub",
+    "unsubscribe",
+];
+
+/// Arguments of commands that work on a single URL
+#[derive(Debug, Clone, PartialEq)]
+pub struct UrlArgs {
+    /// A URL, or a short form like "SR#1234" that needs a backend to be resolved
+    pub url: String,
+    /// Event-only target (e.g. "webhook:NAME") to use instead of the room, or "me"
+    /// for the user who said it
+    pub target: Option<String>,
+    /// Given with a verb, instead of just pasting a URL
+    pub explicit: bool,
+    /// Only for the digest of the room, not live
+    pub digest: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListArgs {
+    /// The subscription type to list (e.g. "packages"), None for all
+    pub kind: Option<String>,
+    /// Only subscriptions matching this, '*' and '?' work like in patterns
+    pub filter: Option<String>,
+    /// Page of long lists, starting with 1
+    pub page: usize,
+    pub target: Option<String>,
+}
+
+/// Arguments of commands that work on many subscriptions at once
+#[derive(Debug, Clone, PartialEq)]
+pub struct BulkArgs {
+    /// The subscription type (e.g. "packages"), None for all
+    pub kind: Option<String>,
+    /// Only subscriptions matching this, '*' and '?' work like in patterns
+    pub filter: Option<String>,
+    pub target: Option<String>,
+}
+
+/// A command the bot understands, with its arguments
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Help,
+    Leave,
+    Shutdown,
+    Subscribe(UrlArgs),
+    Unsubscribe(UrlArgs),
+    /// Unsubscribe from everything (of a type, or matching a filter), once confirmed
+    UnsubscribeAll(BulkArgs),
+    /// Carry out what was asked to be confirmed
+    Confirm,
+    List(ListArgs),
+    Webhooks,
+    WebhookLog(String),
+    /// Show or set the backend short forms are resolved with
+    Backend(Option<String>),
+    /// Show or set how the room reacts to pasted URLs
+    Mode(Option<String>),
+    /// Show, start or end muting the room, e.g. "2h" or "until 08:00"
+    Snooze(Option<String>),
+    /// Show, set or end ("off") the daily quiet hours of the room, e.g. "22:00-07:00"
+    Quiet(Option<String>),
+    /// Show or set the time zone of the room
+    Timezone(Option<String>),
+    /// Show or set the digest schedule of the room, or send it now
+    Digest(Option<String>),
+    /// Current state of a package or request
+    Status(UrlArgs),
+    /// Subscriptions of the room (or a target) as a default_subs document
+    Export(Option<String>),
+    /// Subscriptions read from a default_subs document, applied as one batch
+    Import(Vec<UrlArgs>),
+    /// Import of the document the sender attached last
+    ImportAttachment,
+    /// Show the OBS logins linked to Matrix users, or link one (login, user)
+    Link(Option<(String, String)>),
+    /// Forget the Matrix user of an OBS login
+    Unlink(String),
+    /// Use this room as direct chat for personal subscriptions
+    Dm,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandError {
+    UnknownVerb(String),
+    MissingArgument {
+        verb: &'static str,
+        usage: &'static str,
+    },
+    UnexpectedArgument {
+        verb: &'static str,
+        usage: &'static str,
+        arg: String,
+    },
+    InvalidDocument(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::UnknownVerb(verb) => {
+                write!(f, "Sorry, I don't know the command \"{}\". Try help", verb)
+            }
+            CommandError::MissingArgument { verb, usage } => {
+                write!(f, "{} needs more arguments. Usage: {}", verb, usage)
+            }
+            CommandError::UnexpectedArgument { verb, usage, arg } => {
+                write!(f, "{} does not take \"{}\". Usage: {}", verb, arg, usage)
+            }
+            CommandError::InvalidDocument(reason) => {
+                write!(f, "Sorry, I could not read the document: {}", reason)
+            }
+        }
+    }
+}
+
+const SUB_USAGE: &str = "sub [me] URL [digest|live|webhook:NAME|mailto:ADDRESS]";
+const UNSUB_USAGE: &str =
+    "unsub [me] URL|PATTERN|all [TYPE] [FILTER] [webhook:NAME|mailto:ADDRESS]";
+const LIST_USAGE: &str = "list [me] [all|TYPE] [FILTER] [page N] [webhook:NAME|mailto:ADDRESS]";
+const WEBHOOK_USAGE: &str = "webhook log NAME";
+const BACKEND_USAGE: &str = "backend [DOMAIN]";
+const TIMEZONE_USAGE: &str = "timezone [NAME]";
+const STATUS_USAGE: &str = "status URL";
+const MODE_USAGE: &str = "mode [passive|explicit|preview]";
+const EXPORT_USAGE: &str = "export [me|webhook:NAME|mailto:ADDRESS]";
+const LINK_USAGE: &str = "link [obs:LOGIN @user:server]";
+const UNLINK_USAGE: &str = "unlink obs:LOGIN";
+
+/// Rough check if a token is meant as a URL, with or without scheme
+pub fn looks_like_url(token: &str) -> bool {
+    if token.contains("://") {
+        return true;
+    }
+    match token.split_once('/') {
+        Some((host, _)) => host.contains('.') && !host.starts_with('.'),
+        None => false,
+    }
+}
+
+/// Strips what surrounds URLs in chat: Brackets, quotes, markdown links and trailing punctuation
+pub fn clean_url(token: &str) -> &str {
+    // Markdown: [text](URL)
+    let token = match token.find("](") {
+        Some(x) => &token[x + 2..],
+        None => token,
+    };
+    token
+        .trim_start_matches(|c| "(<[\"'".contains(c))
+        .trim_end_matches(|c| ")>]\"'.,;:!?".contains(c))
+}
+
+/// Path segments of a URL, without query string and fragment
+pub fn url_segments(url: &str) -> Vec<&str> {
+    let (_, path) = host_and_path(url);
+    path.split(['?', '#'])
+        .next()
+        .unwrap_or("")
+        .split('/')
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+/// Splits host and path (without leading '/') of a URL, dropping the scheme
+pub fn host_and_path(url: &str) -> (&str, &str) {
+    let without_scheme = url.split_once("://").map(|(_, x)| x).unwrap_or(url);
+    without_scheme
+        .split_once('/')
+        .unwrap_or((without_scheme, ""))
+}
+
+fn is_number(x: &str) -> bool {
+    !x.is_empty() && x.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Request short forms: "SR#1234", "rq#1234", "sr 1234" or a pasted "osc rq show 1234"
+pub fn short_request_id(arg: &str) -> Option<String> {
+    let lower = arg.to_lowercase();
+    let tokens: Vec<_> = lower.split_whitespace().collect();
+    let id = match tokens.as_slice() {
+        ["osc", "rq" | "request", "show", id] => *id,
+        ["sr" | "rq", id] => *id,
+        [single] => single
+            .strip_prefix("sr#")
+            .or_else(|| single.strip_prefix("rq#"))?,
+        _ => return None,
+    };
+    is_number(id).then(|| id.to_string())
+}
+
+/// All requests of an OBS user: "requests by LOGIN". Logins keep their case.
+pub fn short_requests_by(arg: &str) -> Option<String> {
+    match arg.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [requests, by, login]
+            if requests.eq_ignore_ascii_case("requests") && by.eq_ignore_ascii_case("by") =>
+        {
+            Some(login.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// openQA test short form: "t#1234"
+pub fn short_test_id(arg: &str) -> Option<String> {
+    let id = arg.trim().to_lowercase();
+    let id = id.strip_prefix("t#")?;
+    is_number(id).then(|| id.to_string())
+}
+
+/// Package short form: "PROJECT/PACKAGE", e.g. "home:me/foo".
+/// The project needs a ':', to not mistake "yes/no" for a package.
+pub fn short_package(arg: &str) -> Option<(String, String)> {
+    let arg = arg.trim();
+    if looks_like_url(arg) || arg.contains(char::is_whitespace) {
+        return None;
+    }
+    let (project, package) = arg.split_once('/')?;
+    // Patterns like "home:me:*/python-*" have to start with something literal
+    if !project.contains(':')
+        || project.starts_with(['*', '?'])
+        || package.is_empty()
+        || package.contains('/')
+    {
+        return None;
+    }
+    Some((project.to_string(), package.to_string()))
+}
+
+fn is_short_form(arg: &str) -> bool {
+    short_request_id(arg).is_some()
+        || short_test_id(arg).is_some()
+        || short_package(arg).is_some()
+        || short_requests_by(arg).is_some()
+}
+
+/// Short forms can span several tokens ("osc rq show 1234"). Returns how many.
+/// "requests by LOGIN" is too common in chatter to be picked up without a verb.
+fn short_form_len(tokens: &[&str], explicit: bool) -> Option<usize> {
+    (1..=tokens.len().min(4)).rev().find(|n| {
+        let arg = tokens[..*n].join(" ");
+        is_short_form(&arg) && (explicit || short_requests_by(&arg).is_none())
+    })
+}
+
+/// Subscription types can be given in singular or plural ("list packages", "list package")
+pub fn is_same_kind(kind: &str, subtype: &str) -> bool {
+    kind.trim_end_matches('s') == subtype.trim_end_matches('s')
+}
+
+/// Text of a message to parse commands from. Prefers the HTML body, as the
+/// targets of links are only found there.
+pub fn message_text(message: &Message) -> String {
+    match (&message.format, &message.formatted_body) {
+        (Some(format), Some(html)) if format == "org.matrix.custom.html" => html_to_text(html),
+        _ => message.body.clone(),
+    }
+}
+
+/// Matrix IDs of the users mentioned in a message. Their text in message_text()
+/// is just the display name.
+pub fn mentioned_users(message: &Message) -> Vec<String> {
+    let html = match (&message.format, &message.formatted_body) {
+        (Some(format), Some(html)) if format == "org.matrix.custom.html" => html,
+        _ => return Vec::new(),
+    };
+    html.split("<a ")
+        .skip(1)
+        .filter_map(|tag| html_attribute(tag, "href"))
+        .filter_map(|href| href.split("matrix.to/#/").nth(1))
+        // Some clients encode the ID
+        .map(|x| unescape_html(x).replace("%40", "@").replace("%3A", ":"))
+        .filter(|x| x.starts_with('@'))
+        .collect()
+}
+
+fn html_attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let start = tag.find(&format!("{}=", name))? + name.len() + 1;
+    let value = &tag[start..];
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let value = &value[1..];
+    value.find(quote).map(|end| &value[..end])
+}
+
+fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+/// Plain text of an HTML body. Links are replaced by their target (except for
+/// mentions, which keep their text), quoted replies are dropped.
+fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let end = match rest[start..].find('>') {
+            Some(x) => start + x,
+            None => {
+                rest = "";
+                break;
+            }
+        };
+        let tag = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .find(|x| !x.is_empty())
+            .unwrap_or("")
+            .to_lowercase();
+        let closing = tag.starts_with('/');
+        match name.as_str() {
+            "br" | "p" | "li" | "div" | "pre" | "blockquote" | "tr" if closing || name == "br" => {
+                text.push('\n')
+            }
+            "mx-reply" if !closing => {
+                rest = match rest.find("</mx-reply>") {
+                    Some(x) => &rest[x + "</mx-reply>".len()..],
+                    None => "",
+                };
+            }
+            "a" if !closing => {
+                let href = html_attribute(tag, "href")
+                    .filter(|x| looks_like_url(x) && !x.contains("matrix.to/"));
+                if let (Some(href), Some(close)) = (href, rest.find("</a>")) {
+                    text.push_str(&unescape_html(href));
+                    rest = &rest[close + "</a>".len()..];
+                }
+            }
+            _ => {}
+        }
+    }
+    text.push_str(rest);
+    unescape_html(&text)
+}
+
+/// Parses every line of a message. Lines that are not meant for the bot are skipped.
+/// Everything after "import" is the document to import, not more commands.
+pub fn parse(message: &str, prefix: Option<&str>) -> Vec<Result<Command, CommandError>> {
+    let mut res = Vec::new();
+    let mut lines = message.lines();
+    while let Some(line) = lines.next() {
+        if let Some(first) = import_document(line, prefix) {
+            let document: Vec<_> = std::iter::once(first).chain(lines).collect();
+            res.push(parse_import(&document.join("\n")));
+            break;
+        }
+        match parse_line(line, prefix) {
+            Ok(commands) => res.extend(commands.into_iter().map(Ok)),
+            Err(x) => res.push(Err(x)),
+        }
+    }
+    res
+}
+
+/// The rest of the line, if it starts with "import" (addressed to the bot)
+fn import_document<'a>(line: &'a str, prefix: Option<&str>) -> Option<&'a str> {
+    let (line, addressed) = strip_address(line, prefix)?;
+    if !addressed {
+        return None;
+    }
+    let rest = line.trim_start().strip_prefix("import")?;
+    (rest.is_empty() || rest.starts_with(char::is_whitespace)).then_some(rest)
+}
+
+/// Rooms of the document are where the subscriptions were exported from. They are
+/// imported into the room the command is given in, except for event-only targets.
+/// Without a document, the one attached last is imported.
+fn parse_import(document: &str) -> Result<Command, CommandError> {
+    if document.trim().is_empty() {
+        return Ok(Command::ImportAttachment);
+    }
+    import_args(document).map(Command::Import)
+}
+
+/// The subscriptions of a default_subs document
+pub fn import_args(document: &str) -> Result<Vec<UrlArgs>, CommandError> {
+    let subs = parse_document(document).map_err(CommandError::InvalidDocument)?;
+    Ok(subs
+        .into_iter()
+        .map(|(room, url)| UrlArgs {
+            url: clean_url(url.trim()).to_string(),
+            digest: is_digest(&room),
+            // Digests go to the importing room as well, personal subscriptions
+            // to the importing user
+            target: if is_user_target(&room) {
+                Some(ME.to_string())
+            } else {
+                Some(room).filter(|x| is_event_only_target(x) && !is_digest(x))
+            },
+            explicit: true,
+        })
+        .collect())
+}
+
+/// Returns no commands for lines that are not meant for the bot.
+/// Lines with the prefix (or without one, starting with the name of the bot) are clearly
+/// meant for us, so unknown verbs are errors. Other lines are just normal chatter, which
+/// is only searched for URLs and the commands of old, ignoring everything unparsable.
+pub fn parse_line(line: &str, prefix: Option<&str>) -> Result<Vec<Command>, CommandError> {
+    let (line, addressed) = match strip_address(line, prefix) {
+        Some(x) => x,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut tokens = line.split_whitespace();
+    let verb = match tokens.next() {
+        Some(x) => x,
+        None => return Ok(Vec::new()),
+    };
+    let args: Vec<_> = tokens.collect();
+
+    if addressed {
+        parse_command(verb, &args)
+    } else if UNADDRESSED_VERBS.contains(&verb) {
+        Ok(parse_command(verb, &args).unwrap_or_default())
+    } else {
+        Ok(pasted_urls(verb, &args, false).unwrap_or_default())
+    }
+}
+
+/// Pasting URLs (or a short form) is a shortcut for subscribing to them
+fn pasted_urls(verb: &str, args: &[&str], addressed: bool) -> Result<Vec<Command>, CommandError> {
+    let mut all = vec![verb];
+    all.extend(args);
+    let cleaned: Vec<_> = all.iter().map(|x| clean_url(x)).collect();
+    let starts_with_url = looks_like_url(cleaned[0]) || short_form_len(&cleaned, false).is_some();
+    if addressed && !starts_with_url {
+        return Err(CommandError::UnknownVerb(verb.to_string()));
+    }
+    Ok(url_args("sub", SUB_USAGE, &all, false)?
+        .into_iter()
+        .map(Command::Subscribe)
+        .collect())
+}
+
+fn parse_command(verb: &str, args: &[&str]) -> Result<Vec<Command>, CommandError> {
+    let args = args.to_vec();
+    let command = match verb {
+        "help" => no_args(Command::Help, "help", "help", &args)?,
+        "leave" => Command::Leave,
+        "shutdown" => Command::Shutdown,
+        "sub" | "subscribe" => {
+            let (args, me) = split_me(&args);
+            let (args, digest) = match args.split_last() {
+                Some((mode, rest)) if *mode == "digest" || *mode == "live" => {
+                    (rest, *mode == "digest")
+                }
+                _ => (args, false),
+            };
+            // Digests belong to rooms
+            if me && digest {
+                return Err(CommandError::UnexpectedArgument {
+                    verb: "sub",
+                    usage: SUB_USAGE,
+                    arg: "digest".to_string(),
+                });
+            }
+            return url_args("sub", SUB_USAGE, args, true)?
+                .into_iter()
+                .map(|x| {
+                    let target = me_target(x.target.clone(), me, "sub", SUB_USAGE)?;
+                    Ok(Command::Subscribe(UrlArgs {
+                        digest,
+                        target,
+                        ..x
+                    }))
+                })
+                .collect();
+        }
+        "unsub" | "unsubscribe" => {
+            let (args, me) = split_me(&args);
+            if let Some(args) = bulk_args(args)? {
+                let target = me_target(args.target.clone(), me, "unsub", UNSUB_USAGE)?;
+                return Ok(vec![Command::UnsubscribeAll(BulkArgs { target, ..args })]);
+            }
+            return url_args("unsub", UNSUB_USAGE, args, true)?
+                .into_iter()
+                .map(|x| {
+                    let target = me_target(x.target.clone(), me, "unsub", UNSUB_USAGE)?;
+                    Ok(Command::Unsubscribe(UrlArgs { target, ..x }))
+                })
+                .collect();
+        }
+        "status" => {
+            let urls = url_args("status", STATUS_USAGE, &args, true)?;
+            if let Some(target) = urls.iter().find_map(|x| x.target.clone()) {
+                return Err(CommandError::UnexpectedArgument {
+                    verb: "status",
+                    usage: STATUS_USAGE,
+                    arg: target,
+                });
+            }
+            return Ok(urls.into_iter().map(Command::Status).collect());
+        }
+        "list" => {
+            let (args, me) = split_me(&args);
+            let (args, target) = split_target(args);
+            let target = me_target(target, me, "list", LIST_USAGE)?;
+            let (args, page) = match args {
+                [rest @ .., page, n] if page.eq_ignore_ascii_case("page") => match n.parse() {
+                    Ok(n) if n > 0 => (rest, n),
+                    _ => {
+                        return Err(CommandError::UnexpectedArgument {
+                            verb: "list",
+                            usage: LIST_USAGE,
+                            arg: n.to_string(),
+                        })
+                    }
+                },
+                _ => (args, 1),
+            };
+            let (kind, filter) = match args {
+                [] => (None, None),
+                [kind] => (Some(kind), None),
+                [kind, filter] => (Some(kind), Some(filter.to_string())),
+                [_, _, arg, ..] => {
+                    return Err(CommandError::UnexpectedArgument {
+                        verb: "list",
+                        usage: LIST_USAGE,
+                        arg: arg.to_string(),
+                    })
+                }
+            };
+            Command::List(ListArgs {
+                kind: kind
+                    .filter(|x| !x.eq_ignore_ascii_case("all"))
+                    .map(|x| x.to_string()),
+                filter,
+                page,
+                target,
+            })
+        }
+        "confirm" => no_args(Command::Confirm, "confirm", "confirm", &args)?,
+        "dm" => no_args(Command::Dm, "dm", "dm", &args)?,
+        "webhooks" => no_args(Command::Webhooks, "webhooks", "webhooks", &args)?,
+        "webhook" => match args.as_slice() {
+            ["log", name] => Command::WebhookLog(name.to_string()),
+            ["log"] | [] => {
+                return Err(CommandError::MissingArgument {
+                    verb: "webhook",
+                    usage: WEBHOOK_USAGE,
+                })
+            }
+            ["log", _, arg, ..] | [arg, ..] => {
+                return Err(CommandError::UnexpectedArgument {
+                    verb: "webhook",
+                    usage: WEBHOOK_USAGE,
+                    arg: arg.to_string(),
+                })
+            }
+        },
+        "backend" => match args.as_slice() {
+            [] => Command::Backend(None),
+            [backend] => Command::Backend(Some(backend.to_string())),
+            [_, arg, ..] => {
+                return Err(CommandError::UnexpectedArgument {
+                    verb: "backend",
+                    usage: BACKEND_USAGE,
+                    arg: arg.to_string(),
+                })
+            }
+        },
+        "export" => match split_target(&args) {
+            ([me], None) if *me == ME => Command::Export(Some(ME.to_string())),
+            ([], target) => Command::Export(target),
+            ([arg, ..], _) => {
+                return Err(CommandError::UnexpectedArgument {
+                    verb: "export",
+                    usage: EXPORT_USAGE,
+                    arg: arg.to_string(),
+                })
+            }
+        },
+        // Single line, multi-line documents are handled by parse()
+        "import" => parse_import(&args.join(" "))?,
+        "digest" => match args.as_slice() {
+            [] => Command::Digest(None),
+            args => Command::Digest(Some(args.join(" "))),
+        },
+        "snooze" => match args.as_slice() {
+            [] => Command::Snooze(None),
+            args => Command::Snooze(Some(args.join(" "))),
+        },
+        "quiet" => match args.as_slice() {
+            [] => Command::Quiet(None),
+            args => Command::Quiet(Some(args.join(" "))),
+        },
+        "timezone" => match args.as_slice() {
+            [] => Command::Timezone(None),
+            [timezone] => Command::Timezone(Some(timezone.to_string())),
+            [_, arg, ..] => {
+                return Err(CommandError::UnexpectedArgument {
+                    verb: "timezone",
+                    usage: TIMEZONE_USAGE,
+                    arg: arg.to_string(),
+                })
+            }
+        },
+        // The user may be a mention, whose text is the display name
+        "link" => match args.as_slice() {
+            [] => Command::Link(None),
+            [login, user @ ..] => match login.strip_prefix("obs:") {
+                Some(login) if !login.is_empty() && !user.is_empty() => {
+                    Command::Link(Some((login.to_string(), user.join(" "))))
+                }
+                Some(login) if !login.is_empty() => {
+                    return Err(CommandError::MissingArgument {
+                        verb: "link",
+                        usage: LINK_USAGE,
+                    })
+                }
+                _ => {
+                    return Err(CommandError::UnexpectedArgument {
+                        verb: "link",
+                        usage: LINK_USAGE,
+                        arg: login.to_string(),
+                    })
+                }
+            },
+        },
+        "unlink" => match args.as_slice() {
+            [] => {
+                return Err(CommandError::MissingArgument {
+                    verb: "unlink",
+                    usage: UNLINK_USAGE,
+                })
+            }
+            [login] => match login.strip_prefix("obs:").filter(|x| !x.is_empty()) {
+                Some(login) => Command::Unlink(login.to_string()),
+                None => {
+                    return Err(CommandError::UnexpectedArgument {
+                        verb: "unlink",
+                        usage: UNLINK_USAGE,
+                        arg: login.to_string(),
+                    })
+                }
+            },
+            [_, arg, ..] => {
+                return Err(CommandError::UnexpectedArgument {
+                    verb: "unlink",
+                    usage: UNLINK_USAGE,
+                    arg: arg.to_string(),
+                })
+            }
+        },
+        "mode" => match args.as_slice() {
+            [] => Command::Mode(None),
+            [mode] => Command::Mode(Some(mode.to_string())),
+            [_, arg, ..] => {
+                return Err(CommandError::UnexpectedArgument {
+                    verb: "mode",
+                    usage: MODE_USAGE,
+                    arg: arg.to_string(),
+                })
+            }
+        },
+        _ => return pasted_urls(verb, &args, true),
+    };
+
+    Ok(vec![command])
+}
+
+fn no_args(
+    command: Command,
+    verb: &'static str,
+    usage: &'static str,
+    args: &[&str],
+) -> Result<Command, CommandError> {
+    match args.first() {
+        None => Ok(command),
+        Some(arg) => Err(CommandError::UnexpectedArgument {
+            verb,
+            usage,
+            arg: arg.to_string(),
+        }),
+    }
+}
+
+/// Splits an optional trailing target (e.g. "webhook:NAME" or "mailto:ADDRESS") off the arguments.
+/// The command then works on that target instead of the room.
+fn split_target<'a>(args: &'a [&'a str]) -> (&'a [&'a str], Option<String>) {
+    match args.split_last() {
+        // Digests are chosen with "digest", and only for the room itself. Users only
+        // subscribe themselves, with "me".
+        Some((last, rest))
+            if is_event_only_target(last) && !is_digest(last) && !is_user_target(last) =>
+        {
+            (rest, Some(last.to_string()))
+        }
+        _ => (args, None),
+    }
+}
+
+/// Splits a leading "me" off the arguments, for commands on personal subscriptions
+fn split_me<'a>(args: &'a [&'a str]) -> (&'a [&'a str], bool) {
+    match args.split_first() {
+        Some((first, rest)) if *first == ME => (rest, true),
+        _ => (args, false),
+    }
+}
+
+/// The target of a command with a leading "me", which can't have another one
+fn me_target(
+    target: Option<String>,
+    me: bool,
+    verb: &'static str,
+    usage: &'static str,
+) -> Result<Option<String>, CommandError> {
+    match (me, target) {
+        (true, Some(arg)) => Err(CommandError::UnexpectedArgument { verb, usage, arg }),
+        (true, None) => Ok(Some(ME.to_string())),
+        (false, target) => Ok(target),
+    }
+}
+
+/// "all [TYPE] [FILTER]", or a single pattern that is no subscription itself (like "home:me*").
+/// None for everything else, which are URLs to unsubscribe from.
+fn bulk_args(args: &[&str]) -> Result<Option<BulkArgs>, CommandError> {
+    let (args, target) = split_target(args);
+    let (kind, filter) = match args {
+        [all, rest @ ..] if all.eq_ignore_ascii_case("all") => match rest {
+            [] => (None, None),
+            [kind] => (Some(kind.to_string()), None),
+            [kind, filter] => (Some(kind.to_string()), Some(filter.to_string())),
+            [_, _, arg, ..] => {
+                return Err(CommandError::UnexpectedArgument {
+                    verb: "unsub",
+                    usage: UNSUB_USAGE,
+                    arg: arg.to_string(),
+                })
+            }
+        },
+        [pattern] if is_pattern(pattern) && !looks_like_url(pattern) && !is_short_form(pattern) => {
+            (None, Some(pattern.to_string()))
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(BulkArgs {
+        kind: kind.filter(|x| !x.eq_ignore_ascii_case("all")),
+        filter,
+        target,
+    }))
+}
+
+/// Collects all URLs and short forms. Explicit commands must not have anything else.
+/// Pasted lines may come with some explanation, which is not for us. Short forms are
+/// only taken from their start, URLs from anywhere.
+fn url_args(
+    verb: &'static str,
+    usage: &'static str,
+    args: &[&str],
+    explicit: bool,
+) -> Result<Vec<UrlArgs>, CommandError> {
+    let (args, target) = split_target(args);
+    let args: Vec<_> = args.iter().map(|x| clean_url(x)).collect();
+    if args.is_empty() {
+        return Err(CommandError::MissingArgument { verb, usage });
+    }
+
+    let mut res = Vec::new();
+    let mut pos = 0;
+    while pos < args.len() {
+        let len = match short_form_len(&args[pos..], explicit) {
+            Some(len) if explicit || pos == 0 => len,
+            _ if looks_like_url(args[pos]) => 1,
+            _ if explicit => {
+                return Err(CommandError::UnexpectedArgument {
+                    verb,
+                    usage,
+                    arg: args[pos].to_string(),
+                })
+            }
+            _ => {
+                pos += 1;
+                continue;
+            }
+        };
+        res.push(UrlArgs {
+            url: args[pos..pos + len].join(" "),
+            target: target.clone(),
+            explicit,
+            digest: false,
+        });
+        pos += len;
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PREFIX: Option<&str> = Some("obsbot: ");
+
+    fn sub(url: &str, explicit: bool) -> Command {
+        Command::Subscribe(UrlArgs {
+            url: url.to_string(),
+            target: None,
+            explicit,
+            digest: false,
+        })
+    }
+
+    #[test]
+    fn prefix_is_required() {
+        assert_eq!(parse_line("help", PREFIX), Ok(vec![]));
+        assert_eq!(parse_line("obsbot: help", PREFIX), Ok(vec![Command::Help]));
+        assert_eq!(
+            parse_line("  obsbot: leave", PREFIX),
+            Ok(vec![Command::Leave])
+        );
+        assert_eq!(parse_line("obsbot: ", PREFIX), Ok(vec![]));
+    }
+
+    #[test]
+    fn unknown_verbs_with_prefix() {
+        assert_eq!(
+            parse_line("obsbot: frobnicate now", PREFIX),
+            Err(CommandError::UnknownVerb("frobnicate".to_string()))
+        );
+        assert_eq!(
+            parse_line("obsbot: help me", PREFIX),
+            Err(CommandError::UnexpectedArgument {
+                verb: "help",
+                usage: "help",
+                arg: "me".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn addressed_by_name_without_prefix() {
+        set_names(vec!["obsbot".to_string()]);
+        assert_eq!(
+            parse_line("ObsBot: mode explicit", None),
+            Ok(vec![Command::Mode(Some("explicit".to_string()))])
+        );
+        assert_eq!(
+            parse_line("obsbot, snooze", None),
+            Ok(vec![Command::Snooze(None)])
+        );
+        assert_eq!(
+            parse_line("obsbot: frobnicate", None),
+            Err(CommandError::UnknownVerb("frobnicate".to_string()))
+        );
+        // Only the name followed by ':' or ',' addresses the bot
+        assert_eq!(parse_line("obsbots: mode", None), Ok(vec![]));
+    }
+
+    #[test]
+    fn chatter_without_prefix() {
+        set_names(vec!["obsbot".to_string()]);
+        // New verbs are words in normal sentences
+        assert_eq!(parse_line("mode is broken", None), Ok(vec![]));
+        assert_eq!(parse_line("status?", None), Ok(vec![]));
+        assert_eq!(parse_line("digest please", None), Ok(vec![]));
+        // Parse errors are not answered
+        assert_eq!(parse_line("help me", None), Ok(vec![]));
+        assert_eq!(parse_line("sub to what?", None), Ok(vec![]));
+        // The commands of old still work
+        assert_eq!(parse_line("help", None), Ok(vec![Command::Help]));
+        assert_eq!(
+            parse_line("see https://build.opensuse.org/request/show/1 too", None),
+            Ok(vec![sub(
+                "https://build.opensuse.org/request/show/1",
+                false
+            )])
+        );
+        // Imports have to be addressed, the URL is just pasted then
+        assert_eq!(
+            parse("import\nhttps://build.opensuse.org/request/show/1", None),
+            vec![Ok(sub("https://build.opensuse.org/request/show/1", false))]
+        );
+    }
+
+    #[test]
+    fn pasted_urls() {
+        // Lines for the bot have to start with one
+        assert_eq!(
+            parse_line("obsbot: fixed in build.opensuse.org/request/show/1", PREFIX),
+            Err(CommandError::UnknownVerb("fixed".to_string()))
+        );
+        assert_eq!(
+            parse_line(
+                "[the request](https://build.opensuse.org/request/show/1), fixed.",
+                None
+            ),
+            Ok(vec![sub(
+                "https://build.opensuse.org/request/show/1",
+                false
+            )])
+        );
+        assert_eq!(
+            parse_line(
+                "obsbot: <https://build.opensuse.org/request/show/1> \"build.opensuse.org/request/show/2\"",
+                PREFIX
+            ),
+            Ok(vec![
+                sub("https://build.opensuse.org/request/show/1", false),
+                sub("build.opensuse.org/request/show/2", false)
+            ])
+        );
+    }
+
+    #[test]
+    fn sub_arguments() {
+        assert_eq!(
+            parse_line("obsbot: sub https://x.org/a webhook:ci", PREFIX),
+            Ok(vec![Command::Subscribe(UrlArgs {
+                url: "https://x.org/a".to_string(),
+                target: Some("webhook:ci".to_string()),
+                explicit: true,
+                digest: false,
+            })])
+        );
+        assert_eq!(
+            parse_line("obsbot: sub https://x.org/a digest", PREFIX),
+            Ok(vec![Command::Subscribe(UrlArgs {
+                url: "https://x.org/a".to_string(),
+                target: None,
+                explicit: true,
+                digest: true,
+            })])
+        );
+        assert_eq!(
+            parse_line("obsbot: sub me https://x.org/a", PREFIX),
+            Ok(vec![Command::Subscribe(UrlArgs {
+                url: "https://x.org/a".to_string(),
+                target: Some(ME.to_string()),
+                explicit: true,
+                digest: false,
+            })])
+        );
+        assert_eq!(
+            parse_line("obsbot: sub me https://x.org/a digest", PREFIX),
+            Err(CommandError::UnexpectedArgument {
+                verb: "sub",
+                usage: SUB_USAGE,
+                arg: "digest".to_string()
+            })
+        );
+        assert_eq!(
+            parse_line("obsbot: sub", PREFIX),
+            Err(CommandError::MissingArgument {
+                verb: "sub",
+                usage: SUB_USAGE
+            })
+        );
+        // Explicit commands take nothing but URLs
+        assert_eq!(
+            parse_line("obsbot: sub https://x.org/a please", PREFIX),
+            Err(CommandError::UnexpectedArgument {
+                verb: "sub",
+                usage: SUB_USAGE,
+                arg: "please".to_string()
+            })
+        );
+        // Digests and users are no targets to name
+        assert_eq!(
+            parse_line("obsbot: sub https://x.org/a user:@a:b", PREFIX),
+            Err(CommandError::UnexpectedArgument {
+                verb: "sub",
+                usage: SUB_USAGE,
+                arg: "user:@a:b".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn unsub_all_and_patterns() {
+        assert_eq!(
+            parse_line("obsbot: unsub all packages home:me*", PREFIX),
+            Ok(vec![Command::UnsubscribeAll(BulkArgs {
+                kind: Some("packages".to_string()),
+                filter: Some("home:me*".to_string()),
+                target: None,
+            })])
+        );
+        assert_eq!(
+            parse_line("obsbot: unsub home:me*", PREFIX),
+            Ok(vec![Command::UnsubscribeAll(BulkArgs {
+                kind: None,
+                filter: Some("home:me*".to_string()),
+                target: None,
+            })])
+        );
+        // A package pattern is a subscription of its own
+        assert_eq!(
+            parse_line("obsbot: unsub home:me/python-*", PREFIX),
+            Ok(vec![Command::Unsubscribe(UrlArgs {
+                url: "home:me/python-*".to_string(),
+                target: None,
+                explicit: true,
+                digest: false,
+            })])
+        );
+        assert_eq!(
+            parse_line("obsbot: unsub all a b c", PREFIX),
+            Err(CommandError::UnexpectedArgument {
+                verb: "unsub",
+                usage: UNSUB_USAGE,
+                arg: "c".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn list_arguments() {
+        assert_eq!(
+            parse_line("obsbot: list all home:* page 2 mailto:a@b.org", PREFIX),
+            Ok(vec![Command::List(ListArgs {
+                kind: None,
+                filter: Some("home:*".to_string()),
+                page: 2,
+                target: Some("mailto:a@b.org".to_string()),
+            })])
+        );
+        assert_eq!(
+            parse_line("obsbot: list packages page 0", PREFIX),
+            Err(CommandError::UnexpectedArgument {
+                verb: "list",
+                usage: LIST_USAGE,
+                arg: "0".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn commands_without_arguments() {
+        assert_eq!(
+            parse_line("obsbot: webhook log ci", PREFIX),
+            Ok(vec![Command::WebhookLog("ci".to_string())])
+        );
+        assert_eq!(
+            parse_line("obsbot: webhook", PREFIX),
+            Err(CommandError::MissingArgument {
+                verb: "webhook",
+                usage: WEBHOOK_USAGE
+            })
+        );
+        assert_eq!(
+            parse_line("obsbot: export me", PREFIX),
+            Ok(vec![Command::Export(Some(ME.to_string()))])
+        );
+        assert_eq!(
+            parse_line("obsbot: link obs:alice", PREFIX),
+            Err(CommandError::MissingArgument {
+                verb: "link",
+                usage: LINK_USAGE
+            })
+        );
+        assert_eq!(
+            parse_line("obsbot: link obs:alice Alice Doe", PREFIX),
+            Ok(vec![Command::Link(Some((
+                "alice".to_string(),
+                "Alice Doe".to_string()
+            )))])
+        );
+    }
+
+    #[test]
+    fn every_line_is_parsed() {
+        assert_eq!(
+            parse("obsbot: help\nno command\nobsbot: nope\nobsbot: dm", PREFIX),
+            vec![
+                Ok(Command::Help),
+                Err(CommandError::UnknownVerb("nope".to_string())),
+                Ok(Command::Dm)
+            ]
+        );
+    }
+
+    #[test]
+    fn import_takes_the_rest() {
+        let res = parse(
+            "obsbot: import default_subs = [\n  [\"!a:b\", \"https://x.org/a\"],\n  [\"webhook:ci\", \"https://x.org/b\"],\n]",
+            PREFIX,
+        );
+        assert_eq!(res.len(), 1);
+        match &res[0] {
+            Ok(Command::Import(subs)) => {
+                let targets: Vec<_> = subs.iter().map(|x| x.target.as_deref()).collect();
+                assert_eq!(targets, vec![None, Some("webhook:ci")]);
+            }
+            x => panic!("Not an import: {:?}", x),
+        }
+        assert!(matches!(
+            parse("obsbot: import", PREFIX).as_slice(),
+            [Ok(Command::ImportAttachment)]
+        ));
+    }
+
+    #[test]
+    fn request_short_forms() {
+        for arg in [
+            "SR#1234",
+            "rq#1234",
+            "sr 1234",
+            "RQ 1234",
+            "osc rq show 1234",
+        ] {
+            assert_eq!(short_request_id(arg), Some("1234".to_string()), "{}", arg);
+        }
+        assert_eq!(
+            short_request_id("osc request show 99"),
+            Some("99".to_string())
+        );
+        for arg in [
+            "SR#",
+            "sr#12a",
+            "sr 12 34",
+            "sr",
+            "#1234",
+            "osc rq 1234",
+            "sr -1",
+        ] {
+            assert_eq!(short_request_id(arg), None, "{}", arg);
+        }
+    }
+
+    #[test]
+    fn test_short_forms() {
+        assert_eq!(short_test_id("t#42"), Some("42".to_string()));
+        assert_eq!(short_test_id("T#42"), Some("42".to_string()));
+        assert_eq!(short_test_id("t#"), None);
+        assert_eq!(short_test_id("t#4x"), None);
+        assert_eq!(short_test_id("42"), None);
+    }
+
+    #[test]
+    fn package_short_forms() {
+        assert_eq!(
+            short_package("home:me/foo"),
+            Some(("home:me".to_string(), "foo".to_string()))
+        );
+        assert_eq!(
+            short_package("devel:languages:*/python-*"),
+            Some(("devel:languages:*".to_string(), "python-*".to_string()))
+        );
+        // Needs a ':' in the project and exactly one '/'
+        assert_eq!(short_package("yes/no"), None);
+        assert_eq!(short_package("home:me/"), None);
+        assert_eq!(short_package("home:me/foo/bar"), None);
+        assert_eq!(short_package("*:me/foo"), None);
+        assert_eq!(short_package("build.opensuse.org/package/show"), None);
+    }
+
+    #[test]
+    fn short_forms_span_tokens() {
+        assert_eq!(
+            short_form_len(&["osc", "rq", "show", "12", "x"], false),
+            Some(4)
+        );
+        assert_eq!(short_form_len(&["sr", "12"], false), Some(2));
+        assert_eq!(short_form_len(&["SR#12", "sr"], false), Some(1));
+        assert_eq!(short_form_len(&["hello", "sr#12"], false), None);
+        // Too common in chatter to be pasted
+        assert_eq!(short_form_len(&["requests", "by", "alice"], false), None);
+        assert_eq!(short_form_len(&["requests", "by", "alice"], true), Some(3));
+    }
+
+    #[test]
+    fn short_forms_in_commands() {
+        assert_eq!(
+            parse_line("obsbot: sub sr 12 t#3", PREFIX),
+            Ok(vec![
+                Command::Subscribe(UrlArgs {
+                    url: "sr 12".to_string(),
+                    target: None,
+                    explicit: true,
+                    digest: false,
+                }),
+                Command::Subscribe(UrlArgs {
+                    url: "t#3".to_string(),
+                    target: None,
+                    explicit: true,
+                    digest: false,
+                })
+            ])
+        );
+        // Pasted, only the start of the line is a short form
+        assert_eq!(
+            parse_line("obsbot: SR#12 is fixed by sr#13", PREFIX),
+            Ok(vec![sub("SR#12", false)])
+        );
+    }
+
+    #[test]
+    fn urls() {
+        assert!(looks_like_url("https://build.opensuse.org"));
+        assert!(looks_like_url("build.opensuse.org/request/show/1"));
+        assert!(!looks_like_url("home:me/foo"));
+        assert!(!looks_like_url("./foo/bar"));
+        assert_eq!(clean_url("(https://x.org/a)."), "https://x.org/a");
+        assert_eq!(clean_url("[text](https://x.org/a)"), "https://x.org/a");
+        assert_eq!(
+            url_segments("https://x.org/package/show/a/b?x=1#frag"),
+            vec!["package", "show", "a", "b"]
+        );
+        assert_eq!(url_segments("x.org"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn html_to_text_uses_link_targets() {
+        assert_eq!(
+            html_to_text("see <a href=\"https://x.org/a?b=1&amp;c=2\">this</a> &amp; that"),
+            "see https://x.org/a?b=1&c=2 & that"
+        );
+        // Mentions keep their text, other links need a URL
+        assert_eq!(
+            html_to_text("<a href=\"https://matrix.to/#/@bot:x\">obsbot</a>: help"),
+            "obsbot: help"
+        );
+        assert_eq!(html_to_text("<a href='#top'>top</a>"), "top");
+        assert_eq!(
+            html_to_text("<mx-reply><blockquote>old</blockquote></mx-reply>new<br>line<p>para</p>"),
+            "new\nlinepara\n"
+        );
+        // Broken HTML must not panic
+        assert_eq!(html_to_text("a <b"), "a ");
+    }
+}
@@ -1,8 +1,26 @@
+use crate::command::{self, host_and_path, looks_like_url, Command};
+use crate::digest::{digest_room, digest_target, is_digest, Digests};
+use crate::dm::{is_user_target, resolve_target, DirectRooms, ME};
+use crate::email::{is_email, is_valid_address, Mailer};
+use crate::export::Attachments;
+use crate::glob::GlobIndex;
+use crate::irc::{is_irc_channel, IrcClient, IrcSender};
+use crate::obs_api::ObsApi;
+use crate::openqa_api::OpenQAApi;
+use crate::reply::{Outcome, Replies};
+use crate::snooze::Mutes;
+use crate::users::UserLinks;
+use crate::webhook::{is_webhook, Webhooks};
 use anyhow::{anyhow, Result};
-use lapin::{options::*, types::FieldTable, Channel, Connection, Consumer, ExchangeKind};
-use matrix_bot_api::{ActiveBot, MessageType};
+use lapin::{
+    options::*, types::FieldTable, Channel, Connection, Consumer, ConsumerDelegate, ExchangeKind,
+};
+use matrix_bot_api::handlers::MessageHandler;
+use matrix_bot_api::{ActiveBot, MatrixBot, MessageType};
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 #[derive(Debug, Clone, Copy)]
@@ -14,26 +32,494 @@ pub struct ConnectionDetails {
     pub rabbitscope: &'static str,
 }
 
+/// Version of the JSON document sent to webhooks. Bump on incompatible changes.
+const EVENT_DOCUMENT_VERSION: u32 = 1;
+
+/// For HTML messages showing text that is not ours, like build logs
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Routes outgoing messages to the frontend a room belongs to.
+/// IRC channels start with '#' or '&', webhooks with "webhook:", mail
+/// recipients with "mailto:", digests with "digest:", users with "user:" (to their direct
+/// chat), everything else is a Matrix room-id.
+#[derive(Clone)]
+pub struct Notifier {
+    pub matrix: ActiveBot,
+    pub irc: Option<IrcSender>,
+    pub webhooks: Option<Webhooks>,
+    pub email: Option<Mailer>,
+    /// Answers to subscription commands, sent as one summary per message
+    pub replies: Replies,
+    /// Documents attached for an import, while the message is handled
+    pub attachments: Attachments,
+    /// Rooms that get their notifications later
+    pub mutes: Mutes,
+    /// Events of subscriptions that are only in the digest of a room
+    pub digests: Digests,
+    /// Matrix users to mention for OBS logins
+    pub users: UserLinks,
+    /// Where personal subscriptions are sent to
+    pub dms: DirectRooms,
+}
+
+/// Targets that only receive events, but are no rooms to chat in
+pub fn is_event_only_target(room: &str) -> bool {
+    is_webhook(room) || is_email(room) || is_digest(room) || is_user_target(room)
+}
+
+/// Targets that belong to the room which subscribed them first
+pub fn is_owned_target(target: &str) -> bool {
+    is_webhook(target) || is_email(target)
+}
+
+impl Notifier {
+    pub fn send_message(&self, msg: &str, room: &str, msgtype: MessageType) {
+        if is_event_only_target(room) {
+            println!("{} only receives events, dropping message", room);
+            return;
+        }
+
+        if !is_irc_channel(room) {
+            self.matrix.send_message(msg, room, msgtype);
+            return;
+        }
+
+        match &self.irc {
+            Some(irc) => irc.send_message(msg, room),
+            None => println!("No IRC connection, dropping message for {}", room),
+        }
+    }
+
+    pub fn send_html_message(&self, plain: &str, html: &str, room: &str, msgtype: MessageType) {
+        if is_irc_channel(room) || is_event_only_target(room) {
+            // IRC can't render HTML, so channels get the plain-text variant
+            self.send_message(plain, room, msgtype);
+        } else {
+            self.matrix.send_html_message(plain, html, room, msgtype);
+        }
+    }
+
+    /// Sends an event to a subscriber. Chat rooms and mail get the messages, webhooks the document.
+    pub fn notify(&self, room: &str, plain: &str, html: &str, document: &serde_json::Value) {
+        if is_webhook(room) {
+            match &self.webhooks {
+                Some(webhooks) => webhooks.send(room, document),
+                None => println!("No webhooks configured, dropping event for {}", room),
+            }
+        } else if is_email(room) {
+            match &self.email {
+                Some(email) => email.send(room, plain, html),
+                None => println!("No SMTP server configured, dropping event for {}", room),
+            }
+        } else if is_digest(room) {
+            self.digests.record(room, document);
+        } else if is_user_target(room) {
+            match self.dms.room(room) {
+                Some(dm) => self.notify(&dm, plain, html, document),
+                None => println!("{} has no direct chat, dropping event", room),
+            }
+        } else if !self.mutes.hold(room, plain, html) {
+            self.send_html_message(plain, html, room, MessageType::TextMessage);
+        }
+    }
+
+    /// Checks if subscriptions for this target can be delivered at all
+    pub fn is_known_target(&self, target: &str) -> bool {
+        if is_webhook(target) {
+            return self
+                .webhooks
+                .as_ref()
+                .map(|x| x.contains(target))
+                .unwrap_or(false);
+        }
+        if is_email(target) {
+            return is_valid_address(target)
+                && self
+                    .email
+                    .as_ref()
+                    .map(|x| x.allows(target))
+                    .unwrap_or(false);
+        }
+        // Users only subscribe themselves
+        if is_user_target(target) {
+            return false;
+        }
+        true
+    }
+}
+
+/// Stable JSON representation of an event, as sent to webhooks
+#[derive(Serialize)]
+struct EventDocument<'a, D: Serialize> {
+    version: u32,
+    event: &'a str,
+    backend: &'a str,
+    key: String,
+    url: String,
+    data: &'a D,
+}
+
+/// What subscriptions are made for, e.g. a package or a request
+pub trait SubscriptionKey:
+    Send + Clone + std::hash::Hash + std::cmp::Eq + core::fmt::Display + TryFrom<String>
+{
+    /// Web URL of the key, given the base URL of its subscriber
+    fn url(&self, base_url: &str) -> String {
+        format!("{}/{}", base_url, self)
+    }
+
+    /// Glob pattern, if the key is one, to match events against (see glob.rs)
+    fn pattern(&self) -> Option<String> {
+        None
+    }
+
+    /// Whether a subscription to the key gets an event, given its JSON document.
+    /// Only needed for events a digest keeps until they change (see digest.rs).
+    fn receives(&self, _document: &serde_json::Value) -> bool {
+        false
+    }
+}
+
 #[derive(Clone)]
 pub struct Subscriber<T>
 where
-    T: Send + Clone + std::hash::Hash + std::cmp::Eq + core::fmt::Display + TryFrom<String>,
+    T: SubscriptionKey,
 {
     pub server_details: ConnectionDetails,
-    pub connection: Connection,
-    pub channel: Option<Channel>,
-    pub bot: Arc<Mutex<ActiveBot>>,
+    /// None for event sources that don't come via AMQP (e.g. HTTP webhooks)
+    pub connection: Option<Connection>,
+    pub channel: Arc<Mutex<Option<Channel>>>,
+    pub notifier: Arc<Mutex<Notifier>>,
     pub subnames: Vec<&'static str>,
     pub subscriptions: Arc<Mutex<HashMap<T, HashSet<String>>>>,
+    /// Index of all subscribed keys that are patterns
+    pub patterns: Arc<Mutex<GlobIndex<T>>>,
     pub prefix: Option<String>,
     pub subtype: String,
+    pub rooms: RoomSettings,
+    /// OBS API access of the backend, if configured
+    pub api: Option<ObsApi>,
+    /// openQA API of the backend, for openQA subscribers
+    pub openqa_api: Option<OpenQAApi>,
+}
+
+/// How a room reacts to URLs pasted without "sub"
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomMode {
+    /// Subscribe to them
+    #[default]
+    Passive,
+    /// Ignore them, only "sub URL" subscribes
+    Explicit,
+    /// Answer what they are, but don't subscribe
+    Preview,
+}
+
+impl std::str::FromStr for RoomMode {
+    type Err = ();
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode.to_lowercase().as_str() {
+            "passive" => Ok(RoomMode::Passive),
+            "explicit" => Ok(RoomMode::Explicit),
+            "preview" => Ok(RoomMode::Preview),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for RoomMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoomMode::Passive => write!(f, "passive"),
+            RoomMode::Explicit => write!(f, "explicit"),
+            RoomMode::Preview => write!(f, "preview"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct RoomSetting {
+    #[serde(default)]
+    backend: Option<String>,
+    #[serde(default)]
+    mode: RoomMode,
+    /// Cron-like, when the digest is sent
+    #[serde(default)]
+    digest_schedule: Option<String>,
+    /// Webhook and mail targets this room subscribed first
+    #[serde(default)]
+    targets: Vec<String>,
+    /// Daily, as "HH:MM-HH:MM"
+    #[serde(default)]
+    quiet_hours: Option<String>,
+    /// IANA name like "Europe/Berlin", for quiet hours, snooze and the digest
+    #[serde(default)]
+    timezone: Option<String>,
+}
+
+/// Per-room settings, shared by all handlers. Changes are written to the state
+/// file, if there is one, and read back at startup.
+#[derive(Clone, Default)]
+pub struct RoomSettings {
+    /// All configured OBS backends
+    backends: Vec<String>,
+    /// Users allowed to change settings. Everybody, if empty.
+    admins: Vec<String>,
+    path: Option<PathBuf>,
+    rooms: Arc<Mutex<HashMap<String, RoomSetting>>>,
+}
+
+impl RoomSettings {
+    pub fn new(backends: Vec<String>, admins: Vec<String>) -> RoomSettings {
+        RoomSettings {
+            backends,
+            admins,
+            path: None,
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Reads the settings saved in path (if it exists yet) and saves all changes there
+    pub fn load(&mut self, path: PathBuf) -> Result<()> {
+        if path.exists() {
+            let saved: HashMap<String, RoomSetting> =
+                serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+            if let Ok(mut rooms) = self.rooms.lock() {
+                rooms.extend(saved);
+            }
+        }
+        self.path = Some(path);
+        Ok(())
+    }
+
+    fn save(&self, rooms: &HashMap<String, RoomSetting>) {
+        let path = match &self.path {
+            Some(x) => x,
+            None => return,
+        };
+        let res = serde_json::to_string_pretty(rooms)
+            .map_err(anyhow::Error::from)
+            .and_then(|x| std::fs::write(path, x).map_err(anyhow::Error::from));
+        if let Err(x) = res {
+            println!("Could not save room settings to {:?}: {}", path, x);
+        }
+    }
+
+    /// Only the configured admins, nobody if there are none
+    pub fn is_admin(&self, user: &str) -> bool {
+        self.admins.iter().any(|x| x == user)
+    }
+
+    /// Webhook and mail targets are only used from the room that subscribed them
+    /// first, or by the configured admins (nobody, if there are none). The error
+    /// is the reason, without "Sorry".
+    pub fn may_use_target(&self, room: &str, user: &str, target: &str) -> Result<(), String> {
+        if !is_owned_target(target) || self.is_admin(user) {
+            return Ok(());
+        }
+        let rooms = self
+            .rooms
+            .lock()
+            .map_err(|_| "room settings not lockable".to_string())?;
+        match rooms
+            .iter()
+            .find(|(_, x)| x.targets.iter().any(|x| x == target))
+        {
+            Some((owner, _)) if owner == room => Ok(()),
+            Some(_) => Err(format!("{} belongs to another room", target)),
+            None => Err(format!("{} belongs to no room yet", target)),
+        }
+    }
+
+    /// Like may_use_target(), but a target no room has yet is given to this one
+    pub fn claim_target(&self, room: &str, user: &str, target: &str) -> Result<(), String> {
+        if !is_owned_target(target) {
+            return Ok(());
+        }
+        if let Ok(mut rooms) = self.rooms.lock() {
+            if !rooms
+                .values()
+                .any(|x| x.targets.iter().any(|x| x == target))
+            {
+                rooms
+                    .entry(room.to_string())
+                    .or_default()
+                    .targets
+                    .push(target.to_string());
+                self.save(&rooms);
+                return Ok(());
+            }
+        }
+        self.may_use_target(room, user, target)
+    }
+
+    pub fn backends(&self) -> &[String] {
+        &self.backends
+    }
+
+    /// The backend short forms are resolved with: The one set for the room,
+    /// or the only one there is.
+    pub fn backend(&self, room: &str) -> Option<String> {
+        let set = self
+            .rooms
+            .lock()
+            .ok()
+            .and_then(|x| x.get(room).and_then(|x| x.backend.clone()));
+        match (set, self.backends.as_slice()) {
+            (Some(backend), _) => Some(backend),
+            (None, [only]) => Some(only.clone()),
+            (None, _) => None,
+        }
+    }
+
+    pub fn set_backend(&self, room: &str, backend: &str) -> Result<String, String> {
+        if !self.backends.iter().any(|x| x == backend) {
+            return Err(format!(
+                "Sorry, {} is not configured. Choose one of: {}",
+                backend,
+                self.backends.join(", ")
+            ));
+        }
+        match self.rooms.lock() {
+            Ok(mut rooms) => {
+                rooms.entry(room.to_string()).or_default().backend = Some(backend.to_string());
+                self.save(&rooms);
+                Ok(format!("Short forms in this room now use {}", backend))
+            }
+            Err(_) => Err("Sorry, room settings not lockable".to_string()),
+        }
+    }
+
+    pub fn mode(&self, room: &str) -> RoomMode {
+        self.rooms
+            .lock()
+            .ok()
+            .and_then(|x| x.get(room).map(|x| x.mode))
+            .unwrap_or_default()
+    }
+
+    pub fn digest_schedule(&self, room: &str) -> Option<String> {
+        self.rooms
+            .lock()
+            .ok()
+            .and_then(|x| x.get(room).and_then(|x| x.digest_schedule.clone()))
+    }
+
+    /// The schedule has to be valid already
+    pub fn set_digest_schedule(&self, room: &str, schedule: &str) -> Result<String, String> {
+        match self.rooms.lock() {
+            Ok(mut rooms) => {
+                rooms.entry(room.to_string()).or_default().digest_schedule =
+                    Some(schedule.to_string());
+                self.save(&rooms);
+                Ok(format!(
+                    "The digest of this room is now sent on \"{}\"",
+                    schedule
+                ))
+            }
+            Err(_) => Err("Sorry, room settings not lockable".to_string()),
+        }
+    }
+
+    pub fn quiet_hours(&self, room: &str) -> Option<String> {
+        self.rooms
+            .lock()
+            .ok()
+            .and_then(|x| x.get(room).and_then(|x| x.quiet_hours.clone()))
+    }
+
+    /// The quiet hours have to be valid already. None removes them.
+    pub fn set_quiet_hours(&self, room: &str, hours: Option<&str>) -> Result<String, String> {
+        match self.rooms.lock() {
+            Ok(mut rooms) => {
+                rooms.entry(room.to_string()).or_default().quiet_hours = hours.map(str::to_string);
+                self.save(&rooms);
+                Ok(match hours {
+                    Some(x) => format!("The quiet hours of this room are now {}", x),
+                    None => "This room has no quiet hours anymore".to_string(),
+                })
+            }
+            Err(_) => Err("Sorry, room settings not lockable".to_string()),
+        }
+    }
+
+    pub fn timezone(&self, room: &str) -> Option<String> {
+        self.rooms
+            .lock()
+            .ok()
+            .and_then(|x| x.get(room).and_then(|x| x.timezone.clone()))
+    }
+
+    /// The time zone has to be valid already
+    pub fn set_timezone(&self, room: &str, timezone: &str) -> Result<String, String> {
+        match self.rooms.lock() {
+            Ok(mut rooms) => {
+                rooms.entry(room.to_string()).or_default().timezone = Some(timezone.to_string());
+                self.save(&rooms);
+                Ok(format!("This room now uses the time zone {}", timezone))
+            }
+            Err(_) => Err("Sorry, room settings not lockable".to_string()),
+        }
+    }
+
+    pub fn set_mode(&self, room: &str, mode: &str, user: &str) -> Result<String, String> {
+        if !self.is_admin(user) {
+            return Err("Sorry, only admins can change the mode of a room".to_string());
+        }
+        let mode: RoomMode = mode.parse().map_err(|_| {
+            format!(
+                "Sorry, {} is no mode. Choose one of: passive, explicit, preview",
+                mode
+            )
+        })?;
+        match self.rooms.lock() {
+            Ok(mut rooms) => {
+                rooms.entry(room.to_string()).or_default().mode = mode;
+                self.save(&rooms);
+                Ok(format!("This room is now in {} mode", mode))
+            }
+            Err(_) => Err("Sorry, room settings not lockable".to_string()),
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum ScanLineResult {
-    NotForMe,
-    ListCommand,
-    PossiblyForMe,
+impl RoomState for RoomSettings {
+    fn purge(&self, room: &str) {
+        if let Ok(mut rooms) = self.rooms.lock() {
+            if rooms.remove(room).is_some() {
+                self.save(&rooms);
+            }
+        }
+    }
+
+    fn migrate(&self, from: &str, to: &str) {
+        if let Ok(mut rooms) = self.rooms.lock() {
+            if let Some(setting) = rooms.remove(from) {
+                match rooms.get_mut(to) {
+                    // Settings made in the new room already win, targets stay owned
+                    Some(x) => x.targets.extend(setting.targets),
+                    None => {
+                        rooms.insert(to.to_string(), setting);
+                    }
+                }
+                self.save(&rooms);
+            }
+        }
+    }
+
+    fn rooms(&self) -> Vec<String> {
+        self.rooms
+            .lock()
+            .map(|x| x.keys().cloned().collect())
+            .unwrap_or_default()
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -44,9 +530,14 @@ pub enum MessageParseResult {
 
 impl<T> Subscriber<T>
 where
-    T: Send + Clone + std::hash::Hash + std::cmp::Eq + core::fmt::Display + TryFrom<String>,
+    T: SubscriptionKey,
 {
     pub fn get_base_url(&self) -> String {
+        // Git hosting: Keys are "owner/repo" directly below the domain
+        if self.server_details.buildprefix.is_empty() {
+            return format!("https://{}", self.server_details.domain);
+        }
+
         let tail = if self.server_details.buildprefix == "openqa" {
             String::new()
         } else {
@@ -59,190 +550,409 @@ where
         )
     }
 
-    pub fn list_keys(&self, bot: &ActiveBot, room: &str) {
-        if let Ok(subscriptions) = self.subscriptions.lock() {
-            let mut found_subscriptions = Vec::new();
-
-            for (key, value) in subscriptions.iter() {
-                if value.contains(room) {
-                    found_subscriptions.push(key.clone());
-                }
+    /// Adds to the summary that is sent, once the whole message is handled
+    pub fn reply(&self, room: &str, outcome: Outcome, item: &str, reason: Option<&str>) {
+        if let Ok(notifier) = self.notifier.lock() {
+            match reason {
+                Some(reason) => notifier.replies.failed(room, item, reason),
+                None => notifier.replies.add(room, outcome, item),
             }
+        }
+    }
 
-            let (htmlanswer, plainanswer) = if found_subscriptions.is_empty() {
-                let answer = "No subscriptions found";
-                (answer.to_string(), answer.to_string())
-            } else {
-                let mut unsorted = found_subscriptions
-                    .iter()
-                    .map(|x| format!("{}", x))
-                    .collect::<Vec<_>>();
-                unsorted.sort();
+    /// Matrix users to mention in notifications
+    pub fn users(&self) -> UserLinks {
+        self.notifier
+            .lock()
+            .map(|x| x.users.clone())
+            .unwrap_or_default()
+    }
 
-                unsorted = found_subscriptions
-                    .iter()
-                    .map(|x| format!("<a href={}/{}>{}</a>", self.get_base_url(), x, x))
-                    .collect::<Vec<_>>();
-                unsorted.sort();
+    pub fn notify(&self, room: &str, plain: &str, html: &str, document: &serde_json::Value) {
+        if let Ok(notifier) = self.notifier.lock() {
+            notifier.notify(room, plain, html, document);
+        }
+    }
 
-                (unsorted.join("<br>"), unsorted.join(", "))
+    /// Sends what has to be looked up first, like a build log excerpt, after the event
+    /// in a separate thread, so a slow API doesn't hold up later events. Only chats get
+    /// it: Webhooks, mail and digests have the event already.
+    pub fn follow_up<F>(&self, rooms: &HashSet<String>, look_up: F)
+    where
+        F: FnOnce() -> Option<(String, String)> + Send + 'static,
+    {
+        let rooms: Vec<String> = rooms
+            .iter()
+            .filter(|x| !is_webhook(x) && !is_email(x) && !is_digest(x))
+            .cloned()
+            .collect();
+        if rooms.is_empty() {
+            return;
+        }
+        let notifier = self.notifier.clone();
+        std::thread::spawn(move || {
+            let (plain, html) = match look_up() {
+                Some(x) => x,
+                None => return,
             };
+            if let Ok(notifier) = notifier.lock() {
+                for room in &rooms {
+                    notifier.notify(room, &plain, &html, &serde_json::Value::Null);
+                }
+            }
+        });
+    }
 
-            let plainanswer = format!("On {}: {}", self.server_details.domain, plainanswer);
-            let htmlanswer = format!("On {}:<br>{}", self.server_details.domain, htmlanswer);
+    /// Builds the JSON document for webhooks out of the event data
+    pub fn event_document<D: Serialize>(
+        &self,
+        routing_key: &str,
+        key: &T,
+        data: &D,
+    ) -> Result<serde_json::Value> {
+        let scope = format!("{}.", self.server_details.rabbitscope);
+        let document = EventDocument {
+            version: EVENT_DOCUMENT_VERSION,
+            event: routing_key.strip_prefix(&scope).unwrap_or(routing_key),
+            backend: self.server_details.domain,
+            key: key.to_string(),
+            url: key.url(&self.get_base_url()),
+            data,
+        };
+        Ok(serde_json::to_value(document)?)
+    }
 
-            bot.send_html_message(&plainanswer, &htmlanswer, room, MessageType::TextMessage);
-        } else {
-            println!("ERROR! list_keys: subscriptions not lockable");
-            bot.send_message(
-                "Sorry, I could not list your requests, due to an internal error.",
-                room,
-                MessageType::TextMessage,
-            );
-        }
+    /// Describes the outcome of (un)subscribing a key, e.g. "Subscribing to KEY on DOMAIN"
+    pub fn describe(&self, key: &T, outcome: Result<Outcome, String>) -> Result<String, String> {
+        outcome.map(|x| x.describe(&self.item(key)))
     }
 
-    pub fn subscribe(&mut self, key: T, room: &str) -> Result<String, String> {
-        if let Ok(mut subscriptions) = self.subscriptions.lock() {
+    /// How a key is called in replies
+    fn item(&self, key: &T) -> String {
+        format!("{} on {}", key, self.server_details.domain)
+    }
+
+    /// Subscriptions are either live or in the digest of a room, subscribing in one mode
+    /// ends the other
+    pub fn subscribe(&mut self, key: T, room: &str) -> Result<Outcome, String> {
+        let other_mode = if is_digest(room) {
+            digest_room(room).to_string()
+        } else {
+            digest_target(room)
+        };
+        let (added, left_digest) = if let Ok(mut subscriptions) = self.subscriptions.lock() {
             if !subscriptions.contains_key(&key) {
                 subscriptions.insert(key.clone(), HashSet::new());
+                if let Some(pattern) = key.pattern() {
+                    if let Ok(mut patterns) = self.patterns.lock() {
+                        patterns.insert(&pattern, key.clone());
+                    }
+                }
             }
-            subscriptions
-                .get_mut(&key)
-                .unwrap() // We know its in there, we just added it above
-                .insert(room.to_string());
-
-            Ok(format!(
-                "Subscribing to {} on {}",
-                key, &self.server_details.domain
-            ))
+            let rooms = subscriptions.get_mut(&key).unwrap(); // We know its in there, we just added it above
+            let left_other_mode = rooms.remove(&other_mode);
+            (
+                rooms.insert(room.to_string()),
+                left_other_mode && !is_digest(room),
+            )
         } else {
-            Err(format!("Sorry, I could not add your request {} on {} to the subscriptions, due to an internal error ({}).",
-                key, &self.server_details.domain, "subscriptions not lockable"))
+            return Err(format!("Sorry, I could not add your request {} on {} to the subscriptions, due to an internal error ({}).",
+                key, &self.server_details.domain, "subscriptions not lockable"));
+        };
+        if left_digest {
+            self.prune_digest(&key, room);
         }
+
+        Ok(if added {
+            Outcome::Subscribed
+        } else {
+            Outcome::AlreadySubscribed
+        })
     }
 
-    pub fn unsubscribe(&mut self, key: T, room: &str) -> Result<String, String> {
-        if let Ok(mut subscriptions) = self.subscriptions.lock() {
+    /// Ends live and digest subscriptions of the room
+    pub fn unsubscribe(&mut self, key: T, room: &str) -> Result<Outcome, String> {
+        let (removed, left_digest) = if let Ok(mut subscriptions) = self.subscriptions.lock() {
             if !subscriptions.contains_key(&key) {
-                return Ok(format!("Was not subscribed to {}", key));
+                return Ok(Outcome::NotSubscribed);
             }
-            subscriptions
-                .get_mut(&key)
-                .unwrap() // We know its in there, we just checked it above
-                .remove(room);
+            let rooms = subscriptions.get_mut(&key).unwrap(); // We know its in there, we just checked it above
+            let left_room = rooms.remove(room);
+            let left_digest = rooms.remove(&digest_target(room));
 
             // Check if anybody still uses this key
             if subscriptions.get(&key).unwrap().is_empty() {
                 subscriptions.remove(&key);
+                if let Some(pattern) = key.pattern() {
+                    if let Ok(mut patterns) = self.patterns.lock() {
+                        patterns.remove(&pattern, &key);
+                    }
+                }
             }
+            (
+                left_room | left_digest,
+                left_digest || (left_room && is_digest(room)),
+            )
+        } else {
+            return Err(format!("Sorry, I could not remove your request {} on {} from the subscriptions, due to an internal error ({}).",
+                key, &self.server_details.domain, "subscriptions not lockable"));
+        };
+        if left_digest {
+            self.prune_digest(&key, digest_room(room));
+        }
 
-            Ok(format!(
-                "Unsubscribing room from {} on {}",
-                key, &self.server_details.domain
-            ))
+        Ok(if removed {
+            Outcome::Unsubscribed
         } else {
-            Err(format!("Sorry, I could not remove your request {} on {} from the subscriptions, due to an internal error ({}).",
-                key, &self.server_details.domain, "subscriptions not lockable"))
+            Outcome::NotSubscribed
+        })
+    }
+
+    /// Whether a target still gets an event of this subscriber, given its JSON document
+    pub fn receives(&self, target: &str, document: &serde_json::Value) -> bool {
+        if document["backend"] != self.server_details.domain {
+            return false;
+        }
+        match self.subscriptions.lock() {
+            Ok(subscriptions) => subscriptions
+                .iter()
+                .any(|(key, rooms)| rooms.contains(target) && key.receives(document)),
+            Err(_) => false,
+        }
+    }
+
+    /// Drops what the digest of the room kept for a key it is not subscribed to anymore,
+    /// unless another of its subscriptions gets it as well
+    fn prune_digest(&self, key: &T, room: &str) {
+        let digests = match self.notifier.lock() {
+            Ok(notifier) => notifier.digests.clone(),
+            Err(_) => return,
+        };
+        let target = digest_target(room);
+        digests.prune(room, |document| {
+            !key.receives(document) || self.receives(&target, document)
+        });
+    }
+
+    /// Removes a room from all subscriptions, e.g. after the bot left it. Returns how many it had.
+    pub fn purge(&mut self, room: &str) -> usize {
+        let mut subscriptions = match self.subscriptions.lock() {
+            Ok(x) => x,
+            Err(_) => return 0,
+        };
+        let mut purged = 0;
+        subscriptions.retain(|key, rooms| {
+            if rooms.remove(room) | rooms.remove(&digest_target(room)) {
+                purged += 1;
+            }
+            if rooms.is_empty() {
+                if let (Some(pattern), Ok(mut patterns)) = (key.pattern(), self.patterns.lock()) {
+                    patterns.remove(&pattern, key);
+                }
+                return false;
+            }
+            true
+        });
+        purged
+    }
+
+    /// Moves all subscriptions of a room to the room replacing it
+    pub fn migrate(&mut self, from: &str, to: &str) -> usize {
+        let mut subscriptions = match self.subscriptions.lock() {
+            Ok(x) => x,
+            Err(_) => return 0,
+        };
+        let mut moved = 0;
+        for rooms in subscriptions.values_mut() {
+            if rooms.remove(from) {
+                rooms.insert(to.to_string());
+                moved += 1;
+            }
+            if rooms.remove(&digest_target(from)) {
+                rooms.insert(digest_target(to));
+                moved += 1;
+            }
         }
+        moved
     }
 
-    pub fn scan_line(&self, line: &str) -> ScanLineResult {
-        let prefix = self.prefix.as_deref().unwrap_or("");
-        if !line.starts_with(prefix) {
-            return ScanLineResult::NotForMe;
+    /// All rooms (and targets) with subscriptions, digest targets as their room
+    pub fn rooms(&self) -> HashSet<String> {
+        match self.subscriptions.lock() {
+            Ok(subscriptions) => subscriptions
+                .values()
+                .flatten()
+                .map(|x| digest_room(x).to_string())
+                .collect(),
+            Err(_) => HashSet::new(),
         }
-        // Stripping away the prefix
-        let line = line[prefix.len()..].trim();
+    }
 
-        if line.starts_with(&format!("list {}", self.subtype)) {
-            return ScanLineResult::ListCommand;
+    /// Subscribed pattern keys matching the text
+    pub fn matching_patterns(&self, text: &str) -> Vec<T> {
+        match self.patterns.lock() {
+            Ok(patterns) => patterns.matches(text).into_iter().cloned().collect(),
+            Err(_) => Vec::new(),
         }
+    }
 
-        let search_url = format!("{}/{}/", self.server_details.domain, self.subtype);
-        // Check if its for me
-        if !line.contains(&search_url) {
-            return ScanLineResult::NotForMe;
+    /// Only URLs pointing to our instance are picked up. Which paths are
+    /// understood is up to the key.
+    pub fn is_url_for_me(&self, url: &str) -> bool {
+        let (host, _) = host_and_path(url);
+        let expected_host = if self.server_details.buildprefix.is_empty() {
+            self.server_details.domain.to_string()
+        } else {
+            format!(
+                "{}.{}",
+                self.server_details.buildprefix, self.server_details.domain
+            )
+        };
+        host.eq_ignore_ascii_case(&expected_host) && T::try_from(url.to_string()).is_ok()
+    }
+
+    /// URLs have to point to our instance, short forms ("SR#1234") are resolved
+    /// with the backend of the room
+    pub fn is_for_me(&self, arg: &str, room: &str) -> bool {
+        if looks_like_url(arg) {
+            return self.is_url_for_me(arg);
         }
+        T::try_from(arg.to_string()).is_ok()
+            && self.rooms.backend(room).as_deref() == Some(self.server_details.domain)
+    }
 
-        ScanLineResult::PossiblyForMe
+    pub fn is_known_target(&self, target: &str) -> bool {
+        self.notifier
+            .lock()
+            .map(|x| x.is_known_target(target))
+            .unwrap_or(false)
     }
 
+    /// Sender is who "me" stands for
     pub fn handle_message_helper(
         &mut self,
-        bot: &ActiveBot,
         message: &str,
         room: &str,
+        sender: &str,
     ) -> MessageParseResult {
         let mut res = MessageParseResult::NothingForMe;
-        for line in message.lines() {
-            match self.scan_line(line) {
-                ScanLineResult::PossiblyForMe => { /* Continue below */ }
-                ScanLineResult::NotForMe => {
+        // Parse errors are reported once by the HelpHandler, not by every subscriber
+        let attachments = self.notifier.lock().ok().map(|x| x.attachments.clone());
+        let commands = command::parse(message, self.prefix.as_deref())
+            .into_iter()
+            .flatten()
+            .flat_map(|command| match command {
+                // An import is subscribing to each of its entries
+                Command::Import(subs) => subs.into_iter().map(Command::Subscribe).collect(),
+                Command::ImportAttachment => attachments
+                    .as_ref()
+                    .and_then(|x| x.get(room).ok())
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(Command::Subscribe)
+                    .collect(),
+                x => vec![x],
+            });
+        for command in commands {
+            let (args, subscribe) = match command {
+                Command::Subscribe(args) if self.is_for_me(&args.url, room) => (args, true),
+                Command::Unsubscribe(args) if self.is_for_me(&args.url, room) => (args, false),
+                _ => continue,
+            };
+
+            // URLs pasted without "sub" are only picked up in passive mode
+            let pasted = subscribe && !args.explicit;
+            if pasted && self.rooms.mode(room) == RoomMode::Explicit {
+                continue;
+            }
+
+            if let Some(target) = &args.target {
+                if !self.is_known_target(target) {
+                    let reason = format!("{} is not configured", target);
+                    self.reply(room, Outcome::Failed, &args.url, Some(&reason));
                     continue;
                 }
-                ScanLineResult::ListCommand => {
-                    self.list_keys(bot, room);
+                let allowed = if subscribe {
+                    self.rooms.claim_target(room, sender, target)
+                } else {
+                    self.rooms.may_use_target(room, sender, target)
+                };
+                if let Err(reason) = allowed {
+                    self.reply(room, Outcome::Failed, &args.url, Some(&reason));
                     continue;
                 }
             }
+            let target = match &args.target {
+                _ if args.digest => digest_target(room),
+                Some(target) => resolve_target(target, sender),
+                None => room.to_string(),
+            };
 
-            let key = match T::try_from(line.to_string()) {
+            let key = match T::try_from(args.url.clone()) {
                 Ok(x) => x,
                 Err(_) => {
-                    println!("Message not parsable");
-                    bot.send_message(
-                        "Sorry, I could not parse that. Please post a submitrequest URL",
+                    println!("URL {} not parsable", args.url);
+                    self.reply(
                         room,
-                        MessageType::TextMessage,
+                        Outcome::Failed,
+                        &args.url,
+                        Some("I could not parse it"),
                     );
                     continue;
                 }
             };
 
-            let result = if line.starts_with("unsub") {
-                self.unsubscribe(key, room)
-            } else {
+            if pasted && self.rooms.mode(room) == RoomMode::Preview {
+                let item = format!("{}: {}", self.item(&key), key.url(&self.get_base_url()));
+                self.reply(room, Outcome::Preview, &item, None);
+                continue;
+            }
+
+            let result = if subscribe {
                 res = MessageParseResult::SomethingForMe;
-                self.subscribe(key, room)
+                self.subscribe(key.clone(), &target)
+            } else {
+                self.unsubscribe(key.clone(), &target)
             };
 
+            let item = match &args.target {
+                Some(target) if target == ME => format!("{} for you", self.item(&key)),
+                Some(target) => format!("{} for {}", self.item(&key), target),
+                None if args.digest => format!("{} in the digest", self.item(&key)),
+                None => self.item(&key),
+            };
             match result {
-                // We just send the result-message no matter Ok/Err, but this might
-                // change in the future
-                Ok(message) | Err(message) => {
+                Ok(outcome) => {
+                    println!("{}", outcome.describe(&item));
+                    self.reply(room, outcome, &item, None);
+                }
+                Err(message) => {
                     println!("{}", message);
-                    bot.send_message(&message, room, MessageType::TextMessage)
+                    self.reply(room, Outcome::Failed, &item, Some(&message));
                 }
             }
         }
+        // Sent by the ReplyHandler, together with the other subscribers' replies
         res
     }
 
     pub fn subscribe_to_defaults(&mut self, message: &str, room: &str) {
-        for line in message.lines() {
-            match self.scan_line(line) {
-                ScanLineResult::PossiblyForMe => { /* Continue below */ }
-                ScanLineResult::NotForMe | ScanLineResult::ListCommand => {
-                    continue;
-                }
-            }
+        for command in command::parse(message, None).into_iter().flatten() {
+            let args = match command {
+                Command::Subscribe(args) if self.is_for_me(&args.url, room) => args,
+                _ => continue,
+            };
 
-            let key = match T::try_from(line.to_string()) {
+            let key = match T::try_from(args.url.clone()) {
                 Ok(x) => x,
                 Err(_) => {
-                    println!("Message {} not parsable", line);
+                    println!("Message {} not parsable", args.url);
                     continue;
                 }
             };
 
-            let result = if !line.starts_with("unsub") {
-                self.subscribe(key, room)
-            } else {
-                continue;
-            };
-
-            match result {
+            let outcome = self.subscribe(key.clone(), room);
+            match self.describe(&key, outcome) {
                 // We just print the result-message no matter Ok/Err, but this might
                 // change in the future
                 Ok(message) | Err(message) => {
@@ -252,11 +962,35 @@ where
         }
     }
 
-    pub fn register(&mut self) -> Result<Consumer> {
-        if self.channel.is_some() {
+    pub fn is_registered(&self) -> bool {
+        self.channel.lock().map(|x| x.is_some()).unwrap_or(false)
+    }
+
+    pub fn ack_delivery(&self, delivery_tag: u64) {
+        if let Ok(channel) = self.channel.lock() {
+            if let Some(channel) = channel.as_ref() {
+                let _ = channel
+                    .basic_ack(delivery_tag, BasicAckOptions::default())
+                    .wait();
+            }
+        }
+    }
+
+    pub fn register(&self) -> Result<Consumer> {
+        // The channel is shared between all clones of this subscriber (e.g. the
+        // Matrix- and the IRC-handler), so we only ever register once
+        let mut registered_channel = self
+            .channel
+            .lock()
+            .map_err(|_| anyhow!("Channel not lockable"))?;
+        if registered_channel.is_some() {
             Err(anyhow!("Was already registered!"))
         } else {
-            let channel = self.connection.create_channel().wait()?;
+            let connection = self
+                .connection
+                .as_ref()
+                .ok_or_else(|| anyhow!("No AMQP connection to register with"))?;
+            let channel = connection.create_channel().wait()?;
 
             channel
                 .exchange_declare(
@@ -303,7 +1037,7 @@ where
                 self.subnames.join(", "),
                 self.server_details.domain
             );
-            self.channel = Some(channel);
+            *registered_channel = Some(channel);
             Ok(consumer)
         }
     }
@@ -321,3 +1055,394 @@ pub fn prepend_prefix(
     }
     res
 }
+
+impl<T> Subscriber<T>
+where
+    T: SubscriptionKey + 'static,
+    Subscriber<T>: ConsumerDelegate,
+{
+    /// Registers with AMQP (once), deliveries then go to a clone of this subscriber
+    pub fn register_delegate(&self) {
+        if self.is_registered() {
+            return;
+        }
+        match self.register() {
+            Err(x) => {
+                println!("Error while registering: {:?}", x);
+            }
+            Ok(consumer) => consumer.set_delegate(Box::new(self.clone())),
+        }
+    }
+}
+
+/// Subscribers only connect to their event source once somebody subscribes to something
+pub trait Activate {
+    fn activate(&self) {}
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SubscriptionInfo {
+    pub backend: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub key: String,
+    pub url: String,
+    /// Only in the digest of the room
+    pub digest: bool,
+}
+
+/// Access to the subscriptions of one Subscriber, without knowing its key type
+pub trait SubscriptionStore: Send {
+    fn backend(&self) -> &str;
+    fn event_type(&self) -> &str;
+    fn is_url_for_me(&self, url: &str) -> bool;
+    /// The URL a short form (e.g. "SR#1234") would stand for on this backend
+    fn candidate(&self, arg: &str) -> Option<String>;
+    fn list(&self, room: &str) -> Vec<SubscriptionInfo>;
+    fn subscribe_url(&mut self, url: &str, room: &str) -> Result<String, String>;
+    fn unsubscribe_url(&mut self, url: &str, room: &str) -> Result<String, String>;
+    fn purge(&mut self, room: &str) -> usize;
+    fn migrate(&mut self, from: &str, to: &str) -> usize;
+    fn rooms(&self) -> HashSet<String>;
+    fn receives(&self, target: &str, document: &serde_json::Value) -> bool;
+}
+
+/// What else is kept per room besides its subscriptions, like settings or held
+/// notifications. Registered with the Registry, which purges and migrates it
+/// together with the subscriptions.
+pub trait RoomState: Send {
+    /// Forgets everything of a room the bot can't post to anymore
+    fn purge(&self, room: &str);
+    /// Hands everything of a room over to the one replacing it (a room upgrade)
+    fn migrate(&self, from: &str, to: &str);
+    /// All rooms something is kept for
+    fn rooms(&self) -> Vec<String>;
+}
+
+impl<T> SubscriptionStore for Subscriber<T>
+where
+    T: SubscriptionKey,
+    Subscriber<T>: Activate,
+{
+    fn backend(&self) -> &str {
+        self.server_details.domain
+    }
+
+    fn event_type(&self) -> &str {
+        &self.subtype
+    }
+
+    fn is_url_for_me(&self, url: &str) -> bool {
+        Subscriber::is_url_for_me(self, url)
+    }
+
+    fn candidate(&self, arg: &str) -> Option<String> {
+        if looks_like_url(arg) {
+            return None;
+        }
+        T::try_from(arg.to_string())
+            .ok()
+            .map(|key| key.url(&self.get_base_url()))
+    }
+
+    /// Digest subscriptions of the room included
+    fn list(&self, room: &str) -> Vec<SubscriptionInfo> {
+        let digest = digest_target(room);
+        let mut res = match self.subscriptions.lock() {
+            Ok(subscriptions) => subscriptions
+                .iter()
+                .filter(|(_, rooms)| rooms.contains(room) || rooms.contains(&digest))
+                .map(|(key, rooms)| SubscriptionInfo {
+                    backend: self.server_details.domain.to_string(),
+                    event_type: self.subtype.clone(),
+                    key: key.to_string(),
+                    url: key.url(&self.get_base_url()),
+                    digest: rooms.contains(&digest),
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        res.sort_by(|a, b| a.key.cmp(&b.key));
+        res
+    }
+
+    fn subscribe_url(&mut self, url: &str, room: &str) -> Result<String, String> {
+        if !Subscriber::is_url_for_me(self, url) {
+            return Err(format!(
+                "{} is no {} URL of {}",
+                url,
+                self.subtype,
+                self.backend()
+            ));
+        }
+        if !self.is_known_target(room) {
+            return Err(format!("{} is not configured", room));
+        }
+        let key = T::try_from(url.to_string()).map_err(|_| format!("Could not parse {}", url))?;
+        let outcome = self.subscribe(key.clone(), room);
+        if outcome.is_ok() {
+            self.activate();
+        }
+        self.describe(&key, outcome)
+    }
+
+    fn unsubscribe_url(&mut self, url: &str, room: &str) -> Result<String, String> {
+        let key = T::try_from(url.to_string()).map_err(|_| format!("Could not parse {}", url))?;
+        let outcome = self.unsubscribe(key.clone(), room);
+        self.describe(&key, outcome)
+    }
+
+    fn purge(&mut self, room: &str) -> usize {
+        Subscriber::purge(self, room)
+    }
+
+    fn migrate(&mut self, from: &str, to: &str) -> usize {
+        Subscriber::migrate(self, from, to)
+    }
+
+    fn rooms(&self) -> HashSet<String> {
+        Subscriber::rooms(self)
+    }
+
+    fn receives(&self, target: &str, document: &serde_json::Value) -> bool {
+        Subscriber::receives(self, target, document)
+    }
+}
+
+/// All subscribers of all backends, for everything that works across them
+#[derive(Clone, Default)]
+pub struct Registry {
+    stores: Arc<Mutex<Vec<Box<dyn SubscriptionStore>>>>,
+    states: Arc<Mutex<Vec<Box<dyn RoomState>>>>,
+}
+
+impl Registry {
+    pub fn add<S: SubscriptionStore + 'static>(&self, store: S) {
+        if let Ok(mut stores) = self.stores.lock() {
+            stores.push(Box::new(store));
+        }
+    }
+
+    pub fn add_state<S: RoomState + 'static>(&self, state: S) {
+        if let Ok(mut states) = self.states.lock() {
+            states.push(Box::new(state));
+        }
+    }
+
+    /// Checks if any backend is responsible for the URL
+    pub fn handles(&self, url: &str) -> bool {
+        self.with_stores(|stores| stores.iter().any(|x| x.is_url_for_me(url)))
+            .unwrap_or(false)
+    }
+
+    /// What a short form could stand for, as (backend, URL)
+    pub fn candidates(&self, arg: &str) -> Vec<(String, String)> {
+        self.with_stores(|stores| {
+            stores
+                .iter()
+                .filter_map(|x| x.candidate(arg).map(|url| (x.backend().to_string(), url)))
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+
+    /// The stores a URL or short form belongs to, as (backend, type, URL).
+    /// Short forms are resolved with the backend of the room.
+    pub fn resolve(&self, arg: &str, backend: Option<&str>) -> Vec<(String, String, String)> {
+        self.with_stores(|stores| {
+            stores
+                .iter()
+                .filter_map(|x| {
+                    let url = if looks_like_url(arg) {
+                        Some(arg.to_string()).filter(|_| x.is_url_for_me(arg))
+                    } else if Some(x.backend()) == backend {
+                        x.candidate(arg)
+                    } else {
+                        None
+                    };
+                    url.map(|url| (x.backend().to_string(), x.event_type().to_string(), url))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+
+    /// Subscriptions of a room (or event-only target) on all backends
+    pub fn list(&self, room: &str) -> Vec<SubscriptionInfo> {
+        self.with_stores(|stores| stores.iter().flat_map(|x| x.list(room)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether any subscription of a target gets an event, given its JSON document
+    pub fn receives(&self, target: &str, document: &serde_json::Value) -> bool {
+        // Rather keep too much than drop what is still subscribed
+        self.with_stores(|stores| stores.iter().any(|x| x.receives(target, document)))
+            .unwrap_or(true)
+    }
+
+    /// Unsubscribes a room (or target) from a listed subscription
+    pub fn unsubscribe(
+        &self,
+        subscription: &SubscriptionInfo,
+        room: &str,
+    ) -> Result<String, String> {
+        self.with_stores(|stores| {
+            match stores.iter_mut().find(|x| {
+                x.backend() == subscription.backend && x.event_type() == subscription.event_type
+            }) {
+                Some(store) => store.unsubscribe_url(&subscription.url, room),
+                None => Err(format!("{} is gone", subscription.backend)),
+            }
+        })
+        .unwrap_or_else(|| Err("subscriptions not lockable".to_string()))
+    }
+
+    /// Forgets all subscriptions and state of a room the bot can't post to anymore
+    pub fn purge(&self, room: &str) {
+        let purged = self
+            .with_stores(|stores| stores.iter_mut().map(|x| x.purge(room)).sum::<usize>())
+            .unwrap_or(0);
+        if purged > 0 {
+            println!("Removed {} subscriptions of {}", purged, room);
+        }
+        if let Ok(states) = self.states.lock() {
+            for state in states.iter() {
+                state.purge(room);
+            }
+        }
+    }
+
+    /// Moves all subscriptions and state of a room to the one replacing it.
+    /// Returns the number of subscriptions moved.
+    pub fn migrate(&self, from: &str, to: &str) -> usize {
+        let moved = self
+            .with_stores(|stores| {
+                stores
+                    .iter_mut()
+                    .map(|x| x.migrate(from, to))
+                    .sum::<usize>()
+            })
+            .unwrap_or(0);
+        println!("Moved {} subscriptions of {} to {}", moved, from, to);
+        if let Ok(states) = self.states.lock() {
+            for state in states.iter() {
+                state.migrate(from, to);
+            }
+        }
+        moved
+    }
+
+    /// All rooms (and targets) there are subscriptions or state for
+    pub fn rooms(&self) -> Vec<String> {
+        let mut res = self
+            .with_stores(|stores| {
+                stores
+                    .iter()
+                    .flat_map(|x| x.rooms())
+                    .collect::<HashSet<_>>()
+            })
+            .unwrap_or_default();
+        if let Ok(states) = self.states.lock() {
+            res.extend(states.iter().flat_map(|x| x.rooms()));
+        }
+        let mut res: Vec<_> = res.into_iter().collect();
+        res.sort();
+        res
+    }
+
+    /// All subscription types, like "package" or "request"
+    pub fn event_types(&self) -> Vec<String> {
+        let mut res = self
+            .with_stores(|stores| {
+                stores
+                    .iter()
+                    .map(|x| x.event_type().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        res.sort();
+        res.dedup();
+        res
+    }
+
+    /// Gives access to all stores, or None if they are not lockable
+    pub fn with_stores<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut Vec<Box<dyn SubscriptionStore>>) -> R,
+    {
+        match self.stores.lock() {
+            Ok(mut stores) => Some(f(&mut stores)),
+            Err(_) => None,
+        }
+    }
+}
+
+/// Everything a subscriber has to be known to: The chat frontends and the registry
+pub struct Frontends<'a> {
+    pub bot: &'a mut MatrixBot,
+    pub irc: Option<&'a mut IrcClient>,
+    pub registry: &'a Registry,
+}
+
+impl Frontends<'_> {
+    pub fn add_subscriber<S>(&mut self, sub: S)
+    where
+        S: MessageHandler + SubscriptionStore + Clone + 'static,
+    {
+        self.registry.add(sub.clone());
+        if let Some(irc) = self.irc.as_mut() {
+            irc.add_handler(sub.clone());
+        }
+        self.bot.add_handler(sub);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admins_are_configured() {
+        let rooms = RoomSettings::new(Vec::new(), Vec::new());
+        assert!(!rooms.is_admin("@me:example.org"));
+        assert!(rooms
+            .set_mode("!room:example.org", "explicit", "@me:example.org")
+            .is_err());
+
+        let rooms = RoomSettings::new(Vec::new(), vec!["@admin:example.org".to_string()]);
+        assert!(rooms.is_admin("@admin:example.org"));
+        assert!(!rooms.is_admin("@me:example.org"));
+        assert!(rooms
+            .set_mode("!room:example.org", "explicit", "@me:example.org")
+            .is_err());
+        assert!(rooms
+            .set_mode("!room:example.org", "explicit", "@admin:example.org")
+            .is_ok());
+        assert_eq!(rooms.mode("!room:example.org"), RoomMode::Explicit);
+    }
+
+    #[test]
+    fn targets_belong_to_rooms() {
+        let rooms = RoomSettings::new(Vec::new(), vec!["@admin:example.org".to_string()]);
+        let target = "mailto:team@example.org";
+        assert!(rooms
+            .may_use_target("!a:example.org", "@me:example.org", target)
+            .is_err());
+        assert!(rooms
+            .claim_target("!a:example.org", "@me:example.org", target)
+            .is_ok());
+        assert!(rooms
+            .may_use_target("!a:example.org", "@you:example.org", target)
+            .is_ok());
+        assert!(rooms
+            .claim_target("!b:example.org", "@me:example.org", target)
+            .is_err());
+        assert!(rooms
+            .may_use_target("!b:example.org", "@admin:example.org", target)
+            .is_ok());
+        // Rooms are no targets of their own
+        assert!(rooms
+            .may_use_target("!b:example.org", "@me:example.org", "!a:example.org")
+            .is_ok());
+    }
+}
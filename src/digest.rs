@@ -0,0 +1,638 @@
+//! Rooms can get some subscriptions as a periodic digest instead of live.
+//! Such subscriptions are made for the target "digest:ROOM", whose events are
+//! recorded here and summed up on the schedule of the room.
+
+use crate::command::{self, Command};
+use crate::common::{escape_html, prepend_prefix, Notifier, Registry, RoomSettings, RoomState};
+use anyhow::Result;
+use chrono::{DateTime, Datelike, TimeZone, Timelike, Utc};
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+const TARGET_PREFIX: &str = "digest:";
+/// Every morning at 8
+pub const DEFAULT_SCHEDULE: &str = "0 8 * * *";
+const DIGEST_USAGE: &str = "digest [now|daily|weekly|MIN HOUR DAY MONTH WEEKDAY]";
+
+pub fn is_digest(room: &str) -> bool {
+    room.starts_with(TARGET_PREFIX)
+}
+
+/// The target digest subscriptions of a room are made for
+pub fn digest_target(room: &str) -> String {
+    format!("{}{}", TARGET_PREFIX, room)
+}
+
+/// The room a digest target belongs to
+pub fn digest_room(target: &str) -> &str {
+    target.trim_start_matches(TARGET_PREFIX)
+}
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [
+        (
+            "sub URL digest",
+            "Get the events of URL only in the digest of this room, not live. \"sub URL live\" switches back.",
+        ),
+        (
+            DIGEST_USAGE,
+            "Show or set when the digest is sent (cron-like, default: daily at 8), or send it now.",
+        ),
+    ];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+/// Cron-like schedule: minute, hour, day of month, month and day of week (0 or 7 is Sunday)
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    minutes: Vec<u32>,
+    hours: Vec<u32>,
+    days: Option<Vec<u32>>,
+    months: Vec<u32>,
+    weekdays: Option<Vec<u32>>,
+}
+
+/// "*", "*/N", "N", "N-M", "N-M/S" and lists of them. None for "*" (to tell it apart for days).
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Option<Vec<u32>>, ()> {
+    if field == "*" {
+        return Ok(None);
+    }
+    let mut res = Vec::new();
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| ())?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            x => match x.split_once('-') {
+                Some((a, b)) => (a.parse().map_err(|_| ())?, b.parse().map_err(|_| ())?),
+                None => {
+                    let n = x.parse().map_err(|_| ())?;
+                    (n, n)
+                }
+            },
+        };
+        if step == 0 || start < min || end > max || start > end {
+            return Err(());
+        }
+        res.extend((start..=end).step_by(step as usize));
+    }
+    Ok(Some(res))
+}
+
+impl std::str::FromStr for Schedule {
+    type Err = ();
+
+    fn from_str(schedule: &str) -> Result<Self, Self::Err> {
+        let schedule = match schedule {
+            "daily" => DEFAULT_SCHEDULE,
+            "weekly" => "0 8 * * 1",
+            x => x,
+        };
+        let fields: Vec<_> = schedule.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields.as_slice() else {
+            return Err(());
+        };
+        let all = |x: Option<Vec<u32>>, min, max| x.unwrap_or_else(|| (min..=max).collect());
+        Ok(Schedule {
+            minutes: all(parse_field(minutes, 0, 59)?, 0, 59),
+            hours: all(parse_field(hours, 0, 23)?, 0, 23),
+            days: parse_field(days, 1, 31)?,
+            months: all(parse_field(months, 1, 12)?, 1, 12),
+            weekdays: parse_field(weekdays, 0, 7)?,
+        })
+    }
+}
+
+impl Schedule {
+    pub fn matches<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        let weekday = time.weekday().num_days_from_sunday();
+        let day = self.days.as_ref().map(|x| x.contains(&time.day()));
+        let weekday = self
+            .weekdays
+            .as_ref()
+            .map(|x| x.contains(&weekday) || (weekday == 0 && x.contains(&7)));
+        // Like cron: If both are restricted, either of them is enough
+        let day_matches = match (day, weekday) {
+            (Some(day), Some(weekday)) => day || weekday,
+            (Some(x), None) | (None, Some(x)) => x,
+            (None, None) => true,
+        };
+        day_matches
+            && self.minutes.contains(&time.minute())
+            && self.hours.contains(&time.hour())
+            && self.months.contains(&time.month())
+    }
+}
+
+/// What happened to a request since the last digest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RequestChanges {
+    url: String,
+    state: String,
+    changes: u32,
+}
+
+/// Events recorded for one room since its last digest
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Record {
+    /// Stays until the package builds again or the room unsubscribes, as
+    /// "PROJECT/PACKAGE (REPOSITORY/ARCH)" -> event document
+    failing: BTreeMap<String, Value>,
+    requests: BTreeMap<String, RequestChanges>,
+    tests_passed: u32,
+    tests_failed: u32,
+    /// Events of other types per key, e.g. pushes to a repository
+    other: BTreeMap<String, u32>,
+}
+
+impl Record {
+    fn add(&mut self, document: &Value) {
+        let text = |x: &Value| x.as_str().unwrap_or_default().to_string();
+        let data = &document["data"];
+        let key = text(&document["key"]);
+        let url = text(&document["url"]);
+        match document["event"].as_str().unwrap_or_default() {
+            x @ ("obs.package.build_fail" | "obs.package.build_success") => {
+                let build = format!(
+                    "{}/{} ({}/{})",
+                    text(&data["project"]),
+                    text(&data["package"]),
+                    text(&data["repository"]),
+                    text(&data["arch"])
+                );
+                if x.ends_with("fail") {
+                    self.failing.insert(build, document.clone());
+                } else {
+                    self.failing.remove(&build);
+                }
+            }
+            x if x.starts_with("obs.request.") => {
+                let request = self.requests.entry(key).or_insert_with(|| RequestChanges {
+                    url,
+                    state: String::new(),
+                    changes: 0,
+                });
+                request.changes += 1;
+                if x.ends_with("delete") {
+                    request.state = "deleted".to_string();
+                } else if let Some(state) = data["state"].as_str() {
+                    request.state = state.to_string();
+                }
+            }
+            "openqa.job.done" => match data["result"].as_str() {
+                Some("passed" | "softfailed") => self.tests_passed += 1,
+                _ => self.tests_failed += 1,
+            },
+            _ => *self.other.entry(key).or_default() += 1,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.failing.is_empty()
+            && self.requests.is_empty()
+            && self.tests_passed + self.tests_failed == 0
+            && self.other.is_empty()
+    }
+
+    /// The digest as (plain, html). Failing packages are kept for the next one.
+    fn take_messages(&mut self) -> (String, String) {
+        let mut plain = vec!["Digest".to_string()];
+        let mut html = vec!["<strong>Digest</strong>".to_string()];
+
+        if !self.failing.is_empty() {
+            plain.push(format!("Failing packages ({}):", self.failing.len()));
+            plain.extend(self.failing.keys().map(|x| format!("- {}", x)));
+            html.push(format!(
+                "Failing packages ({}):<ul>{}</ul>",
+                self.failing.len(),
+                self.failing
+                    .iter()
+                    .map(|(build, document)| format!(
+                        "<li><a href=\"{}\">{}</a></li>",
+                        escape_html(document["url"].as_str().unwrap_or_default()),
+                        escape_html(build)
+                    ))
+                    .collect::<String>()
+            ));
+        }
+
+        let requests = std::mem::take(&mut self.requests);
+        if !requests.is_empty() {
+            plain.push(format!("Changed requests ({}):", requests.len()));
+            plain.extend(
+                requests
+                    .iter()
+                    .map(|(id, x)| format!("- {}: {} ({} changes)", id, x.state, x.changes)),
+            );
+            html.push(format!(
+                "Changed requests ({}):<ul>{}</ul>",
+                requests.len(),
+                requests
+                    .iter()
+                    .map(|(id, x)| format!(
+                        "<li><a href=\"{}\">{}</a>: {} ({} changes)</li>",
+                        escape_html(&x.url),
+                        escape_html(id),
+                        escape_html(&x.state),
+                        x.changes
+                    ))
+                    .collect::<String>()
+            ));
+        }
+
+        if self.tests_passed + self.tests_failed > 0 {
+            let tests = format!(
+                "openQA tests: {} passed, {} failed",
+                self.tests_passed, self.tests_failed
+            );
+            plain.push(tests.clone());
+            html.push(tests);
+            self.tests_passed = 0;
+            self.tests_failed = 0;
+        }
+
+        let other = std::mem::take(&mut self.other);
+        if !other.is_empty() {
+            let events = format!(
+                "Other events: {}",
+                other
+                    .iter()
+                    .map(|(key, n)| format!("{} ({})", key, n))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            plain.push(events.clone());
+            html.push(escape_html(&events));
+        }
+        (plain.join("\n"), html.join("<br>"))
+    }
+}
+
+/// Events of digest subscriptions per room. Changes are written to the state
+/// file, if there is one, and read back at startup. Cheap to clone.
+#[derive(Clone, Default)]
+pub struct Digests {
+    path: Option<PathBuf>,
+    records: Arc<Mutex<HashMap<String, Record>>>,
+}
+
+impl Digests {
+    /// Reads the records saved in path (if it exists yet) and saves all changes there
+    pub fn load(&mut self, path: PathBuf) -> Result<()> {
+        if path.exists() {
+            let saved: HashMap<String, Record> =
+                serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+            if let Ok(mut records) = self.records.lock() {
+                records.extend(saved);
+            }
+        }
+        self.path = Some(path);
+        Ok(())
+    }
+
+    fn save(&self, records: &HashMap<String, Record>) {
+        let path = match &self.path {
+            Some(x) => x,
+            None => return,
+        };
+        let res = serde_json::to_string_pretty(records)
+            .map_err(anyhow::Error::from)
+            .and_then(|x| std::fs::write(path, x).map_err(anyhow::Error::from));
+        if let Err(x) = res {
+            println!("Could not save digests to {:?}: {}", path, x);
+        }
+    }
+
+    /// Keeps an event of a "digest:ROOM" target for the next digest of the room
+    pub fn record(&self, target: &str, document: &Value) {
+        if let Ok(mut records) = self.records.lock() {
+            records
+                .entry(digest_room(target).to_string())
+                .or_default()
+                .add(document);
+            self.save(&records);
+        }
+    }
+
+    /// Drops failing packages of a room that fail the check, e.g. because it is not
+    /// subscribed to them anymore. The check runs without the records locked, as it
+    /// locks subscriptions.
+    pub fn prune<F: Fn(&Value) -> bool>(&self, room: &str, keep: F) {
+        let failing: Vec<(String, Value)> = match self.records.lock() {
+            Ok(records) => match records.get(room) {
+                Some(record) => record.failing.clone().into_iter().collect(),
+                None => return,
+            },
+            Err(_) => return,
+        };
+        let gone: Vec<String> = failing
+            .into_iter()
+            .filter(|(_, document)| !keep(document))
+            .map(|(build, _)| build)
+            .collect();
+        if gone.is_empty() {
+            return;
+        }
+        if let Ok(mut records) = self.records.lock() {
+            if let Some(record) = records.get_mut(room) {
+                for build in gone {
+                    record.failing.remove(&build);
+                }
+            }
+            self.save(&records);
+        }
+    }
+
+    /// Sends the digest of a room. Returns false if there is nothing to tell.
+    fn send(&self, notifier: &Notifier, registry: &Registry, room: &str) -> bool {
+        // In case a subscription ended some other way
+        let target = digest_target(room);
+        self.prune(room, |document| registry.receives(&target, document));
+        let (plain, html) = match self.records.lock() {
+            Ok(mut records) => {
+                let messages = match records.get_mut(room) {
+                    Some(record) if !record.is_empty() => record.take_messages(),
+                    _ => return false,
+                };
+                self.save(&records);
+                messages
+            }
+            Err(_) => return false,
+        };
+        println!("Sending digest to {}", room);
+        // Like any notification, so it is held while the room is muted
+        notifier.notify(room, &plain, &html, &Value::Null);
+        true
+    }
+
+    /// Sends the digests on the schedules of their rooms in a separate thread
+    pub fn run(&self, notifier: Notifier, rooms: RoomSettings, registry: Registry) {
+        let digests = self.clone();
+        std::thread::spawn(move || loop {
+            // Once at the start of every minute
+            let second = Utc::now().second() as u64;
+            std::thread::sleep(std::time::Duration::from_secs(60 - second.min(59)));
+
+            let now = Utc::now();
+            let due: Vec<String> = match digests.records.lock() {
+                Ok(records) => records
+                    .keys()
+                    .filter(|room| {
+                        let timezone = notifier.mutes.timezone(room);
+                        schedule(&rooms, room).matches(&now.with_timezone(&timezone))
+                    })
+                    .cloned()
+                    .collect(),
+                Err(_) => continue,
+            };
+            for room in due {
+                digests.send(&notifier, &registry, &room);
+            }
+        });
+    }
+}
+
+impl RoomState for Digests {
+    fn purge(&self, room: &str) {
+        if let Ok(mut records) = self.records.lock() {
+            if records.remove(room).is_some() {
+                self.save(&records);
+            }
+        }
+    }
+
+    fn migrate(&self, from: &str, to: &str) {
+        if let Ok(mut records) = self.records.lock() {
+            if let Some(record) = records.remove(from) {
+                records.entry(to.to_string()).or_insert(record);
+                self.save(&records);
+            }
+        }
+    }
+
+    fn rooms(&self) -> Vec<String> {
+        self.records
+            .lock()
+            .map(|x| x.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// The schedule of a room, or the default if it has none (or it became invalid)
+fn schedule(rooms: &RoomSettings, room: &str) -> Schedule {
+    rooms
+        .digest_schedule(room)
+        .and_then(|x| x.parse().ok())
+        .unwrap_or_else(|| DEFAULT_SCHEDULE.parse().unwrap()) // Constant, can't fail
+}
+
+/// Answers "digest"
+#[derive(Clone)]
+pub struct DigestHandler {
+    pub prefix: Option<String>,
+    pub notifier: Notifier,
+    pub rooms: RoomSettings,
+    pub registry: Registry,
+}
+
+impl MessageHandler for DigestHandler {
+    fn handle_message(&mut self, _bot: &ActiveBot, message: &Message) -> HandleResult {
+        for command in command::parse(&command::message_text(message), self.prefix.as_deref())
+            .into_iter()
+            .flatten()
+        {
+            if let Command::Digest(args) = command {
+                let room = &message.room;
+                let answer = match args.as_deref() {
+                    None => format!(
+                        "The digest of this room is sent on \"{}\"",
+                        self.rooms
+                            .digest_schedule(room)
+                            .unwrap_or_else(|| DEFAULT_SCHEDULE.to_string())
+                    ),
+                    Some("now") => {
+                        if self
+                            .notifier
+                            .digests
+                            .send(&self.notifier, &self.registry, room)
+                        {
+                            continue;
+                        }
+                        "Nothing happened since the last digest".to_string()
+                    }
+                    Some(schedule) => match schedule.parse::<Schedule>() {
+                        Ok(_) => match self.rooms.set_digest_schedule(room, schedule) {
+                            Ok(x) | Err(x) => x,
+                        },
+                        Err(_) => format!(
+                            "Sorry, {} is no schedule. Usage: {}",
+                            schedule, DIGEST_USAGE
+                        ),
+                    },
+                };
+                self.notifier
+                    .send_message(&answer, room, MessageType::TextMessage);
+            }
+        }
+        HandleResult::ContinueHandling
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.ymd(2026, 10, day).and_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn schedules() {
+        let schedule: Schedule = "daily".parse().unwrap();
+        assert!(schedule.matches(&at(18, 8, 0)));
+        assert!(!schedule.matches(&at(18, 8, 1)));
+        let weekly: Schedule = "weekly".parse().unwrap();
+        assert!(weekly.matches(&at(19, 8, 0)));
+        assert!(!weekly.matches(&at(18, 8, 0)));
+
+        let schedule: Schedule = "*/15 8-17/3 * * 1-5".parse().unwrap();
+        assert!(schedule.matches(&at(19, 14, 45)));
+        assert!(!schedule.matches(&at(19, 15, 45)));
+        assert!(!schedule.matches(&at(19, 14, 50)));
+        assert!(!schedule.matches(&at(18, 14, 45)));
+
+        // 0 and 7 are Sunday
+        let sunday: Schedule = "0 9 * * 7".parse().unwrap();
+        assert!(sunday.matches(&at(18, 9, 0)));
+        let sunday: Schedule = "0 9 * * 0,6".parse().unwrap();
+        assert!(sunday.matches(&at(18, 9, 0)));
+
+        // Like cron, a restricted day of month or day of week is enough
+        let schedule: Schedule = "0 9 1 * 1".parse().unwrap();
+        assert!(schedule.matches(&at(1, 9, 0)));
+        assert!(schedule.matches(&at(19, 9, 0)));
+        assert!(!schedule.matches(&at(18, 9, 0)));
+        let schedule: Schedule = "0 9 1 11 *".parse().unwrap();
+        assert!(!schedule.matches(&at(1, 9, 0)));
+
+        for invalid in [
+            "",
+            "hourly",
+            "0 8 * *",
+            "0 8 * * * *",
+            "60 8 * * *",
+            "0 24 * * *",
+            "0 8 0 * *",
+            "0 8 * 13 *",
+            "0 8 * * 8",
+            "0 8-6 * * *",
+            "*/0 8 * * *",
+            "a 8 * * *",
+            "0 8,, * * *",
+        ] {
+            assert!(invalid.parse::<Schedule>().is_err(), "{}", invalid);
+        }
+    }
+
+    fn build(event: &str, package: &str) -> Value {
+        json!({
+            "event": event,
+            "url": format!("https://build.example.org/package/show/home:me/{}", package),
+            "data": {
+                "project": "home:me",
+                "package": package,
+                "repository": "openSUSE_Tumbleweed",
+                "arch": "x86_64"
+            }
+        })
+    }
+
+    #[test]
+    fn failing_packages() {
+        let digests = Digests::default();
+        let target = digest_target("!room:example.org");
+        digests.record(&target, &build("obs.package.build_fail", "foo"));
+        digests.record(&target, &build("obs.package.build_fail", "bar"));
+        digests.record(&target, &build("obs.package.build_success", "bar"));
+        let failing = |digests: &Digests| {
+            let records = digests.records.lock().unwrap();
+            let record = &records["!room:example.org"];
+            (
+                record.failing.keys().cloned().collect::<Vec<_>>(),
+                record.is_empty(),
+            )
+        };
+        assert_eq!(
+            failing(&digests),
+            (
+                vec!["home:me/foo (openSUSE_Tumbleweed/x86_64)".to_string()],
+                false
+            )
+        );
+
+        // Still failing in the next digest
+        let (plain, _) = digests
+            .records
+            .lock()
+            .unwrap()
+            .get_mut("!room:example.org")
+            .unwrap()
+            .take_messages();
+        assert!(plain.contains("home:me/foo"), "{}", plain);
+        assert!(!failing(&digests).1);
+
+        // Until the room is not subscribed to it anymore
+        digests.prune("!room:example.org", |x| x["data"]["package"] != "foo");
+        assert_eq!(failing(&digests), (Vec::new(), true));
+    }
+
+    #[test]
+    fn digests_are_escaped_and_saved() {
+        let path = std::env::temp_dir().join(format!("digests-{}.json", std::process::id()));
+        let mut digests = Digests::default();
+        digests.load(path.clone()).unwrap();
+        let target = digest_target("!room:example.org");
+        digests.record(&target, &build("obs.package.build_fail", "<b>foo"));
+        digests.record(
+            &target,
+            &json!({
+                "event": "obs.request.state_change",
+                "key": "<i>1",
+                "url": "https://build.example.org/request/show/1\" onclick=\"x",
+                "data": {"state": "accepted"}
+            }),
+        );
+
+        // A restart keeps them
+        let mut restarted = Digests::default();
+        restarted.load(path.clone()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let (_, html) = restarted
+            .records
+            .lock()
+            .unwrap()
+            .get_mut("!room:example.org")
+            .unwrap()
+            .take_messages();
+        assert!(
+            html.contains("<a href=\"https://build.example.org/package/show/home:me/&lt;b&gt;foo\">home:me/&lt;b&gt;foo (openSUSE_Tumbleweed/x86_64)</a>"),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("<a href=\"https://build.example.org/request/show/1&quot; onclick=&quot;x\">&lt;i&gt;1</a>: accepted (1 changes)"),
+            "{}",
+            html
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! Personal subscriptions ("sub me URL"), which belong to a user instead of a
+//! room. Their notifications go to the direct chat of the user, wherever the
+//! subscription was made.
+//!
+//! The first time a Matrix user subscribes themselves without a direct chat, the
+//! bot opens one through the client-server API (matrix_bot_api can't). Users can
+//! also invite it to one and say "dm" there, e.g. if that failed or to move their
+//! notifications to another room.
+
+use crate::command::{self, Command};
+use crate::common::{prepend_prefix, Notifier, RoomState};
+use crate::matrix_api::MatrixApi;
+use anyhow::{anyhow, Result};
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Stands for the user who gave the command, until it is resolved with resolve_target()
+pub const ME: &str = "me";
+const PREFIX: &str = "user:";
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [
+        (
+            "sub me URL",
+            "Subscribe yourself, to get the notifications in your direct chat with me. Works with unsub, list and export as well.",
+        ),
+        (
+            "dm",
+            "Use this room as your direct chat with me, instead of the one I opened.",
+        ),
+    ];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+pub fn is_user_target(target: &str) -> bool {
+    target.starts_with(PREFIX)
+}
+
+/// The target of the personal subscriptions of a user
+pub fn user_target(user: &str) -> String {
+    format!("{}{}", PREFIX, user)
+}
+
+/// Replaces "me" by the target of the sender
+pub fn resolve_target(target: &str, sender: &str) -> String {
+    if target == ME {
+        user_target(sender)
+    } else {
+        target.to_string()
+    }
+}
+
+/// The direct chat of each user. Changes are written to the state file, if there
+/// is one, and read back at startup. Cheap to clone.
+#[derive(Clone, Default)]
+pub struct DirectRooms {
+    path: Option<PathBuf>,
+    /// Per Matrix ID (or IRC nick)
+    rooms: Arc<Mutex<BTreeMap<String, String>>>,
+}
+
+impl DirectRooms {
+    /// Reads the rooms saved in path (if it exists yet) and saves all changes there
+    pub fn load(&mut self, path: PathBuf) -> Result<()> {
+        if path.exists() {
+            let saved: BTreeMap<String, String> =
+                serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+            if let Ok(mut rooms) = self.rooms.lock() {
+                rooms.extend(saved);
+            }
+        }
+        self.path = Some(path);
+        Ok(())
+    }
+
+    fn save(&self, rooms: &BTreeMap<String, String>) {
+        let path = match &self.path {
+            Some(x) => x,
+            None => return,
+        };
+        let res = serde_json::to_string_pretty(rooms)
+            .map_err(anyhow::Error::from)
+            .and_then(|x| std::fs::write(path, x).map_err(anyhow::Error::from));
+        if let Err(x) = res {
+            println!("Could not save direct chats to {:?}: {}", path, x);
+        }
+    }
+
+    fn set(&self, user: &str, room: &str) {
+        if let Ok(mut rooms) = self.rooms.lock() {
+            rooms.insert(user.to_string(), room.to_string());
+            self.save(&rooms);
+        }
+    }
+
+    /// The direct chat to send to for a user target
+    pub fn room(&self, target: &str) -> Option<String> {
+        let user = target.strip_prefix(PREFIX)?;
+        self.rooms.lock().ok()?.get(user).cloned()
+    }
+}
+
+impl RoomState for DirectRooms {
+    /// The users of the room need a new direct chat
+    fn purge(&self, room: &str) {
+        if let Ok(mut rooms) = self.rooms.lock() {
+            let before = rooms.len();
+            rooms.retain(|_, x| x != room);
+            if rooms.len() != before {
+                self.save(&rooms);
+            }
+        }
+    }
+
+    fn migrate(&self, from: &str, to: &str) {
+        if let Ok(mut rooms) = self.rooms.lock() {
+            let mut changed = false;
+            for room in rooms.values_mut().filter(|x| *x == from) {
+                *room = to.to_string();
+                changed = true;
+            }
+            if changed {
+                self.save(&rooms);
+            }
+        }
+    }
+
+    fn rooms(&self) -> Vec<String> {
+        self.rooms
+            .lock()
+            .map(|x| x.values().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Answers "dm", and opens a direct chat for users subscribing themselves
+/// without one
+#[derive(Clone)]
+pub struct DmHandler {
+    pub prefix: Option<String>,
+    pub notifier: Notifier,
+    pub matrix: MatrixApi,
+}
+
+impl DmHandler {
+    /// Opens a direct chat with a Matrix user and sends their personal notifications there
+    fn open_dm(&self, user: &str) -> Result<String> {
+        // IRC nicks have no Matrix account to invite
+        if !user.starts_with('@') {
+            return Err(anyhow!("{} is no Matrix user", user));
+        }
+        let room = self.matrix.create_dm(user)?;
+        self.notifier.dms.set(user, &room);
+        self.notifier.send_message(
+            &format!(
+                "Your personal notifications (sub me URL) are sent here, {}",
+                user
+            ),
+            &room,
+            MessageType::TextMessage,
+        );
+        Ok(room)
+    }
+}
+
+impl MessageHandler for DmHandler {
+    fn handle_message(&mut self, _bot: &ActiveBot, message: &Message) -> HandleResult {
+        let target = user_target(&message.sender);
+        let mut hint = false;
+        for command in command::parse(&command::message_text(message), self.prefix.as_deref())
+            .into_iter()
+            .flatten()
+        {
+            match command {
+                Command::Dm => {
+                    self.notifier.dms.set(&message.sender, &message.room);
+                    self.notifier.send_message(
+                        &format!(
+                            "Your personal notifications (sub me URL) are sent here now, {}",
+                            message.sender
+                        ),
+                        &message.room,
+                        MessageType::TextMessage,
+                    );
+                }
+                Command::Subscribe(args) if args.target.as_deref() == Some(ME) => {
+                    hint |= self.notifier.dms.room(&target).is_none();
+                }
+                _ => {}
+            }
+        }
+        if hint {
+            let answer = match self.open_dm(&message.sender) {
+                Ok(_) => format!(
+                    "I invited you to a direct chat, your personal notifications are sent there, {}",
+                    message.sender
+                ),
+                Err(x) => {
+                    println!("Could not open a direct chat with {}: {}", message.sender, x);
+                    format!(
+                        "I could not open a direct chat with you. Invite me to one and say \"{}dm\" there to get your personal notifications.",
+                        self.prefix.as_deref().unwrap_or("")
+                    )
+                }
+            };
+            self.notifier
+                .send_message(&answer, &message.room, MessageType::TextMessage);
+        }
+        HandleResult::ContinueHandling
+    }
+}
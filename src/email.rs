@@ -0,0 +1,261 @@
+use crate::common::prepend_prefix;
+use anyhow::{anyhow, Result};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const TARGET_PREFIX: &str = "mailto:";
+// Upper bound for waiting on new mails, if no batch is pending
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3600);
+
+fn default_batch_window() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    None,
+    Starttls,
+    Tls,
+}
+
+fn default_security() -> SmtpSecurity {
+    SmtpSecurity::Starttls
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EmailDetails {
+    pub smtp_host: String,
+    pub smtp_port: Option<u16>,
+    #[serde(default = "default_security")]
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    /// Seconds to collect notifications for one recipient before sending them as one mail
+    #[serde(default = "default_batch_window")]
+    pub batch_window: u64,
+    /// Domains ("example.com") and addresses ("me@example.com") that can be subscribed.
+    /// Nothing, if empty.
+    #[serde(default)]
+    pub allowed: Vec<String>,
+}
+
+/// Subscription targets of the form "mailto:ADDRESS" get events as mail
+pub fn is_email(room: &str) -> bool {
+    room.starts_with(TARGET_PREFIX)
+}
+
+/// Checks that the target contains an address lettre can actually send to
+pub fn is_valid_address(target: &str) -> bool {
+    target
+        .trim_start_matches(TARGET_PREFIX)
+        .parse::<Mailbox>()
+        .is_ok()
+}
+
+/// Checks the address of the target against the allowed domains and addresses
+fn is_allowed(allowed: &[String], target: &str) -> bool {
+    let address = match target.trim_start_matches(TARGET_PREFIX).parse::<Mailbox>() {
+        Ok(x) => x.email,
+        Err(_) => return false,
+    };
+    allowed.iter().any(|x| {
+        if x.contains('@') {
+            x.eq_ignore_ascii_case(address.as_ref())
+        } else {
+            x.eq_ignore_ascii_case(address.domain())
+        }
+    })
+}
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [(
+        "URL mailto:ADDRESS",
+        "Subscribe a mail address instead of this room (works with unsub and list, too).",
+    )];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+struct Mail {
+    recipient: String,
+    plain: String,
+    html: String,
+}
+
+#[derive(Default)]
+struct Batch {
+    since: Option<Instant>,
+    plain: Vec<String>,
+    html: Vec<String>,
+}
+
+/// Handle to queue notifications for sending by mail. Cheap to clone.
+#[derive(Clone)]
+pub struct Mailer {
+    tx: Sender<Mail>,
+    allowed: Arc<Vec<String>>,
+}
+
+impl Mailer {
+    pub fn new(details: EmailDetails) -> Result<Mailer> {
+        let transport = build_transport(&details)?;
+        let from = details
+            .from
+            .parse::<Mailbox>()
+            .map_err(|x| anyhow!("Invalid sender address {}: {}", details.from, x))?;
+        let window = Duration::from_secs(details.batch_window);
+        let allowed = Arc::new(details.allowed);
+
+        let (tx, rx) = channel::<Mail>();
+        std::thread::spawn(move || {
+            let mut batches: HashMap<String, Batch> = HashMap::new();
+            loop {
+                let timeout = batches
+                    .values()
+                    .filter_map(|x| x.since)
+                    .map(|x| (x + window).saturating_duration_since(Instant::now()))
+                    .min()
+                    .unwrap_or(IDLE_TIMEOUT);
+
+                let disconnected = match rx.recv_timeout(timeout) {
+                    Ok(mail) => {
+                        let batch = batches.entry(mail.recipient).or_default();
+                        batch.since.get_or_insert_with(Instant::now);
+                        batch.plain.push(mail.plain);
+                        batch.html.push(mail.html);
+                        false
+                    }
+                    Err(RecvTimeoutError::Timeout) => false,
+                    Err(RecvTimeoutError::Disconnected) => true,
+                };
+
+                // On shutdown, everything still pending goes out right away
+                let due: Vec<_> = batches
+                    .iter()
+                    .filter(|(_, x)| {
+                        disconnected || x.since.map(|t| t.elapsed() >= window) == Some(true)
+                    })
+                    .map(|(recipient, _)| recipient.clone())
+                    .collect();
+                for recipient in due {
+                    if let Some(batch) = batches.remove(&recipient) {
+                        if let Err(x) = send_batch(&transport, &from, &recipient, batch) {
+                            println!("Error while sending mail to {}: {:?}", recipient, x);
+                        }
+                    }
+                }
+
+                if disconnected {
+                    return;
+                }
+            }
+        });
+
+        Ok(Mailer { tx, allowed })
+    }
+
+    /// Only allowed addresses can be subscribed, so the bot can't be used to send mail anywhere
+    pub fn allows(&self, target: &str) -> bool {
+        is_allowed(&self.allowed, target)
+    }
+
+    /// Queues a notification for the given "mailto:ADDRESS" target
+    pub fn send(&self, target: &str, plain: &str, html: &str) {
+        let mail = Mail {
+            recipient: target.trim_start_matches(TARGET_PREFIX).to_string(),
+            plain: plain.to_string(),
+            html: html.to_string(),
+        };
+        if self.tx.send(mail).is_err() {
+            println!("Mail thread is gone, dropping notification for {}", target);
+        }
+    }
+}
+
+fn build_transport(details: &EmailDetails) -> Result<SmtpTransport> {
+    let mut builder = match details.security {
+        SmtpSecurity::None => SmtpTransport::builder_dangerous(&details.smtp_host),
+        SmtpSecurity::Starttls => SmtpTransport::starttls_relay(&details.smtp_host)?,
+        SmtpSecurity::Tls => SmtpTransport::relay(&details.smtp_host)?,
+    };
+
+    if let Some(port) = details.smtp_port {
+        builder = builder.port(port);
+    }
+
+    if let (Some(username), Some(password)) = (&details.username, &details.password) {
+        builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+
+    Ok(builder.build())
+}
+
+fn send_batch(
+    transport: &SmtpTransport,
+    from: &Mailbox,
+    recipient: &str,
+    batch: Batch,
+) -> Result<()> {
+    let to = recipient
+        .parse::<Mailbox>()
+        .map_err(|x| anyhow!("Invalid recipient address: {}", x))?;
+
+    let subject = match batch.plain.len() {
+        // Notifications can have more lines, e.g. a log excerpt
+        1 => format!(
+            "[obs_chat_bot] {}",
+            batch.plain[0].lines().next().unwrap_or("").trim()
+        ),
+        n => format!("[obs_chat_bot] {} notifications", n),
+    };
+    let plain = batch.plain.join("\n");
+    let html = format!(
+        "<html><body><ul>{}</ul></body></html>",
+        batch
+            .html
+            .iter()
+            .map(|x| format!("<li>{}</li>", x))
+            .collect::<String>()
+    );
+
+    let email = Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(subject)
+        .multipart(MultiPart::alternative_plain_html(plain, html))?;
+
+    transport.send(&email)?;
+    println!(
+        "Sent {} notification(s) to {}",
+        batch.plain.len(),
+        recipient
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_allowed_addresses() {
+        let allowed = vec!["example.com".to_string(), "Me@Other.org".to_string()];
+        assert!(is_allowed(&allowed, "mailto:dev@example.com"));
+        assert!(is_allowed(&allowed, "mailto:dev@EXAMPLE.com"));
+        assert!(is_allowed(&allowed, "mailto:me@other.org"));
+        assert!(!is_allowed(&allowed, "mailto:you@other.org"));
+        assert!(!is_allowed(&allowed, "mailto:dev@sub.example.com"));
+        assert!(!is_allowed(&allowed, "mailto:dev@example.com.evil.net"));
+        assert!(!is_allowed(&allowed, "mailto:not an address"));
+        assert!(!is_allowed(&[], "mailto:dev@example.com"));
+    }
+}
@@ -0,0 +1,222 @@
+//! Subscriptions of a room as a document in the shape of `default_subs`, to take
+//! them along to another room or into the config.
+
+use crate::command::{self, Command, UrlArgs};
+use crate::common::{escape_html, prepend_prefix, Notifier, Registry, RoomSettings};
+use crate::digest::digest_target;
+use crate::dm::resolve_target;
+use crate::matrix_api::MatrixApi;
+
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [
+        (
+            "export [me]",
+            "Show the subscriptions of this room as default_subs, for the config or another room.",
+        ),
+        (
+            "import [DOCUMENT]",
+            "Subscribe this room to everything in an exported document (on the following lines, or the file you sent last).",
+        ),
+    ];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+#[derive(Deserialize)]
+struct Document {
+    default_subs: Vec<(String, String)>,
+}
+
+/// Reads `default_subs = [["ROOM", "URL"], ...]` (TOML), or the same as JSON,
+/// with or without the surrounding object
+pub fn parse_document(text: &str) -> Result<Vec<(String, String)>, String> {
+    let text = text.trim();
+    if let Ok(subs) = serde_json::from_str::<Vec<(String, String)>>(text) {
+        return Ok(subs);
+    }
+    if let Ok(document) = serde_json::from_str::<Document>(text) {
+        return Ok(document.default_subs);
+    }
+    toml::from_str::<Document>(text)
+        .map(|x| x.default_subs)
+        .map_err(|x| x.to_string())
+}
+
+/// The TOML form, one subscription per line
+pub fn document(subs: &[(String, String)]) -> String {
+    let mut res = "default_subs = [\n".to_string();
+    for (room, url) in subs {
+        // JSON strings are valid TOML strings
+        res += &format!(
+            "    [{}, {}],\n",
+            serde_json::Value::from(room.as_str()),
+            serde_json::Value::from(url.as_str())
+        );
+    }
+    res + "]"
+}
+
+#[derive(Clone)]
+pub struct ExportHandler {
+    pub prefix: Option<String>,
+    pub notifier: Notifier,
+    pub registry: Registry,
+    pub rooms: RoomSettings,
+}
+
+impl MessageHandler for ExportHandler {
+    fn handle_message(&mut self, _bot: &ActiveBot, message: &Message) -> HandleResult {
+        for command in command::parse(&command::message_text(message), self.prefix.as_deref())
+            .into_iter()
+            .flatten()
+        {
+            if let Command::Export(target) = command {
+                let (plain, html) =
+                    match self.export(target.as_deref(), &message.room, &message.sender) {
+                        Ok(x) => x,
+                        Err(x) => (x.clone(), x),
+                    };
+                self.notifier.send_html_message(
+                    &plain,
+                    &html,
+                    &message.room,
+                    MessageType::TextMessage,
+                );
+            }
+        }
+        HandleResult::ContinueHandling
+    }
+}
+
+impl ExportHandler {
+    fn export(
+        &self,
+        target: Option<&str>,
+        room: &str,
+        sender: &str,
+    ) -> Result<(String, String), String> {
+        if let Some(target) = target {
+            if !self.notifier.is_known_target(target) {
+                return Err(format!("Sorry, {} is not configured", target));
+            }
+            self.rooms
+                .may_use_target(room, sender, target)
+                .map_err(|x| format!("Sorry, {}", x))?;
+        }
+        let target = target.map(|x| resolve_target(x, sender));
+        let room = target.as_deref().unwrap_or(room);
+
+        let mut subs: Vec<_> = self
+            .registry
+            .list(room)
+            .into_iter()
+            .map(|x| {
+                let room = if x.digest {
+                    digest_target(room)
+                } else {
+                    room.to_string()
+                };
+                (room, x.url)
+            })
+            .collect();
+        if subs.is_empty() {
+            return Err("No subscriptions found".to_string());
+        }
+        subs.sort();
+        subs.dedup();
+
+        let document = document(&subs);
+        let html = format!("<pre><code>{}</code></pre>", escape_html(&document));
+        Ok((document, html))
+    }
+}
+
+/// Subscriptions of an attached document, or why it could not be read
+type Imported = Result<Vec<UrlArgs>, String>;
+
+/// Documents attached for an import, fetched by the AttachmentHandler before the
+/// other handlers see the command. Dropped by the ReplyHandler after the message.
+#[derive(Clone, Default)]
+pub struct Attachments {
+    pending: Arc<Mutex<HashMap<String, Imported>>>,
+}
+
+impl Attachments {
+    /// The subscriptions of the attachment to import in the room, or why there are none
+    pub fn get(&self, room: &str) -> Imported {
+        self.pending
+            .lock()
+            .ok()
+            .and_then(|x| x.get(room).cloned())
+            .unwrap_or_else(|| {
+                Err(
+                    "Sorry, I found no document to import. Paste it after import instead"
+                        .to_string(),
+                )
+            })
+    }
+
+    fn set(&self, room: &str, subs: Imported) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.insert(room.to_string(), subs);
+        }
+    }
+
+    pub fn clear(&self, room: &str) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.remove(room);
+        }
+    }
+}
+
+/// Reads the file the sender attached last for "import" without a document.
+/// Has to come before the HelpHandler and the subscribers. Matrix only.
+#[derive(Clone)]
+pub struct AttachmentHandler {
+    pub prefix: Option<String>,
+    pub matrix: MatrixApi,
+    pub attachments: Attachments,
+}
+
+impl MessageHandler for AttachmentHandler {
+    fn handle_message(&mut self, _bot: &ActiveBot, message: &Message) -> HandleResult {
+        let wanted = command::parse(&command::message_text(message), self.prefix.as_deref())
+            .into_iter()
+            .any(|x| x == Ok(Command::ImportAttachment));
+        if wanted {
+            let subs = self.fetch(&message.room, &message.sender);
+            self.attachments.set(&message.room, subs);
+        }
+        HandleResult::ContinueHandling
+    }
+}
+
+impl AttachmentHandler {
+    fn fetch(&self, room: &str, sender: &str) -> Imported {
+        let attachment = match self.matrix.latest_attachment(room, sender) {
+            Ok(Some(x)) => x,
+            Ok(None) => {
+                return Err(
+                    "Sorry, I found no file you sent recently (files in encrypted rooms can't be read). Send it first, or paste it after import".to_string(),
+                )
+            }
+            Err(x) => {
+                println!("Could not look up attachments in {}: {}", room, x);
+                return Err("Sorry, I could not look up the files of this room".to_string());
+            }
+        };
+        let content = self.matrix.download(&attachment.url).map_err(|x| {
+            println!("Could not download {}: {}", attachment.url, x);
+            format!("Sorry, I could not download {}", attachment.name)
+        })?;
+        let document = String::from_utf8(content)
+            .map_err(|_| format!("Sorry, {} is no text document", attachment.name))?;
+        command::import_args(&document).map_err(|x| format!("{} ({})", x, attachment.name))
+    }
+}
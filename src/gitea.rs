@@ -0,0 +1,338 @@
+use crate::command::{looks_like_url, message_text, url_segments};
+use crate::common::{
+    escape_html, prepend_prefix, Activate, ConnectionDetails, Frontends, Notifier, RoomSettings,
+    Subscriber, SubscriptionKey,
+};
+use crate::glob::GlobIndex;
+use crate::http_receiver::{EventSource, HttpReceiver};
+use anyhow::{anyhow, Result};
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message};
+use serde::{Deserialize, Serialize};
+
+use std::collections::hash_map::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+const EVENT_PUSH: &str = "push";
+const EVENT_PULL_REQUEST: &str = "pull_request";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GiteaDetails {
+    pub host: String,
+    /// Events have to be signed with it
+    pub secret: String,
+}
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [
+        (
+            "GITEA_REPO_URL",
+            "Subscribe to a git repository. Get notification on pushes and pull requests.",
+        ),
+        (
+            "unsub GITEA_REPO_URL",
+            "Unsubscribe from a git repository. Get no more notifications.",
+        ),
+        (
+            "list repos",
+            "List all repositories currently subscribed to.",
+        ),
+    ];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+#[derive(Debug, Clone, std::cmp::PartialEq, std::cmp::Eq, Hash)]
+struct RepoKey {
+    owner: String,
+    repo: String,
+}
+
+impl std::fmt::Display for RepoKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.owner, self.repo)
+    }
+}
+
+impl SubscriptionKey for RepoKey {}
+
+impl TryFrom<String> for RepoKey {
+    type Error = ();
+
+    fn try_from(line: String) -> Result<Self, Self::Error> {
+        let line = line.trim();
+        if line.contains('\n') {
+            return Err(());
+        }
+
+        if !looks_like_url(line) {
+            return Err(());
+        }
+
+        // Everything after the host: owner/repo[/pulls/123, /src/branch/..., ...]
+        match url_segments(line).as_slice() {
+            [owner, repo, ..] => Ok(RepoKey {
+                owner: owner.to_string(),
+                repo: repo.trim_end_matches(".git").to_string(),
+            }),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct GiteaUser {
+    login: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct GiteaRepository {
+    full_name: String,
+    html_url: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct GiteaCommit {
+    id: String,
+    message: String,
+    url: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct GiteaPushInfo {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    compare_url: Option<String>,
+    commits: Vec<GiteaCommit>,
+    pusher: Option<GiteaUser>,
+    repository: GiteaRepository,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct GiteaPullRequest {
+    number: i64,
+    title: String,
+    html_url: String,
+    state: String,
+    merged: Option<bool>,
+    user: Option<GiteaUser>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct GiteaPullRequestInfo {
+    action: String,
+    pull_request: GiteaPullRequest,
+    repository: GiteaRepository,
+    sender: Option<GiteaUser>,
+}
+
+fn repo_key(repository: &GiteaRepository) -> Result<RepoKey> {
+    match repository.full_name.split_once('/') {
+        Some((owner, repo)) => Ok(RepoKey {
+            owner: owner.to_string(),
+            repo: repo.to_string(),
+        }),
+        None => Err(anyhow!(
+            "Repository name not of the form owner/repo: {}",
+            repository.full_name
+        )),
+    }
+}
+
+fn login(user: &Option<GiteaUser>) -> &str {
+    user.as_ref().map(|x| x.login.as_str()).unwrap_or("someone")
+}
+
+impl MessageHandler for Subscriber<RepoKey> {
+    /// Will be called for every text message send to a room the bot is in
+    fn handle_message(&mut self, _bot: &ActiveBot, message: &Message) -> HandleResult {
+        // Events are pushed to us via HTTP, so there is nothing to register
+        self.handle_message_helper(&message_text(message), &message.room, &message.sender);
+        HandleResult::ContinueHandling
+    }
+}
+
+// Events are pushed to us via HTTP, so there is nothing to register
+impl Activate for Subscriber<RepoKey> {}
+
+impl Subscriber<RepoKey> {
+    fn generate_push_messages(&self, jsondata: &GiteaPushInfo) -> (String, String) {
+        let branch = jsondata.git_ref.trim_start_matches("refs/heads/");
+        let plain = format!(
+            "Push to {} ({}): {} commit(s) by {}",
+            jsondata.repository.full_name,
+            branch,
+            jsondata.commits.len(),
+            login(&jsondata.pusher),
+        );
+
+        let commits = jsondata
+            .commits
+            .iter()
+            .map(|x| {
+                format!(
+                    "<a href=\"{}\">{}</a> {}",
+                    escape_html(&x.url),
+                    escape_html(&x.id[..x.id.len().min(8)]),
+                    escape_html(x.message.lines().next().unwrap_or(""))
+                )
+            })
+            .collect::<Vec<_>>();
+        let html = format!(
+            "<strong>Push</strong> to <a href=\"{}\">{}</a> ({}): <a href=\"{}\">{} commit(s)</a> by {}{}",
+            escape_html(&jsondata.repository.html_url),
+            escape_html(&jsondata.repository.full_name),
+            escape_html(branch),
+            escape_html(
+                jsondata
+                    .compare_url
+                    .as_deref()
+                    .unwrap_or(&jsondata.repository.html_url)
+            ),
+            jsondata.commits.len(),
+            escape_html(login(&jsondata.pusher)),
+            if commits.is_empty() {
+                String::new()
+            } else {
+                format!("<br>{}", commits.join("<br>"))
+            }
+        );
+
+        (plain, html)
+    }
+
+    fn generate_pull_request_messages(&self, jsondata: &GiteaPullRequestInfo) -> (String, String) {
+        let action = if jsondata.action == "closed" && jsondata.pull_request.merged == Some(true) {
+            "merged"
+        } else {
+            jsondata.action.as_str()
+        };
+
+        let plain = format!(
+            "Pull request {}#{} was {} by {}: {}",
+            jsondata.repository.full_name,
+            jsondata.pull_request.number,
+            action,
+            login(&jsondata.sender),
+            jsondata.pull_request.title,
+        );
+        let html = format!(
+            "<a href=\"{}\">Pull request {}#{}</a> was <strong>{}</strong> by {}: {}",
+            escape_html(&jsondata.pull_request.html_url),
+            escape_html(&jsondata.repository.full_name),
+            jsondata.pull_request.number,
+            escape_html(action),
+            escape_html(login(&jsondata.sender)),
+            escape_html(&jsondata.pull_request.title),
+        );
+
+        (plain, html)
+    }
+
+    fn send_to_subscribers<D: Serialize>(
+        &self,
+        event: &str,
+        key: RepoKey,
+        jsondata: &D,
+        plain: &str,
+        html: &str,
+    ) -> Result<()> {
+        let rooms;
+        if let Ok(subscriptions) = self.subscriptions.lock() {
+            // This is a message we are not subscribed to
+            if !subscriptions.contains_key(&key) {
+                return Ok(());
+            }
+
+            rooms = subscriptions[&key].clone();
+        } else {
+            return Ok(());
+        }
+
+        println!("Git {}: {}", event, key);
+
+        let document = self.event_document(&format!("gitea.{}", event), &key, jsondata)?;
+        for room in &rooms {
+            self.notify(room, plain, html, &document);
+        }
+
+        Ok(())
+    }
+}
+
+impl EventSource for Subscriber<RepoKey> {
+    fn event_header(&self) -> &'static str {
+        "X-Gitea-Event"
+    }
+
+    fn signature_header(&self) -> &'static str {
+        "X-Gitea-Signature"
+    }
+
+    fn handle_event(&self, event: &str, body: &str) -> Result<()> {
+        match event {
+            EVENT_PUSH => {
+                let jsondata: GiteaPushInfo = serde_json::from_str(body)?;
+                let (plain, html) = self.generate_push_messages(&jsondata);
+                let key = repo_key(&jsondata.repository)?;
+                self.send_to_subscribers(event, key, &jsondata, &plain, &html)
+            }
+            EVENT_PULL_REQUEST => {
+                let jsondata: GiteaPullRequestInfo = serde_json::from_str(body)?;
+                let (plain, html) = self.generate_pull_request_messages(&jsondata);
+                let key = repo_key(&jsondata.repository)?;
+                self.send_to_subscribers(event, key, &jsondata, &plain, &html)
+            }
+            // Everything else is not interesting (yet)
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn init(
+    frontends: &mut Frontends,
+    receiver: &mut HttpReceiver,
+    notifier: &Notifier,
+    rooms: &RoomSettings,
+    details: &GiteaDetails,
+    prefix: Option<String>,
+    default_subs: &Option<Vec<(String, String)>>,
+) -> Result<()> {
+    let server_details = ConnectionDetails {
+        // Configured hosts live as long as the bot does
+        domain: Box::leak(details.host.clone().into_boxed_str()),
+        login: "",
+        buildprefix: "",
+        rabbitprefix: "",
+        rabbitscope: "",
+    };
+    let mut sub: Subscriber<RepoKey> = Subscriber {
+        subtype: "repos".to_string(),
+        server_details,
+        connection: None,
+        channel: Arc::new(Mutex::new(None)),
+        subnames: Vec::new(),
+        notifier: Arc::new(Mutex::new(notifier.clone())),
+        subscriptions: Arc::new(Mutex::new(HashMap::new())),
+        patterns: Arc::new(Mutex::new(GlobIndex::default())),
+        prefix,
+        rooms: rooms.clone(),
+        api: None,
+        openqa_api: None,
+    };
+
+    if let Some(subs) = default_subs {
+        for (room, url) in subs {
+            sub.subscribe_to_defaults(url, room);
+        }
+    }
+
+    let path = format!("/gitea/{}", details.host);
+    println!("Receiving events of {} on {}", details.host, path);
+    receiver.add_source(path, details.secret.clone(), Box::new(sub.clone()));
+
+    frontends.add_subscriber(sub);
+
+    Ok(())
+}
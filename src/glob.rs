@@ -0,0 +1,229 @@
+//! Glob patterns like "home:me:*/python-*" and an index to match events against
+//! many of them, without scanning all patterns for every event.
+
+use std::collections::HashMap;
+
+/// '*' matches any sequence of characters except '/', '?' any single one
+pub fn is_pattern(text: &str) -> bool {
+    text.contains(['*', '?'])
+}
+
+/// The part of a pattern before its first wildcard
+fn literal_prefix(pattern: &str) -> &str {
+    match pattern.find(['*', '?']) {
+        Some(x) => &pattern[..x],
+        None => pattern,
+    }
+}
+
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last '*' and the text position it was tried at, to backtrack to
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some('?') if text[t] != '/' => {
+                p += 1;
+                t += 1;
+            }
+            Some(x) if *x == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the last '*' eat one more character, but never a '/'
+                Some((star_p, star_t)) if text[star_t] != '/' => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                _ => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|x| *x == '*')
+}
+
+struct Node<V> {
+    children: HashMap<u8, usize>,
+    /// Patterns whose literal prefix ends at this node
+    patterns: Vec<(String, V)>,
+}
+
+impl<V> Default for Node<V> {
+    fn default() -> Self {
+        Node {
+            children: HashMap::new(),
+            patterns: Vec::new(),
+        }
+    }
+}
+
+/// Trie over the literal prefixes of the patterns. A lookup walks the text down the
+/// trie once and only runs the full match on patterns whose prefix fits.
+pub struct GlobIndex<V> {
+    nodes: Vec<Node<V>>,
+}
+
+impl<V> Default for GlobIndex<V> {
+    fn default() -> Self {
+        GlobIndex {
+            nodes: vec![Node::default()],
+        }
+    }
+}
+
+impl<V: PartialEq> GlobIndex<V> {
+    pub fn insert(&mut self, pattern: &str, value: V) {
+        let mut node = 0;
+        for byte in literal_prefix(pattern).bytes() {
+            node = match self.nodes[node].children.get(&byte) {
+                Some(x) => *x,
+                None => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children.insert(byte, child);
+                    child
+                }
+            };
+        }
+        self.nodes[node].patterns.push((pattern.to_string(), value));
+    }
+
+    /// Nodes are kept, even if they end up empty. There are only as many as
+    /// prefixes were ever subscribed to.
+    pub fn remove(&mut self, pattern: &str, value: &V) {
+        let mut node = 0;
+        for byte in literal_prefix(pattern).bytes() {
+            node = match self.nodes[node].children.get(&byte) {
+                Some(x) => *x,
+                None => return,
+            };
+        }
+        self.nodes[node]
+            .patterns
+            .retain(|(x, v)| !(x == pattern && v == value));
+    }
+
+    pub fn matches(&self, text: &str) -> Vec<&V> {
+        let mut res = Vec::new();
+        let mut node = Some(0);
+        let mut bytes = text.bytes();
+        while let Some(current) = node {
+            for (pattern, value) in &self.nodes[current].patterns {
+                if glob_match(pattern, text) {
+                    res.push(value);
+                }
+            }
+            node = bytes
+                .next()
+                .and_then(|x| self.nodes[current].children.get(&x).copied());
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Straightforward, but exponential
+    fn reference_match(pattern: &[char], text: &[char]) -> bool {
+        match pattern.split_first() {
+            None => text.is_empty(),
+            Some(('*', rest)) => (0..=text.len())
+                .take_while(|i| !text[..*i].contains(&'/'))
+                .any(|i| reference_match(rest, &text[i..])),
+            Some(('?', rest)) => {
+                !text.is_empty() && text[0] != '/' && reference_match(rest, &text[1..])
+            }
+            Some((c, rest)) => text.first() == Some(c) && reference_match(rest, &text[1..]),
+        }
+    }
+
+    /// All strings of up to len characters out of chars
+    fn strings(chars: &[char], len: usize) -> Vec<String> {
+        let mut res = vec![String::new()];
+        let mut last = res.clone();
+        for _ in 0..len {
+            last = last
+                .iter()
+                .flat_map(|x| chars.iter().map(move |c| format!("{}{}", x, c)))
+                .collect();
+            res.extend(last.iter().cloned());
+        }
+        res
+    }
+
+    #[test]
+    fn matches_like_the_reference() {
+        let texts = strings(&['a', 'b', '/'], 5);
+        for pattern in strings(&['a', 'b', '/', '*', '?'], 4) {
+            let p: Vec<char> = pattern.chars().collect();
+            for text in &texts {
+                let t: Vec<char> = text.chars().collect();
+                assert_eq!(
+                    glob_match(&pattern, text),
+                    reference_match(&p, &t),
+                    "{} on {}",
+                    pattern,
+                    text
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn wildcards_stop_at_slashes() {
+        assert!(glob_match(
+            "home:me:*/python-*",
+            "home:me:branches/python-foo"
+        ));
+        assert!(!glob_match("home:me:*/python-*", "home:me:a/b/python-foo"));
+        assert!(!glob_match("home:me*", "home:me/foo"));
+        assert!(glob_match("home:me*/*", "home:me/foo"));
+        assert!(!glob_match("home:me?foo", "home:me/foo"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("?", ""));
+        assert!(glob_match("a*", "a"));
+        assert!(!glob_match("a", "ab"));
+    }
+
+    #[test]
+    fn question_mark_is_one_character() {
+        assert!(glob_match("gr??e", "größe"));
+        assert!(!glob_match("gr?e", "größe"));
+    }
+
+    #[test]
+    fn index_finds_matching_patterns() {
+        let mut index = GlobIndex::default();
+        index.insert("home:me:*/python-*", 1);
+        index.insert("home:me*/*", 2);
+        index.insert("*/foo", 3);
+        index.insert("devel:*/*", 4);
+
+        let mut found: Vec<_> = index
+            .matches("home:me:x/python-a")
+            .into_iter()
+            .copied()
+            .collect();
+        found.sort();
+        assert_eq!(found, vec![1, 2]);
+        assert_eq!(index.matches("home:me/foo").len(), 2);
+        assert!(index.matches("home:you/bar").is_empty());
+
+        index.remove("home:me*/*", &2);
+        // Only removes the given value
+        index.remove("*/foo", &1);
+        assert_eq!(index.matches("home:me/foo"), vec![&3]);
+    }
+}
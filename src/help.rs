@@ -1,31 +1,191 @@
 use crate::build_res;
+use crate::command::{self, looks_like_url, Command, UrlArgs};
+use crate::common::{prepend_prefix, Notifier, Registry, RoomMode, RoomSettings};
+use crate::digest;
+use crate::dm;
+use crate::email;
+use crate::export;
+use crate::gitea;
 use crate::leave;
+use crate::list;
 use crate::openqa;
+use crate::snooze;
+use crate::status;
 use crate::submitrequests;
+use crate::unsub;
+use crate::users;
+use crate::webhook;
 
-use matrix_bot_api::handlers::{extract_command, HandleResult, MessageHandler};
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
 use matrix_bot_api::{ActiveBot, Message, MessageType};
 
-#[derive(Debug)]
+#[derive(Clone)]
 pub struct HelpHandler {
     pub prefix: Option<String>,
+    pub notifier: Notifier,
+    pub git_hosting: bool,
+    pub obs_api: bool,
+    pub registry: Registry,
+    pub rooms: RoomSettings,
 }
 
 impl MessageHandler for HelpHandler {
-    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
-        let command = match extract_command(&message.body, self.prefix.as_deref().unwrap_or("")) {
-            Some(x) => x,
-            None => return HandleResult::ContinueHandling,
-        };
-        if command != "help" {
-            return HandleResult::ContinueHandling;
+    /// Answers help and reports commands nobody else can handle
+    fn handle_message(&mut self, _bot: &ActiveBot, message: &Message) -> HandleResult {
+        for command in command::parse(&command::message_text(message), self.prefix.as_deref()) {
+            let answer = match command {
+                Ok(Command::Help) => {
+                    self.send_help(&message.room);
+                    continue;
+                }
+                Ok(Command::Subscribe(args))
+                    if !args.explicit && self.rooms.mode(&message.room) == RoomMode::Explicit =>
+                {
+                    continue
+                }
+                Ok(Command::Subscribe(args)) | Ok(Command::Unsubscribe(args)) => {
+                    // Part of the summary the ReplyHandler sends
+                    if let Some(reason) = self.check_resource(&args, &message.room) {
+                        self.notifier
+                            .replies
+                            .failed(&message.room, &args.url, &reason);
+                    }
+                    continue;
+                }
+                Ok(Command::ImportAttachment) => {
+                    match self.notifier.attachments.get(&message.room) {
+                        Ok(subs) => {
+                            for args in subs {
+                                if let Some(reason) = self.check_resource(&args, &message.room) {
+                                    self.notifier
+                                        .replies
+                                        .failed(&message.room, &args.url, &reason);
+                                }
+                            }
+                            continue;
+                        }
+                        Err(x) => x,
+                    }
+                }
+                Ok(Command::Import(subs)) => {
+                    for args in subs {
+                        if let Some(reason) = self.check_resource(&args, &message.room) {
+                            self.notifier
+                                .replies
+                                .failed(&message.room, &args.url, &reason);
+                        }
+                    }
+                    continue;
+                }
+                Ok(Command::Backend(None)) => match self.rooms.backend(&message.room) {
+                    Some(x) => format!("Short forms in this room use {}", x),
+                    None => format!(
+                        "No backend set for short forms in this room. Choose one of: {}",
+                        self.rooms.backends().join(", ")
+                    ),
+                },
+                Ok(Command::Backend(Some(backend))) => {
+                    match self.rooms.set_backend(&message.room, &backend) {
+                        Ok(x) | Err(x) => x,
+                    }
+                }
+                Ok(Command::Mode(None)) => {
+                    format!("This room is in {} mode", self.rooms.mode(&message.room))
+                }
+                Ok(Command::Mode(Some(mode))) => {
+                    match self.rooms.set_mode(&message.room, &mode, &message.sender) {
+                        Ok(x) | Err(x) => x,
+                    }
+                }
+                Ok(_) => continue,
+                Err(x) => x.to_string(),
+            };
+            self.notifier
+                .send_message(&answer, &message.room, MessageType::TextMessage);
+        }
+        // The other handlers may have something to say about the rest of the message
+        HandleResult::ContinueHandling
+    }
+}
+
+impl HelpHandler {
+    /// Returns the reason, if no subscriber will pick up the URL or short form
+    fn check_resource(&self, args: &UrlArgs, room: &str) -> Option<String> {
+        if looks_like_url(&args.url) {
+            return if args.explicit && !self.registry.handles(&args.url) {
+                Some("none of my backends handles it".to_string())
+            } else {
+                None
+            };
         }
 
+        let candidates = self.registry.candidates(&args.url);
+        let backend = self.rooms.backend(room);
+        if let Some(backend) = &backend {
+            if candidates.iter().any(|(x, _)| x == backend) {
+                return None;
+            }
+        }
+
+        match (candidates.as_slice(), backend) {
+            ([], _) => Some("none of my backends handles it".to_string()),
+            ([(_, url)], Some(backend)) => Some(format!(
+                "it does not exist on {}, only as {}",
+                backend, url
+            )),
+            ([(_, url)], None) => Some(format!(
+                "it could only be {}. Please paste the full URL or set a backend for this room.",
+                url
+            )),
+            _ => Some(format!(
+                "it is ambiguous, it could be any of: {}. Pick one with \"backend DOMAIN\" or paste the full URL.",
+                candidates
+                    .iter()
+                    .map(|(_, url)| url.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
+
+    fn send_help(&self, room: &str) {
         let mut items = vec![("help".to_string(), "Print this help".to_string())];
         items.append(&mut leave::help_str(self.prefix.as_deref()));
+        items.append(&mut prepend_prefix(
+            self.prefix.as_deref(),
+            &[
+                (
+                    "backend [DOMAIN]",
+                    "Show or set the backend for short forms like SR#1234 in this room.",
+                ),
+                (
+                    "mode [passive|explicit|preview]",
+                    "Show or set what pasted URLs do in this room: subscribe (passive), nothing (explicit, only \"sub URL\" subscribes) or show what they are (preview). Only admins can change it.",
+                ),
+            ],
+        ));
+        items.append(&mut list::help_str(self.prefix.as_deref()));
+        items.append(&mut export::help_str(self.prefix.as_deref()));
+        items.append(&mut unsub::help_str(self.prefix.as_deref()));
+        items.append(&mut snooze::help_str(self.prefix.as_deref()));
+        items.append(&mut digest::help_str(self.prefix.as_deref()));
+        items.append(&mut users::help_str(self.prefix.as_deref()));
+        items.append(&mut dm::help_str(self.prefix.as_deref()));
         items.append(&mut build_res::help_str(self.prefix.as_deref()));
         items.append(&mut submitrequests::help_str(self.prefix.as_deref()));
         items.append(&mut openqa::help_str(self.prefix.as_deref()));
+        if self.obs_api {
+            items.append(&mut status::help_str(self.prefix.as_deref()));
+        }
+        if self.git_hosting {
+            items.append(&mut gitea::help_str(self.prefix.as_deref()));
+        }
+        if self.notifier.email.is_some() {
+            items.append(&mut email::help_str(self.prefix.as_deref()));
+        }
+        if self.notifier.webhooks.is_some() {
+            items.append(&mut webhook::help_str(self.prefix.as_deref()));
+        }
 
         let mut plainmsg = "Hi, I'm a friendly robot and provide these options:".to_string();
         for (key, text) in &items {
@@ -44,7 +204,7 @@ impl MessageHandler for HelpHandler {
         htmlmsg += "\n";
         htmlmsg += "</table>";
 
-        bot.send_html_message(&plainmsg, &htmlmsg, &message.room, MessageType::TextMessage);
-        HandleResult::StopHandling
+        self.notifier
+            .send_html_message(&plainmsg, &htmlmsg, room, MessageType::TextMessage);
     }
 }
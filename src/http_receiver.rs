@@ -0,0 +1,138 @@
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use tiny_http::{Method, Request, Response, Server};
+
+use std::collections::HashMap;
+use std::io::Read;
+
+// Larger payloads are rejected without reading them completely
+const MAX_BODY_SIZE: usize = 5 * 1024 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HttpReceiverDetails {
+    pub listen: String,
+}
+
+/// An event source that pushes its events via HTTP instead of AMQP
+pub trait EventSource: Send {
+    /// Header that carries the type of event (e.g. "push")
+    fn event_header(&self) -> &'static str;
+    /// Header that carries the hex-encoded HMAC-SHA256 of the body
+    fn signature_header(&self) -> &'static str;
+    fn handle_event(&self, event: &str, body: &str) -> Result<()>;
+}
+
+struct Source {
+    secret: String,
+    handler: Box<dyn EventSource>,
+}
+
+/// Optional HTTP listener. Each source gets its own path to POST events to.
+pub struct HttpReceiver {
+    details: HttpReceiverDetails,
+    sources: HashMap<String, Source>,
+}
+
+fn header_value(request: &Request, name: &'static str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|x| x.field.equiv(name))
+        .map(|x| x.value.as_str().to_string())
+}
+
+fn verify_signature(secret: &str, signature: &str, body: &[u8]) -> bool {
+    // Some senders prefix the signature like GitHub does
+    let signature = signature.trim().trim_start_matches("sha256=");
+    let signature = match hex::decode(signature) {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    // HMAC accepts keys of any length, so this can't fail
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+impl HttpReceiver {
+    pub fn new(details: HttpReceiverDetails) -> HttpReceiver {
+        HttpReceiver {
+            details,
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Only events signed with the secret are accepted
+    pub fn add_source(&mut self, path: String, secret: String, handler: Box<dyn EventSource>) {
+        self.sources.insert(path, Source { secret, handler });
+    }
+
+    /// Binds the listener and handles requests in a separate thread
+    pub fn run(self) -> Result<()> {
+        let server = Server::http(&self.details.listen)
+            .map_err(|x| anyhow!("Could not listen on {}: {}", self.details.listen, x))?;
+        println!("LISTENING ON http://{}", self.details.listen);
+
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                self.handle_request(request);
+            }
+        });
+        Ok(())
+    }
+
+    fn handle_request(&self, mut request: Request) {
+        let (status, answer) = match self.process(&mut request) {
+            Ok(()) => (200, "OK".to_string()),
+            Err((status, answer)) => {
+                println!(
+                    "Rejected HTTP event on {}: {} {}",
+                    request.url(),
+                    status,
+                    answer
+                );
+                (status, answer)
+            }
+        };
+
+        let _ = request.respond(Response::from_string(answer).with_status_code(status));
+    }
+
+    fn process(&self, request: &mut Request) -> Result<(), (u16, String)> {
+        if request.method() != &Method::Post {
+            return Err((405, "Only POST is supported".to_string()));
+        }
+
+        let source = self
+            .sources
+            .get(request.url())
+            .ok_or_else(|| (404, "Unknown event source".to_string()))?;
+
+        if request.body_length().unwrap_or(0) > MAX_BODY_SIZE {
+            return Err((413, "Payload too large".to_string()));
+        }
+        let mut body = Vec::new();
+        request
+            .as_reader()
+            .take(MAX_BODY_SIZE as u64)
+            .read_to_end(&mut body)
+            .map_err(|x| (400, format!("Could not read body: {}", x)))?;
+
+        let signature = header_value(request, source.handler.signature_header())
+            .ok_or_else(|| (401, "Signature missing".to_string()))?;
+        if !verify_signature(&source.secret, &signature, &body) {
+            return Err((401, "Signature invalid".to_string()));
+        }
+
+        let event = header_value(request, source.handler.event_header())
+            .ok_or_else(|| (400, "Event type missing".to_string()))?;
+        let body = std::str::from_utf8(&body).map_err(|x| (400, x.to_string()))?;
+
+        source
+            .handler
+            .handle_event(&event, body)
+            .map_err(|x| (400, x.to_string()))
+    }
+}
@@ -0,0 +1,546 @@
+use crate::common::Registry;
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message};
+use native_tls::TlsConnector;
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+// How long a read may block before we look for queued outgoing lines again
+const READ_TIMEOUT: Duration = Duration::from_millis(200);
+// Waiting time before reconnecting, doubled after every failed attempt
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+// Limit of a line including the trailing CRLF
+const MAX_LINE: usize = 512;
+// The server prepends ":nick!user@host " when relaying our lines, which counts against MAX_LINE too
+const SOURCE_RESERVE: usize = 100;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IrcDetails {
+    pub server: String,
+    pub port: u16,
+    #[serde(default)]
+    pub use_tls: bool,
+    pub nick: String,
+    pub password: Option<String>,
+    pub channels: Vec<String>,
+}
+
+pub fn is_irc_channel(room: &str) -> bool {
+    room.starts_with('#') || room.starts_with('&')
+}
+
+// Most servers use the rfc1459 casemapping, where []\~ are the uppercase of {}|^
+fn irc_lowercase(c: char) -> char {
+    match c {
+        '[' => '{',
+        ']' => '}',
+        '\\' => '|',
+        '~' => '^',
+        _ => c.to_ascii_lowercase(),
+    }
+}
+
+/// Nicks differing only in case are the same user
+fn nick_eq(a: &str, b: &str) -> bool {
+    a.chars()
+        .map(irc_lowercase)
+        .eq(b.chars().map(irc_lowercase))
+}
+
+/// Splits line into parts of at most max bytes, without splitting characters
+fn split_line(line: &str, max: usize) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = line;
+    while rest.len() > max {
+        let mut end = max;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            // max is smaller than the first character, which has to go out whole
+            end = rest
+                .chars()
+                .next()
+                .map(char::len_utf8)
+                .unwrap_or(rest.len());
+        }
+        parts.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    if !rest.is_empty() || parts.is_empty() {
+        parts.push(rest);
+    }
+    parts
+}
+
+/// Handle to queue messages for the IRC connection. Cheap to clone.
+#[derive(Clone)]
+pub struct IrcSender {
+    tx: Sender<String>,
+}
+
+impl IrcSender {
+    pub fn send_message(&self, msg: &str, channel: &str) {
+        let head = format!("PRIVMSG {} :", channel);
+        let max = MAX_LINE.saturating_sub(head.len() + 2 + SOURCE_RESERVE);
+        // IRC has no multi-line messages, so every line gets its own PRIVMSG
+        for line in msg.lines().filter(|x| !x.trim().is_empty()) {
+            for part in split_line(line, max) {
+                self.send_raw(format!("{}{}", head, part));
+            }
+        }
+    }
+
+    /// Leaves the channel. The server confirms it, see IrcClient::handle_line().
+    pub fn part(&self, channel: &str) {
+        self.send_raw(format!("PART {}", channel));
+    }
+
+    fn send_raw(&self, line: String) {
+        if self.tx.send(line).is_err() {
+            println!("IRC connection is gone, dropping message");
+        }
+    }
+}
+
+trait IrcStream: Read + Write + Send {}
+impl<T: Read + Write + Send> IrcStream for T {}
+
+/// Second frontend next to Matrix. Channels are treated like rooms and
+/// all messages are given to the registered handlers, just like MatrixBot does.
+/// Lost connections are reestablished, messages queued meanwhile are sent then.
+pub struct IrcClient {
+    details: IrcDetails,
+    nick: String,
+    /// Joined on (re)connect: the configured ones, minus those we left, plus those we got invited to
+    channels: Vec<String>,
+    /// Until the server welcomes us, only the lines for the registration are sent
+    registered: bool,
+    /// Answers to the server, sent before the queued messages
+    protocol: Vec<String>,
+    tx: Sender<String>,
+    rx: Receiver<String>,
+    handlers: Vec<Box<dyn MessageHandler + Send>>,
+    /// To drop the subscriptions of channels we get kicked from
+    registry: Registry,
+}
+
+struct IrcLine<'a> {
+    prefix: Option<&'a str>,
+    command: &'a str,
+    params: Vec<&'a str>,
+}
+
+fn parse_line(line: &str) -> Option<IrcLine<'_>> {
+    let mut rest = line.trim_end();
+    // IRCv3 message tags are not used by us
+    if rest.starts_with('@') {
+        rest = rest.split_once(' ')?.1;
+    }
+
+    let mut prefix = None;
+    if let Some(stripped) = rest.strip_prefix(':') {
+        let (p, r) = stripped.split_once(' ')?;
+        prefix = Some(p);
+        rest = r;
+    }
+
+    let (command, mut rest) = match rest.split_once(' ') {
+        Some((c, r)) => (c, r),
+        None => (rest, ""),
+    };
+
+    let mut params = Vec::new();
+    while !rest.is_empty() {
+        if let Some(trailing) = rest.strip_prefix(':') {
+            params.push(trailing);
+            break;
+        }
+        match rest.split_once(' ') {
+            Some((p, r)) => {
+                params.push(p);
+                rest = r;
+            }
+            None => {
+                params.push(rest);
+                break;
+            }
+        }
+    }
+
+    Some(IrcLine {
+        prefix,
+        command,
+        params,
+    })
+}
+
+impl IrcClient {
+    pub fn new(details: IrcDetails, registry: Registry) -> IrcClient {
+        let (tx, rx) = channel();
+        IrcClient {
+            nick: details.nick.clone(),
+            channels: details.channels.clone(),
+            registered: false,
+            protocol: Vec::new(),
+            details,
+            tx,
+            rx,
+            handlers: Vec::new(),
+            registry,
+        }
+    }
+
+    pub fn sender(&self) -> IrcSender {
+        IrcSender {
+            tx: self.tx.clone(),
+        }
+    }
+
+    /// Add an additional handler.
+    /// Each message will be given to all registered handlers until
+    /// one of them returns "HandleResult::StopHandling".
+    pub fn add_handler<M>(&mut self, handler: M)
+    where
+        M: MessageHandler + 'static + Send,
+    {
+        self.handlers.push(Box::new(handler));
+    }
+
+    /// Connects to the server and handles the connection in a separate thread.
+    /// Handlers get the given ActiveBot, but are expected to answer via the Notifier.
+    pub fn run(mut self, bot: ActiveBot) -> Result<()> {
+        let stream = self.connect()?;
+        std::thread::spawn(move || self.connection_loop(stream, bot));
+        Ok(())
+    }
+
+    fn connect(&mut self) -> Result<Box<dyn IrcStream>> {
+        let addr = format!("{}:{}", self.details.server, self.details.port);
+        let tcp = TcpStream::connect(&addr)?;
+
+        // The read timeout is only set after the TLS handshake, which would otherwise fail
+        let stream: Box<dyn IrcStream> = if self.details.use_tls {
+            let connector = TlsConnector::new()?;
+            let tls = connector
+                .connect(&self.details.server, tcp)
+                .map_err(|x| anyhow!("TLS handshake with {} failed: {}", addr, x))?;
+            tls.get_ref().set_read_timeout(Some(READ_TIMEOUT))?;
+            Box::new(tls)
+        } else {
+            tcp.set_read_timeout(Some(READ_TIMEOUT))?;
+            Box::new(tcp)
+        };
+        println!("CONNECTED TO irc://{}", &addr);
+
+        self.nick = self.details.nick.clone();
+        self.registered = false;
+        self.protocol.clear();
+        if let Some(password) = &self.details.password {
+            self.protocol.push(format!("PASS {}", password));
+        }
+        self.protocol.push(format!("NICK {}", self.nick));
+        self.protocol
+            .push(format!("USER {} 0 * :obs_chat_bot", self.nick));
+        Ok(stream)
+    }
+
+    fn connection_loop(mut self, mut stream: Box<dyn IrcStream>, bot: ActiveBot) {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let err = self.io_loop(stream, &bot);
+            println!("IRC connection lost: {}", err);
+            // Only back off further if we never got a working connection
+            if self.registered {
+                backoff = MIN_BACKOFF;
+            }
+            stream = loop {
+                println!("Reconnecting to IRC in {} seconds", backoff.as_secs());
+                std::thread::sleep(backoff);
+                backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+                match self.connect() {
+                    Ok(x) => break x,
+                    Err(x) => println!("Could not connect to IRC: {}", x),
+                }
+            };
+        }
+    }
+
+    fn next_queued(&self) -> Option<String> {
+        if self.registered {
+            self.rx.try_recv().ok()
+        } else {
+            None
+        }
+    }
+
+    /// Handles the connection until it fails
+    fn io_loop(&mut self, mut stream: Box<dyn IrcStream>, bot: &ActiveBot) -> anyhow::Error {
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            for line in self.protocol.drain(..) {
+                if let Err(x) = stream.write_all(format!("{}\r\n", line).as_bytes()) {
+                    return anyhow!("Error while writing to IRC: {:?}", x);
+                }
+            }
+            // Until the server welcomes us, messages wait in the queue
+            while let Some(line) = self.next_queued() {
+                if let Err(x) = stream.write_all(format!("{}\r\n", line).as_bytes()) {
+                    return anyhow!("Error while writing to IRC: {:?}", x);
+                }
+            }
+
+            match stream.read(&mut chunk) {
+                Ok(0) => return anyhow!("Connection closed by server"),
+                Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+                Err(x) if x.kind() == ErrorKind::WouldBlock || x.kind() == ErrorKind::TimedOut => {
+                    continue;
+                }
+                Err(x) => return anyhow!("Error while reading from IRC: {:?}", x),
+            }
+
+            while let Some(pos) = buffer.iter().position(|x| *x == b'\n') {
+                let raw: Vec<u8> = buffer.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&raw);
+                if let Some(line) = parse_line(&line) {
+                    self.handle_line(line, bot);
+                }
+            }
+        }
+    }
+
+    fn handle_line(&mut self, line: IrcLine, bot: &ActiveBot) {
+        let nick = line.prefix.and_then(|x| x.split('!').next()).unwrap_or("");
+        let from_us = nick_eq(nick, &self.nick);
+        match line.command {
+            "PING" => self
+                .protocol
+                .push(format!("PONG :{}", line.params.join(" "))),
+            // Welcome, we are registered now
+            "001" => {
+                self.registered = true;
+                for channel in &self.channels {
+                    self.protocol.push(format!("JOIN {}", channel));
+                }
+            }
+            // Nick already in use
+            "433" => {
+                self.nick.push('_');
+                self.protocol.push(format!("NICK {}", self.nick));
+            }
+            "INVITE" if line.params.len() == 2 && nick_eq(line.params[0], &self.nick) => {
+                self.protocol.push(format!("JOIN {}", line.params[1]));
+            }
+            "JOIN" if from_us && !line.params.is_empty() => {
+                let channel = line.params[0].to_string();
+                if !self.channels.contains(&channel) {
+                    self.channels.push(channel);
+                }
+            }
+            // Confirmation of leave
+            "PART" if from_us && !line.params.is_empty() => {
+                self.channels.retain(|x| x != line.params[0]);
+            }
+            // We can't post there anymore, until somebody invites us back
+            "KICK" if line.params.len() >= 2 && nick_eq(line.params[1], &self.nick) => {
+                println!("Kicked from {}", line.params[0]);
+                self.channels.retain(|x| x != line.params[0]);
+                self.registry.purge(line.params[0]);
+            }
+            "PRIVMSG" if line.params.len() == 2 => {
+                let room = line.params[0];
+                // Only channels are treated as rooms, private queries are ignored
+                if !is_irc_channel(room) || from_us {
+                    return;
+                }
+                let message = Message {
+                    sender: nick.to_string(),
+                    mtype: "m.text".to_string(),
+                    body: line.params[1].to_string(),
+                    date: Local::now(),
+                    room: room.to_string(),
+                    thumb: None,
+                    url: None,
+                    id: String::new(),
+                    formatted_body: None,
+                    format: None,
+                    source: None,
+                    receipt: HashMap::new(),
+                    redacted: false,
+                    in_reply_to: None,
+                    extra_content: None,
+                };
+                for handler in self.handlers.iter_mut() {
+                    match handler.handle_message(bot, &message) {
+                        HandleResult::ContinueHandling => continue,
+                        HandleResult::StopHandling => break,
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::RoomSettings;
+    use crate::leave::LeaveHandler;
+    use matrix_bot_api::MatrixBot;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    #[test]
+    fn nicks_ignore_case() {
+        assert!(nick_eq("obsbot", "OBSBot"));
+        assert!(nick_eq("bot[1]", "BOT{1}"));
+        assert!(nick_eq("a\\b~", "a|b^"));
+        assert!(!nick_eq("obsbot", "obsbot_"));
+    }
+
+    #[test]
+    fn split_keeps_characters_whole() {
+        assert_eq!(split_line("short", 10), vec!["short"]);
+        assert_eq!(split_line("abcdef", 3), vec!["abc", "def"]);
+        // 'ä' takes two bytes and must not be cut in half
+        assert_eq!(split_line("aää", 2), vec!["a", "ä", "ä"]);
+        assert_eq!(split_line("€", 1), vec!["€"]);
+    }
+
+    #[test]
+    fn privmsg_fits_the_line_limit() {
+        let (tx, rx) = channel();
+        let sender = IrcSender { tx };
+        sender.send_message(&"ü".repeat(1000), "#obs");
+        let lines: Vec<String> = rx.try_iter().collect();
+        assert!(lines.len() > 1);
+        for line in &lines {
+            assert!(line.len() + 2 + SOURCE_RESERVE <= MAX_LINE);
+            assert!(line.starts_with("PRIVMSG #obs :ü"));
+        }
+        let text: String = lines.iter().map(|x| &x["PRIVMSG #obs :".len()..]).collect();
+        assert_eq!(text, "ü".repeat(1000));
+    }
+
+    /// Passes (sender, channel, text) of the messages it gets on to the test
+    struct Recorder(Sender<(String, String, String)>);
+
+    impl MessageHandler for Recorder {
+        fn handle_message(&mut self, _bot: &ActiveBot, message: &Message) -> HandleResult {
+            let _ = self.0.send((
+                message.sender.clone(),
+                message.room.clone(),
+                message.body.clone(),
+            ));
+            HandleResult::ContinueHandling
+        }
+    }
+
+    /// The server side of the connection, like a local ircd sees it
+    struct Ircd {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Ircd {
+        fn send(&mut self, line: &str) {
+            self.writer
+                .write_all(format!("{}\r\n", line).as_bytes())
+                .unwrap();
+        }
+
+        /// Reads lines of the bot until the expected one, returns those before it
+        fn until(&mut self, expected: &str) -> Vec<String> {
+            let mut seen = Vec::new();
+            loop {
+                let mut line = String::new();
+                match self.reader.read_line(&mut line) {
+                    Ok(n) if n > 0 => {}
+                    x => panic!("no {:?} after {:?}: {:?}", expected, seen, x),
+                }
+                let line = line.trim_end_matches(['\r', '\n']).to_string();
+                if line == expected {
+                    return seen;
+                }
+                seen.push(line);
+            }
+        }
+    }
+
+    #[test]
+    fn against_local_ircd() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let details = IrcDetails {
+            server: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            use_tls: false,
+            nick: "obsbot".to_string(),
+            password: None,
+            channels: vec!["#obs".to_string()],
+        };
+        let registry = Registry::default();
+        let mut client = IrcClient::new(details, registry.clone());
+        client.add_handler(LeaveHandler {
+            prefix: Some("obsbot: ".to_string()),
+            registry,
+            irc: Some(client.sender()),
+            rooms: RoomSettings::new(Vec::new(), vec!["admin".to_string()]),
+        });
+        let (tx, messages) = channel();
+        client.add_handler(Recorder(tx));
+        let bot = MatrixBot::new(Recorder(channel().0)).get_activebot_clone();
+        client.run(bot).unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut ircd = Ircd {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        };
+        assert_eq!(ircd.until("USER obsbot 0 * :obs_chat_bot"), ["NICK obsbot"]);
+        ircd.send(":irc.local 001 obsbot :Welcome");
+        ircd.until("JOIN #obs");
+        ircd.send(":obsbot!bot@local JOIN #obs");
+        ircd.send("PING :irc.local");
+        ircd.until("PONG :irc.local");
+
+        // Channel messages reach the handlers, queries don't
+        ircd.send(":someone!s@local PRIVMSG obsbot :hello");
+        ircd.send(":someone!s@local PRIVMSG #obs :list packages");
+        assert_eq!(
+            messages.recv_timeout(Duration::from_secs(10)).unwrap(),
+            (
+                "someone".to_string(),
+                "#obs".to_string(),
+                "list packages".to_string()
+            )
+        );
+
+        // Anybody could pretend to be an admin by nick, but not without one
+        ircd.send(":mallory!m@local PRIVMSG #obs :obsbot: leave");
+        ircd.until("PRIVMSG #obs :Sorry, only admins can make me leave IRC channels");
+        ircd.send(":admin!a@local PRIVMSG #obs :obsbot: shutdown");
+        ircd.until("PRIVMSG #obs :Sorry, I can only be shut down on Matrix");
+        ircd.send("PING :check");
+        let lines = ircd.until("PONG :check");
+        assert!(lines.iter().all(|x| !x.starts_with("PART")), "{:?}", lines);
+
+        ircd.send(":admin!a@local PRIVMSG #obs :obsbot: leave");
+        ircd.until("PRIVMSG #obs :Bye!");
+        ircd.until("PART #obs");
+        // Handled commands don't reach later handlers
+        assert!(messages.try_recv().is_err());
+    }
+}
@@ -1,36 +1,91 @@
-use crate::common::prepend_prefix;
+use crate::command::{self, Command};
+use crate::common::{prepend_prefix, Registry, RoomSettings};
+use crate::irc::{is_irc_channel, IrcSender};
 use matrix_bot_api::handlers::HandleResult::{ContinueHandling, StopHandling};
-use matrix_bot_api::handlers::{HandleResult, StatelessHandler};
-use matrix_bot_api::{ActiveBot, MatrixBot, Message, MessageType};
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
 
-pub fn shutdown(bot: &ActiveBot, message: &Message, _cmd: &str) -> HandleResult {
-    bot.send_message("Bye!", &message.room, MessageType::RoomNotice);
-    bot.shutdown();
-    ContinueHandling
+/// Handles leave and shutdown. Leaving a room also drops its subscriptions, as
+/// the bot can't post there anymore.
+/// Anybody can take an IRC nick, so on IRC only admins can make the bot leave
+/// and nobody can shut it down.
+#[derive(Clone)]
+pub struct LeaveHandler {
+    pub prefix: Option<String>,
+    pub registry: Registry,
+    /// To part IRC channels
+    pub irc: Option<IrcSender>,
+    pub rooms: RoomSettings,
 }
 
-pub fn leave(bot: &ActiveBot, message: &Message, _cmd: &str) -> HandleResult {
-    bot.send_message("Bye!", &message.room, MessageType::RoomNotice);
-    bot.leave_room(&message.room);
-    StopHandling
+impl MessageHandler for LeaveHandler {
+    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
+        // Only whole commands, not mentions of them in a longer message
+        let text = command::message_text(message);
+        let command = match command::parse(&text, self.prefix.as_deref()).as_slice() {
+            [Ok(x)] => x.clone(),
+            _ => return ContinueHandling,
+        };
+        match command {
+            Command::Leave
+                if is_irc_channel(&message.room) && !self.rooms.is_admin(&message.sender) =>
+            {
+                self.answer(
+                    bot,
+                    "Sorry, only admins can make me leave IRC channels",
+                    &message.room,
+                );
+                StopHandling
+            }
+            Command::Leave => {
+                self.bye(bot, &message.room);
+                self.registry.purge(&message.room);
+                if !is_irc_channel(&message.room) {
+                    bot.leave_room(&message.room);
+                } else if let Some(irc) = &self.irc {
+                    irc.part(&message.room);
+                }
+                StopHandling
+            }
+            Command::Shutdown if is_irc_channel(&message.room) => {
+                self.answer(
+                    bot,
+                    "Sorry, I can only be shut down on Matrix",
+                    &message.room,
+                );
+                StopHandling
+            }
+            Command::Shutdown => {
+                self.bye(bot, &message.room);
+                bot.shutdown();
+                ContinueHandling
+            }
+            _ => ContinueHandling,
+        }
+    }
 }
 
-pub fn register_handler(bot: &mut MatrixBot, prefix: Option<&str>) {
-    let mut handler = StatelessHandler::new();
-    match prefix {
-        Some(x) => handler.set_cmd_prefix(x),
-        None => handler.set_cmd_prefix(""),
+impl LeaveHandler {
+    fn bye(&self, bot: &ActiveBot, room: &str) {
+        self.answer(bot, "Bye!", room);
     }
 
-    handler.register_handle("leave", leave);
-    handler.register_handle("shutdown", shutdown);
-    bot.add_handler(handler);
+    fn answer(&self, bot: &ActiveBot, text: &str, room: &str) {
+        if !is_irc_channel(room) {
+            bot.send_message(text, room, MessageType::RoomNotice);
+        } else if let Some(irc) = &self.irc {
+            irc.send_message(text, room);
+        }
+    }
 }
 
 pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
     let without_prefix = [
-        ("leave", "Leave the current room"),
-        ("shutdown", "Shutdown the bot completely"),
+        (
+            "leave",
+            "Leave the current room and drop all of its subscriptions (on IRC only for admins)",
+        ),
+        ("shutdown", "Shutdown the bot completely (only on Matrix)"),
     ];
 
     prepend_prefix(prefix, &without_prefix)
@@ -0,0 +1,269 @@
+use crate::command::{self, is_same_kind, Command, ListArgs};
+use crate::common::{
+    escape_html, prepend_prefix, Notifier, Registry, RoomSettings, SubscriptionInfo,
+};
+use crate::dm::{resolve_target, ME};
+use crate::glob::{glob_match, is_pattern};
+
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+
+// Rows per message, larger rooms get pages
+const PAGE_SIZE: usize = 50;
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [(
+        "list [me] [all|TYPE] [FILTER] [page N]",
+        "List the subscriptions of this room on all backends, e.g. \"list packages home:me*\".",
+    )];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+/// Lists subscriptions of all subscribers and backends in one message
+#[derive(Clone)]
+pub struct ListHandler {
+    pub prefix: Option<String>,
+    pub notifier: Notifier,
+    pub registry: Registry,
+    pub rooms: RoomSettings,
+}
+
+impl MessageHandler for ListHandler {
+    fn handle_message(&mut self, _bot: &ActiveBot, message: &Message) -> HandleResult {
+        for command in command::parse(&command::message_text(message), self.prefix.as_deref())
+            .into_iter()
+            .flatten()
+        {
+            if let Command::List(args) = command {
+                let (plain, html) = match self.list(&args, &message.room, &message.sender) {
+                    Ok(x) => x,
+                    Err(x) => {
+                        let html = escape_html(&x);
+                        (x, html)
+                    }
+                };
+                self.notifier.send_html_message(
+                    &plain,
+                    &html,
+                    &message.room,
+                    MessageType::TextMessage,
+                );
+            }
+        }
+        HandleResult::ContinueHandling
+    }
+}
+
+/// Keys containing the filter, or matching it (or a project part of them) as a pattern
+pub fn matches_filter(filter: &str, key: &str) -> bool {
+    if !is_pattern(filter) {
+        return key.to_lowercase().contains(&filter.to_lowercase());
+    }
+    glob_match(filter, key)
+        || key
+            .match_indices('/')
+            .any(|(end, _)| glob_match(filter, &key[..end]))
+}
+
+/// "package" and "packages" are both listed as "packages"
+pub fn plural(kind: &str) -> String {
+    format!("{}s", kind.trim_end_matches('s'))
+}
+
+/// The number of pages and the rows of the page, if there is such a page
+fn page_rows(rows: usize, page: usize) -> Result<(usize, std::ops::Range<usize>), usize> {
+    let pages = rows.div_ceil(PAGE_SIZE);
+    if page > pages {
+        return Err(pages);
+    }
+    let start = (page - 1) * PAGE_SIZE;
+    Ok((pages, start..rows.min(start + PAGE_SIZE)))
+}
+
+/// The command for the page after this one
+fn next_page(prefix: Option<&str>, args: &ListArgs) -> String {
+    let mut command = vec!["list".to_string()];
+    // "me" comes first, other targets last
+    let me = args.target.as_deref() == Some(ME);
+    if me {
+        command.push(ME.to_string());
+    }
+    command.push(args.kind.clone().unwrap_or_else(|| "all".to_string()));
+    command.extend(args.filter.clone());
+    command.push(format!("page {}", args.page + 1));
+    if !me {
+        command.extend(args.target.clone());
+    }
+    format!("{}{}", prefix.unwrap_or(""), command.join(" "))
+}
+
+impl ListHandler {
+    fn list(&self, args: &ListArgs, room: &str, sender: &str) -> Result<(String, String), String> {
+        let kinds = self.registry.event_types();
+        if let Some(kind) = &args.kind {
+            if !kinds.iter().any(|x| is_same_kind(kind, x)) {
+                return Err(format!(
+                    "Sorry, I can only list all, {}",
+                    kinds
+                        .iter()
+                        .map(|x| plural(x))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
+        if let Some(target) = &args.target {
+            if !self.notifier.is_known_target(target) {
+                return Err(format!("Sorry, {} is not configured", target));
+            }
+            self.rooms
+                .may_use_target(room, sender, target)
+                .map_err(|x| format!("Sorry, {}", x))?;
+        }
+
+        let target = args.target.as_deref().map(|x| resolve_target(x, sender));
+        let mut subscriptions: Vec<SubscriptionInfo> = self
+            .registry
+            .list(target.as_deref().unwrap_or(room))
+            .into_iter()
+            .filter(|x| {
+                args.kind
+                    .as_deref()
+                    .is_none_or(|kind| is_same_kind(kind, &x.event_type))
+            })
+            .filter(|x| {
+                args.filter
+                    .as_deref()
+                    .is_none_or(|filter| matches_filter(filter, &x.key))
+            })
+            .collect();
+        if subscriptions.is_empty() {
+            return Err(match &args.filter {
+                Some(filter) => format!("No subscriptions found matching {}", filter),
+                None => "No subscriptions found".to_string(),
+            });
+        }
+        subscriptions.sort_by(|a, b| {
+            (&a.event_type, &a.backend, &a.key).cmp(&(&b.event_type, &b.backend, &b.key))
+        });
+
+        let (pages, rows) = page_rows(subscriptions.len(), args.page)
+            .map_err(|x| format!("Sorry, there are only {} pages", x))?;
+        let page = &subscriptions[rows];
+
+        let mut plain = Vec::new();
+        let mut html = "<table>".to_string();
+        let mut group = None;
+        for subscription in page {
+            let current = Some((&subscription.event_type, &subscription.backend));
+            if group != current {
+                group = current;
+                // Counted over all pages
+                let count = subscriptions
+                    .iter()
+                    .filter(|x| {
+                        x.event_type == subscription.event_type && x.backend == subscription.backend
+                    })
+                    .count();
+                let heading = format!(
+                    "{} on {} ({})",
+                    plural(&subscription.event_type),
+                    subscription.backend,
+                    count
+                );
+                plain.push(format!("{}:", heading));
+                html += &format!("<tr><th>{}</th></tr>", escape_html(&heading));
+            }
+            let mode = if subscription.digest { " (digest)" } else { "" };
+            plain.push(format!("  {}{}", subscription.key, mode));
+            html += &format!(
+                "<tr><td><a href=\"{}\">{}</a>{}</td></tr>",
+                escape_html(&subscription.url),
+                escape_html(&subscription.key),
+                mode
+            );
+        }
+        html += "</table>";
+
+        if pages > 1 {
+            let footer = format!("Page {} of {}", args.page, pages);
+            let footer = if args.page < pages {
+                format!(
+                    "{}, next: {}",
+                    footer,
+                    next_page(self.prefix.as_deref(), args)
+                )
+            } else {
+                footer
+            };
+            html += &escape_html(&footer);
+            plain.push(footer);
+        }
+        Ok((plain.join("\n"), html))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters() {
+        // Substrings, ignoring case
+        assert!(matches_filter("kernel", "openSUSE:Factory/Kernel-default"));
+        assert!(matches_filter("factory", "openSUSE:Factory"));
+        assert!(!matches_filter("gcc", "openSUSE:Factory/kernel-default"));
+        // Patterns match the key or a project of it
+        assert!(matches_filter("home:me*", "home:me:branches/foo"));
+        assert!(matches_filter("home:me:*", "home:me:branches/foo"));
+        assert!(matches_filter(
+            "*/kernel-*",
+            "openSUSE:Factory/kernel-default"
+        ));
+        assert!(!matches_filter("home:me:?", "home:me:branches/foo"));
+        assert!(!matches_filter("*/gcc", "openSUSE:Factory/kernel-default"));
+    }
+
+    #[test]
+    fn plurals() {
+        assert_eq!(plural("package"), "packages");
+        assert_eq!(plural("packages"), "packages");
+        assert_eq!(plural("request"), "requests");
+    }
+
+    #[test]
+    fn pages() {
+        assert_eq!(page_rows(3, 1), Ok((1, 0..3)));
+        assert_eq!(page_rows(PAGE_SIZE, 1), Ok((1, 0..PAGE_SIZE)));
+        assert_eq!(
+            page_rows(PAGE_SIZE + 1, 2),
+            Ok((2, PAGE_SIZE..PAGE_SIZE + 1))
+        );
+        assert_eq!(page_rows(PAGE_SIZE + 1, 3), Err(2));
+        assert_eq!(page_rows(0, 1), Err(0));
+
+        let args = |kind: Option<&str>, filter: Option<&str>, target: Option<&str>| ListArgs {
+            kind: kind.map(str::to_string),
+            filter: filter.map(str::to_string),
+            page: 1,
+            target: target.map(str::to_string),
+        };
+        assert_eq!(next_page(None, &args(None, None, None)), "list all page 2");
+        assert_eq!(
+            next_page(
+                Some("obsbot: "),
+                &args(Some("packages"), Some("home:me*"), None)
+            ),
+            "obsbot: list packages home:me* page 2"
+        );
+        assert_eq!(
+            next_page(None, &args(None, None, Some(ME))),
+            format!("list {} all page 2", ME)
+        );
+        assert_eq!(
+            next_page(None, &args(Some("requests"), None, Some("webhook:ci"))),
+            "list requests page 2 webhook:ci"
+        );
+    }
+}
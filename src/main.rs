@@ -1,15 +1,55 @@
+mod api;
 mod build_res;
+mod command;
 mod common;
+mod digest;
+mod dm;
+mod email;
+mod export;
+mod gitea;
+mod glob;
 mod help;
+mod http_receiver;
+mod irc;
 mod leave;
+mod list;
+mod matrix_api;
+mod membership;
+mod obs_api;
 mod openqa;
+mod openqa_api;
+mod reply;
+mod snooze;
+mod status;
 mod submitrequests;
+mod unsub;
+mod users;
+mod webhook;
 
 use anyhow::{anyhow, Result};
-use common::ConnectionDetails;
+use api::{Api, ApiDetails};
+use common::{ConnectionDetails, Frontends, Notifier, Registry, RoomSettings};
+use digest::{DigestHandler, Digests};
+use dm::{DirectRooms, DmHandler};
+use email::{EmailDetails, Mailer};
+use export::{AttachmentHandler, Attachments, ExportHandler};
+use gitea::GiteaDetails;
 use help::HelpHandler;
+use http_receiver::{HttpReceiver, HttpReceiverDetails};
+use irc::{IrcClient, IrcDetails};
+use leave::LeaveHandler;
+use list::ListHandler;
+use matrix_api::MatrixApi;
 use matrix_bot_api::MatrixBot;
+use obs_api::{ObsApi, ObsApiDetails};
+use openqa_api::{OpenQAApi, OpenQAApiDetails};
+use reply::{Replies, ReplyHandler};
+use snooze::{Mutes, QuietHoursDetails, SnoozeHandler};
+use status::StatusHandler;
 use std::env::args;
+use unsub::UnsubscribeAllHandler;
+use users::{LinkHandler, MaintainerDetails, UserLinks};
+use webhook::{WebhookDetails, WebhookHandler, Webhooks};
 
 use lapin::{Connection, ConnectionProperties};
 
@@ -61,6 +101,60 @@ fn main() -> Result<()> {
     let prefix = settings.get_str("prefix").ok();
 
     let default_subs = settings.get::<Vec<(String, String)>>("default_subs").ok();
+
+    let default_backends = settings
+        .get::<Vec<(String, String)>>("default_backends")
+        .unwrap_or_default();
+
+    let admins = settings.get::<Vec<String>>("admins").unwrap_or_default();
+
+    let state_dir = settings.get_str("state_dir").ok();
+
+    let irc_details = settings.get::<IrcDetails>("irc").ok();
+
+    // Without a prefix, commands are addressed with the name of the bot ("obsbot: ...")
+    let localpart = user.trim_start_matches('@').split(':').next().unwrap_or("");
+    command::set_names(
+        std::iter::once(localpart.to_string())
+            .chain(irc_details.as_ref().map(|x| x.nick.clone()))
+            .collect(),
+    );
+
+    let webhook_details = settings.get::<Vec<WebhookDetails>>("webhooks").ok();
+
+    let email_details = settings.get::<EmailDetails>("email").ok();
+
+    let http_receiver_details = settings.get::<HttpReceiverDetails>("http_receiver").ok();
+
+    let api_details = settings.get::<ApiDetails>("api").ok();
+
+    let obs_api_details = settings
+        .get::<Vec<ObsApiDetails>>("obs_api")
+        .unwrap_or_default();
+
+    let openqa_api_details = settings
+        .get::<Vec<OpenQAApiDetails>>("openqa_api")
+        .unwrap_or_default();
+
+    let quiet_hours_details = settings
+        .get::<Vec<QuietHoursDetails>>("quiet_hours")
+        .unwrap_or_default();
+
+    let maintainer_details = settings
+        .get::<Vec<MaintainerDetails>>("maintainers")
+        .unwrap_or_default();
+
+    let status_on_subscribe = settings.get_bool("status_on_subscribe").unwrap_or(false);
+
+    // Unsigned events could come from anybody, so a host without a secret is an error
+    let gitea_details = match settings.get::<Vec<GiteaDetails>>("gitea") {
+        Ok(x) => x,
+        Err(config::ConfigError::NotFound(_)) => Vec::new(),
+        Err(x) => return Err(anyhow!("Invalid [[gitea]] config: {}", x)),
+    };
+    if let Some(x) = gitea_details.iter().find(|x| x.secret.is_empty()) {
+        return Err(anyhow!("The secret of Gitea host {} is empty", x.host));
+    }
     // =========================================================
 
     // Check if backends are supported
@@ -70,16 +164,217 @@ fn main() -> Result<()> {
         }
     }
 
-    // Defining the first handler for general help output
+    // All subscribers register here, so their subscriptions can be managed from outside the chat
+    let registry = Registry::default();
+
+    // Optional second frontend, sharing all handlers
+    let mut irc = irc_details.map(|x| IrcClient::new(x, registry.clone()));
+
+    // Settings changed in the chat are kept in the state dir
+    let state_file = |name: &str| -> Result<std::path::PathBuf> {
+        match &state_dir {
+            Some(x) => {
+                std::fs::create_dir_all(x)?;
+                Ok(std::path::PathBuf::from(x).join(name))
+            }
+            None => Ok(xdg::BaseDirectories::with_prefix("obs_chat_bot")?.place_data_file(name)?),
+        }
+    };
+
+    // Short forms like "SR#1234" are resolved with the backend of the room
+    let mut rooms = RoomSettings::new(backends.clone(), admins);
+    for (room, backend) in &default_backends {
+        if let Err(x) = rooms.set_backend(room, backend) {
+            println!("Default backend for {}: {}", room, x);
+        }
+    }
+    snooze::set_defaults(&rooms, &quiet_hours_details)?;
+    // What was changed in the rooms overrides the defaults
+    rooms.load(state_file("rooms.json")?)?;
+
+    // Creating the bot with a first handler to handle leave and shutdown
+    let leave_handler = LeaveHandler {
+        prefix: prefix.clone(),
+        registry: registry.clone(),
+        irc: irc.as_ref().map(IrcClient::sender),
+        rooms: rooms.clone(),
+    };
+    if let Some(irc) = irc.as_mut() {
+        irc.add_handler(leave_handler.clone());
+    }
+    let mut bot = MatrixBot::new(leave_handler);
+
+    // For what the bot framework can't do, like reading attachments or opening direct chats
+    let matrix_api = MatrixApi::new(&homeserver_url, &user, &password);
+
+    let webhooks = webhook_details.map(Webhooks::new);
+
+    // Optional listener for event sources that can't use AMQP
+    let receiver = http_receiver_details.map(HttpReceiver::new);
+    if receiver.is_none() && !gitea_details.is_empty() {
+        println!("WARNING: Gitea configured, but no http_receiver to get its events");
+    }
+
+    // Matrix users to mention for OBS logins, linked in the chat
+    let mut users = UserLinks::new(maintainer_details);
+    users.load(state_file("users.json")?)?;
+    // Where personal subscriptions go, chosen with "dm"
+    let mut dms = DirectRooms::default();
+    dms.load(state_file("dms.json")?)?;
+
+    // Held notifications and snoozes survive restarts
+    let mut mutes = Mutes::new(rooms.clone());
+    mutes.load(state_file("mutes.json")?)?;
+
+    // So is what was recorded for the next digests
+    let mut digests = Digests::default();
+    digests.load(state_file("digests.json")?)?;
+
+    let notifier = Notifier {
+        matrix: bot.get_activebot_clone(),
+        irc: irc.as_ref().map(IrcClient::sender),
+        webhooks: webhooks.clone(),
+        email: email_details.map(Mailer::new).transpose()?,
+        replies: Replies::default(),
+        attachments: Attachments::default(),
+        mutes,
+        digests,
+        users,
+        dms,
+    };
+    // Digests of what arrived while rooms were muted
+    notifier.mutes.run(notifier.clone());
+
+    // Digests are sent on the schedules of their rooms
+    notifier
+        .digests
+        .run(notifier.clone(), rooms.clone(), registry.clone());
+
+    // Leaving a room (or being removed from it) forgets everything about it
+    registry.add_state(rooms.clone());
+    registry.add_state(notifier.digests.clone());
+    registry.add_state(notifier.mutes.clone());
+    registry.add_state(notifier.dms.clone());
+
+    // Attached documents are read before anything else sees the import
+    bot.add_handler(AttachmentHandler {
+        prefix: prefix.clone(),
+        matrix: matrix_api.clone(),
+        attachments: notifier.attachments.clone(),
+    });
+
+    // Add another handler for general help output
     let help_handler = HelpHandler {
         prefix: prefix.clone(),
+        notifier: notifier.clone(),
+        git_hosting: receiver.is_some() && !gitea_details.is_empty(),
+        obs_api: !obs_api_details.is_empty(),
+        registry: registry.clone(),
+        rooms: rooms.clone(),
     };
+    if let Some(irc) = irc.as_mut() {
+        irc.add_handler(help_handler.clone());
+    }
+    bot.add_handler(help_handler);
 
-    // Creating the bot
-    let mut bot = MatrixBot::new(help_handler);
+    // One list of the subscriptions on all backends
+    let list_handler = ListHandler {
+        prefix: prefix.clone(),
+        notifier: notifier.clone(),
+        registry: registry.clone(),
+        rooms: rooms.clone(),
+    };
+    if let Some(irc) = irc.as_mut() {
+        irc.add_handler(list_handler.clone());
+    }
+    bot.add_handler(list_handler);
+
+    let export_handler = ExportHandler {
+        prefix: prefix.clone(),
+        notifier: notifier.clone(),
+        registry: registry.clone(),
+        rooms: rooms.clone(),
+    };
+    if let Some(irc) = irc.as_mut() {
+        irc.add_handler(export_handler.clone());
+    }
+    bot.add_handler(export_handler);
+
+    let snooze_handler = SnoozeHandler {
+        prefix: prefix.clone(),
+        notifier: notifier.clone(),
+        mutes: notifier.mutes.clone(),
+        rooms: rooms.clone(),
+    };
+    if let Some(irc) = irc.as_mut() {
+        irc.add_handler(snooze_handler.clone());
+    }
+    bot.add_handler(snooze_handler);
+
+    let digest_handler = DigestHandler {
+        prefix: prefix.clone(),
+        notifier: notifier.clone(),
+        rooms: rooms.clone(),
+        registry: registry.clone(),
+    };
+    if let Some(irc) = irc.as_mut() {
+        irc.add_handler(digest_handler.clone());
+    }
+    bot.add_handler(digest_handler);
+
+    let link_handler = LinkHandler {
+        prefix: prefix.clone(),
+        notifier: notifier.clone(),
+        rooms: rooms.clone(),
+    };
+    if let Some(irc) = irc.as_mut() {
+        irc.add_handler(link_handler.clone());
+    }
+    bot.add_handler(link_handler);
+
+    let dm_handler = DmHandler {
+        prefix: prefix.clone(),
+        notifier: notifier.clone(),
+        matrix: matrix_api.clone(),
+    };
+    if let Some(irc) = irc.as_mut() {
+        irc.add_handler(dm_handler.clone());
+    }
+    bot.add_handler(dm_handler);
+
+    // Bulk unsubscriptions, part of the summary like single ones
+    let unsub_handler = UnsubscribeAllHandler::new(
+        prefix.clone(),
+        notifier.clone(),
+        registry.clone(),
+        rooms.clone(),
+    );
+    if let Some(irc) = irc.as_mut() {
+        irc.add_handler(unsub_handler.clone());
+    }
+    bot.add_handler(unsub_handler);
+
+    // Handler to inspect the configured webhooks
+    if let Some(webhooks) = webhooks {
+        let webhook_handler = WebhookHandler {
+            prefix: prefix.clone(),
+            notifier: notifier.clone(),
+            webhooks,
+            rooms: rooms.clone(),
+        };
+        if let Some(irc) = irc.as_mut() {
+            irc.add_handler(webhook_handler.clone());
+        }
+        bot.add_handler(webhook_handler);
+    }
+
+    let mut frontends = Frontends {
+        bot: &mut bot,
+        irc: irc.as_mut(),
+        registry: &registry,
+    };
 
-    // Add another handler to handle leave and shutdown
-    leave::register_handler(&mut bot, prefix.as_deref());
+    let apis: Vec<ObsApi> = obs_api_details.into_iter().map(ObsApi::new).collect();
 
     // Establish connections to all chosen backends
     for details in [OPENSUSE_CONNECTION, SUSE_CONNECTION].iter() {
@@ -99,16 +394,21 @@ fn main() -> Result<()> {
 
         // Subscribe to build_success/build_fails
         build_res::init(
-            &mut bot,
+            &mut frontends,
+            &notifier,
+            &rooms,
             details,
             conn.clone(),
+            apis.iter().find(|x| x.backend() == details.domain).cloned(),
             prefix.clone(),
             &default_subs,
         )?;
 
         // Subscribe to request-changes
         submitrequests::init(
-            &mut bot,
+            &mut frontends,
+            &notifier,
+            &rooms,
             details,
             conn.clone(),
             prefix.clone(),
@@ -116,15 +416,75 @@ fn main() -> Result<()> {
         )?;
 
         // Subscribe to openQA-changes (module will modify buildprefix to openqa)
+        let openqa_url = openqa_api_details
+            .iter()
+            .find(|x| x.backend == details.domain)
+            .map(|x| x.url.clone())
+            .unwrap_or_else(|| format!("https://openqa.{}", details.domain));
         openqa::init(
-            &mut bot,
+            &mut frontends,
+            &notifier,
+            &rooms,
             details,
             conn.clone(),
+            OpenQAApi::new(&openqa_url),
             prefix.clone(),
             &default_subs,
         )?;
     }
 
+    if let Some(mut receiver) = receiver {
+        // Subscribe to pushes and pull requests of Gitea instances
+        for details in &gitea_details {
+            gitea::init(
+                &mut frontends,
+                &mut receiver,
+                &notifier,
+                &rooms,
+                details,
+                prefix.clone(),
+                &default_subs,
+            )?;
+        }
+
+        receiver.run()?;
+    }
+
+    // Has to come after all subscribers, to answer with one summary per message
+    let reply_handler = ReplyHandler {
+        notifier: notifier.clone(),
+    };
+    if let Some(irc) = irc.as_mut() {
+        irc.add_handler(reply_handler.clone());
+    }
+    bot.add_handler(reply_handler);
+
+    // Looks up the current state of packages and requests in the OBS API.
+    // Without any configured, it only says so when asked.
+    let status_handler = StatusHandler {
+        prefix: prefix.clone(),
+        notifier: notifier.clone(),
+        registry: registry.clone(),
+        rooms: rooms.clone(),
+        apis,
+        on_subscribe: status_on_subscribe,
+    };
+    if let Some(irc) = irc.as_mut() {
+        irc.add_handler(status_handler.clone());
+    }
+    bot.add_handler(status_handler);
+
+    // Kicks, bans and room upgrades are not seen otherwise
+    membership::watch(matrix_api, registry.clone(), notifier.clone());
+
+    if let Some(api_details) = api_details {
+        Api::new(api_details, registry).run()?;
+    }
+
+    if let Some(irc) = irc {
+        irc.run(bot.get_activebot_clone())?;
+    }
+
     // Blocking call until shutdown is issued
     bot.run(&user, &password, &homeserver_url);
 
@@ -0,0 +1,291 @@
+//! Client for the Matrix client-server API, for what matrix_bot_api doesn't
+//! offer: It only hands text messages to the bot and can only send messages, so
+//! attachments, memberships and room upgrades are looked up and direct chats
+//! opened here.
+//! Logs in with the credentials of the bot as a device of its own.
+
+use crate::obs_api::api_url;
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+/// Attachments are documents, not images
+const MAX_DOWNLOAD: u64 = 1024 * 1024;
+
+#[derive(Clone)]
+pub struct MatrixApi {
+    homeserver: String,
+    user: String,
+    password: String,
+    client: reqwest::Client,
+    token: Arc<Mutex<Option<String>>>,
+}
+
+/// A file sent to a room
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub name: String,
+    /// mxc:// URL of the content
+    pub url: String,
+}
+
+#[derive(Deserialize)]
+struct Login {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct Messages {
+    chunk: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+struct JoinedRooms {
+    joined_rooms: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct CreatedRoom {
+    room_id: String,
+}
+
+#[derive(Deserialize)]
+struct Tombstone {
+    replacement_room: Option<String>,
+}
+
+impl MatrixApi {
+    pub fn new(homeserver: &str, user: &str, password: &str) -> MatrixApi {
+        MatrixApi {
+            homeserver: homeserver.trim_end_matches('/').to_string(),
+            user: user.to_string(),
+            password: password.to_string(),
+            client: reqwest::Client::new(),
+            token: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn login(&self) -> Result<String> {
+        let body = json!({
+            "type": "m.login.password",
+            "identifier": {"type": "m.id.user", "user": self.user},
+            "password": self.password,
+            "initial_device_display_name": "obs_chat_bot API",
+        });
+        let url = api_url(&self.homeserver, &["_matrix", "client", "v3", "login"], &[])?;
+        let mut res = self.client.post(&url).json(&body).send()?;
+        if !res.status().is_success() {
+            return Err(anyhow!(
+                "login at {} failed with HTTP {}",
+                self.homeserver,
+                res.status()
+            ));
+        }
+        let login: Login = res.json()?;
+        Ok(login.access_token)
+    }
+
+    /// Sends a request, logging in first or again if needed
+    fn send(
+        &self,
+        method: reqwest::Method,
+        segments: &[&str],
+        query: &[(&str, &str)],
+        body: Option<&Value>,
+    ) -> Result<reqwest::Response> {
+        let url = api_url(&self.homeserver, segments, query)?;
+        for _ in 0..2 {
+            let token = match self.token.lock().map(|x| x.clone()) {
+                Ok(Some(x)) => x,
+                _ => {
+                    let token = self.login()?;
+                    if let Ok(mut x) = self.token.lock() {
+                        *x = Some(token.clone());
+                    }
+                    token
+                }
+            };
+            let mut req = self.client.request(method.clone(), &url).bearer_auth(token);
+            if let Some(body) = body {
+                req = req.json(body);
+            }
+            let res = req.send()?;
+            if res.status().as_u16() != 401 {
+                return Ok(res);
+            }
+            // Logged out or expired
+            if let Ok(mut x) = self.token.lock() {
+                *x = None;
+            }
+        }
+        Err(anyhow!("{} does not accept the login", self.homeserver))
+    }
+
+    /// GETs a client API path as JSON
+    fn get<T: serde::de::DeserializeOwned>(
+        &self,
+        segments: &[&str],
+        query: &[(&str, &str)],
+    ) -> Result<T> {
+        let mut path = vec!["_matrix", "client", "v3"];
+        path.extend(segments);
+        let mut res = self.send(reqwest::Method::GET, &path, query, None)?;
+        if !res.status().is_success() {
+            return Err(anyhow!(
+                "{} answered with HTTP {}",
+                self.homeserver,
+                res.status()
+            ));
+        }
+        Ok(res.json()?)
+    }
+
+    /// POSTs JSON to a client API path
+    fn post<T: serde::de::DeserializeOwned>(&self, segments: &[&str], body: &Value) -> Result<T> {
+        let mut path = vec!["_matrix", "client", "v3"];
+        path.extend(segments);
+        let mut res = self.send(reqwest::Method::POST, &path, &[], Some(body))?;
+        if !res.status().is_success() {
+            return Err(anyhow!(
+                "{} answered with HTTP {}",
+                self.homeserver,
+                res.status()
+            ));
+        }
+        Ok(res.json()?)
+    }
+
+    /// All rooms the bot is a member of
+    pub fn joined_rooms(&self) -> Result<Vec<String>> {
+        let res: JoinedRooms = self.get(&["joined_rooms"], &[])?;
+        Ok(res.joined_rooms)
+    }
+
+    /// The room that replaced an upgraded room
+    pub fn replacement_room(&self, room: &str) -> Result<Option<String>> {
+        let path = [
+            "_matrix",
+            "client",
+            "v3",
+            "rooms",
+            room,
+            "state",
+            "m.room.tombstone",
+            "",
+        ];
+        let mut res = self.send(reqwest::Method::GET, &path, &[], None)?;
+        match res.status().as_u16() {
+            200 => {
+                let tombstone: Tombstone = res.json()?;
+                Ok(tombstone.replacement_room.filter(|x| !x.is_empty()))
+            }
+            // No tombstone, the room was not upgraded
+            404 => Ok(None),
+            x => Err(anyhow!("{} answered with HTTP {}", self.homeserver, x)),
+        }
+    }
+
+    pub fn join(&self, room: &str) -> Result<()> {
+        self.post::<Value>(&["join", room], &json!({}))?;
+        Ok(())
+    }
+
+    pub fn leave(&self, room: &str) -> Result<()> {
+        self.post::<Value>(&["rooms", room, "leave"], &json!({}))?;
+        Ok(())
+    }
+
+    /// Opens a direct chat with a user, who is invited to it. Returns the room.
+    pub fn create_dm(&self, user: &str) -> Result<String> {
+        let body = json!({
+            "is_direct": true,
+            "invite": [user],
+            "preset": "trusted_private_chat",
+        });
+        let room: CreatedRoom = self.post(&["createRoom"], &body)?;
+        Ok(room.room_id)
+    }
+
+    /// The last file the user sent to the room, among the latest messages
+    pub fn latest_attachment(&self, room: &str, sender: &str) -> Result<Option<Attachment>> {
+        let filter = json!({"types": ["m.room.message"], "senders": [sender]}).to_string();
+        let messages: Messages = self.get(
+            &["rooms", room, "messages"],
+            &[("dir", "b"), ("limit", "50"), ("filter", &filter)],
+        )?;
+        Ok(messages
+            .chunk
+            .iter()
+            .filter(|x| x["sender"] == sender)
+            .find_map(attachment))
+    }
+
+    /// Content of an mxc:// URL
+    pub fn download(&self, mxc: &str) -> Result<Vec<u8>> {
+        let (server, id) = mxc
+            .strip_prefix("mxc://")
+            .and_then(|x| x.split_once('/'))
+            .ok_or_else(|| anyhow!("{} is no mxc:// URL", mxc))?;
+        let path = ["_matrix", "client", "v1", "media", "download", server, id];
+        let res = self.send(reqwest::Method::GET, &path, &[], None)?;
+        if !res.status().is_success() {
+            return Err(anyhow!(
+                "downloading {} failed with HTTP {}",
+                mxc,
+                res.status()
+            ));
+        }
+        let mut content = Vec::new();
+        res.take(MAX_DOWNLOAD + 1).read_to_end(&mut content)?;
+        if content.len() as u64 > MAX_DOWNLOAD {
+            return Err(anyhow!("{} is larger than {} bytes", mxc, MAX_DOWNLOAD));
+        }
+        Ok(content)
+    }
+}
+
+/// The file of an m.file message. Files of encrypted rooms can't be read.
+fn attachment(event: &Value) -> Option<Attachment> {
+    let content = &event["content"];
+    if content["msgtype"] != "m.file" {
+        return None;
+    }
+    Some(Attachment {
+        name: content["body"].as_str().unwrap_or("attachment").to_string(),
+        url: content["url"].as_str()?.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attachments_of_messages() {
+        let file = json!({
+            "type": "m.room.message",
+            "sender": "@me:example.org",
+            "content": {
+                "msgtype": "m.file",
+                "body": "subs.toml",
+                "url": "mxc://example.org/AbCdEf",
+                "info": {"mimetype": "application/toml", "size": 120}
+            }
+        });
+        assert_eq!(
+            attachment(&file),
+            Some(Attachment {
+                name: "subs.toml".to_string(),
+                url: "mxc://example.org/AbCdEf".to_string(),
+            })
+        );
+
+        let text = json!({"content": {"msgtype": "m.text", "body": "import"}});
+        assert_eq!(attachment(&text), None);
+        let encrypted = json!({"content": {"msgtype": "m.file", "body": "subs.toml", "file": {}}});
+        assert_eq!(attachment(&encrypted), None);
+    }
+}
@@ -0,0 +1,65 @@
+//! matrix_bot_api doesn't tell the bot when it was kicked or banned from a room,
+//! or when a room was upgraded. The rooms are checked here every few minutes:
+//! Rooms the bot is not in anymore are purged, and everything of upgraded rooms
+//! is moved to the room replacing them.
+
+use crate::common::{Notifier, Registry};
+use crate::matrix_api::MatrixApi;
+use matrix_bot_api::MessageType;
+
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// Matrix room-ids start with '!', IRC channels and targets don't
+fn is_matrix_room(room: &str) -> bool {
+    room.starts_with('!')
+}
+
+/// Checks the rooms in a separate thread
+pub fn watch(api: MatrixApi, registry: Registry, notifier: Notifier) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(CHECK_INTERVAL);
+        if let Err(x) = check(&api, &registry, &notifier) {
+            println!("Could not check the rooms: {}", x);
+        }
+    });
+}
+
+fn check(api: &MatrixApi, registry: &Registry, notifier: &Notifier) -> anyhow::Result<()> {
+    let joined = api.joined_rooms()?;
+    for room in registry.rooms().into_iter().filter(|x| is_matrix_room(x)) {
+        if !joined.contains(&room) {
+            println!("Not in {} anymore (kicked or banned)", room);
+            registry.purge(&room);
+            continue;
+        }
+        let replacement = match api.replacement_room(&room) {
+            Ok(Some(x)) => x,
+            Ok(None) => continue,
+            Err(x) => {
+                println!("Could not look up the tombstone of {}: {}", room, x);
+                continue;
+            }
+        };
+        // Without being in the new room, there is no point in moving there
+        if let Err(x) = api.join(&replacement) {
+            println!(
+                "Could not join {}, which replaced {}: {}",
+                replacement, room, x
+            );
+            continue;
+        }
+        let moved = registry.migrate(&room, &replacement);
+        notifier.send_message(
+            &format!(
+                "This room replaced {}, its {} subscriptions and settings moved here.",
+                room, moved
+            ),
+            &replacement,
+            MessageType::RoomNotice,
+        );
+        if let Err(x) = api.leave(&room) {
+            println!("Could not leave {}: {}", room, x);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,541 @@
+//! Client for the OBS HTTP API, to look up the current state of what the
+//! events only report changes of.
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ObsApiDetails {
+    /// Which backend ("opensuse.org", "suse.de") these credentials are for
+    pub backend: String,
+    pub user: String,
+    pub password: String,
+    /// Defaults to https://api.BACKEND
+    pub url: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct ObsApi {
+    details: ObsApiDetails,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Clone)]
+pub struct BuildResult {
+    pub repository: String,
+    pub arch: String,
+    pub package: String,
+    pub code: String,
+    pub details: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BuildSummary {
+    pub repository: String,
+    pub arch: String,
+    /// Build state of the whole repository, like "building" or "published"
+    pub state: String,
+    /// Number of packages per status code
+    pub counts: Vec<(String, u32)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestAction {
+    pub action_type: String,
+    pub source: Option<String>,
+    pub target: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Review {
+    pub state: String,
+    /// User, group, project or package the review is assigned to
+    pub by: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestInfo {
+    pub id: String,
+    pub creator: String,
+    pub state: String,
+    pub who: String,
+    pub when: String,
+    pub actions: Vec<RequestAction>,
+    pub reviews: Vec<Review>,
+    pub description: String,
+}
+
+impl ObsApi {
+    pub fn new(details: ObsApiDetails) -> ObsApi {
+        ObsApi {
+            details,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn backend(&self) -> &str {
+        &self.details.backend
+    }
+
+    pub fn url(&self) -> String {
+        match &self.details.url {
+            Some(x) => x.trim_end_matches('/').to_string(),
+            None => format!("https://api.{}", self.details.backend),
+        }
+    }
+
+    /// GETs the path made of the segments, with the query, returning the body
+    pub fn get(&self, segments: &[&str], query: &[(&str, &str)]) -> Result<String> {
+        let mut res = self
+            .client
+            .get(&api_url(&self.url(), segments, query)?)
+            .basic_auth(&self.details.user, Some(&self.details.password))
+            .send()?;
+        match res.status().as_u16() {
+            200 => Ok(res.text()?),
+            401 | 403 => Err(anyhow!("access denied, check the obs_api credentials")),
+            404 => Err(anyhow!("not found")),
+            x => Err(anyhow!("{} answered with HTTP {}", self.url(), x)),
+        }
+    }
+
+    /// Results of one package (and its multibuild flavors), optionally only of one repository and arch
+    pub fn build_results(
+        &self,
+        project: &str,
+        package: &str,
+        repository: Option<&str>,
+        arch: Option<&str>,
+    ) -> Result<Vec<BuildResult>> {
+        let mut query = vec![
+            ("package", package),
+            ("multibuild", "1"),
+            ("locallink", "1"),
+        ];
+        if let Some(repository) = repository {
+            query.push(("repository", repository));
+        }
+        if let Some(arch) = arch {
+            query.push(("arch", arch));
+        }
+        let xml = self.get(&["build", project, "_result"], &query)?;
+        Ok(parse_build_results(&xml))
+    }
+
+    /// The last bytes of a build log (the whole log, if it is shorter), and
+    /// whether it was cut, so starts somewhere in a line
+    pub fn build_log_tail(
+        &self,
+        project: &str,
+        repository: &str,
+        arch: &str,
+        package: &str,
+        bytes: u64,
+    ) -> Result<(String, bool)> {
+        let path = ["build", project, repository, arch, package, "_log"];
+        let entry = self.get(&path, &[("view", "entry")])?;
+        let start = tail_start(&entry, bytes);
+        let log = self.get(&path, &[("nostream", "1"), ("start", &start.to_string())])?;
+        Ok((log, start > 0))
+    }
+
+    /// Number of packages per status code for all repositories of a project
+    pub fn build_summary(&self, project: &str) -> Result<Vec<BuildSummary>> {
+        let xml = self.get(&["build", project, "_result"], &[("view", "summary")])?;
+        Ok(parse_build_summary(&xml))
+    }
+
+    pub fn request(&self, id: &str) -> Result<RequestInfo> {
+        let xml = self.get(&["request", id], &[])?;
+        Ok(parse_request(id, &xml))
+    }
+}
+
+/// The URL of an API path. Segments and query are encoded, package names
+/// like "gtk+" or "libsigc++2" would be garbled otherwise.
+pub fn api_url(base: &str, segments: &[&str], query: &[(&str, &str)]) -> Result<String> {
+    let mut url = url::Url::parse(base)?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("{} can't be the base of a URL", base))?
+        .pop_if_empty()
+        .extend(segments);
+    if !query.is_empty() {
+        url.query_pairs_mut().extend_pairs(query);
+    }
+    Ok(url.to_string())
+}
+
+/// Where the last bytes of a log start, given its "view=entry" answer
+fn tail_start(entry: &str, bytes: u64) -> u64 {
+    let size: u64 = tags(entry)
+        .iter()
+        .find(|x| x.name == "entry")
+        .and_then(|x| x.attr("size").parse().ok())
+        .unwrap_or(0);
+    size.saturating_sub(bytes)
+}
+
+fn parse_build_results(xml: &str) -> Vec<BuildResult> {
+    let mut res = Vec::new();
+    let mut current = None;
+    for tag in tags(xml) {
+        match tag.name.as_str() {
+            "result" if !tag.closing => current = Some(tag),
+            "status" if !tag.closing => {
+                if let Some(result) = &current {
+                    res.push(BuildResult {
+                        repository: result.attr("repository"),
+                        arch: result.attr("arch"),
+                        package: tag.attr("package"),
+                        code: tag.attr("code"),
+                        details: tag.attrs.get("details").cloned(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    res
+}
+
+fn parse_build_summary(xml: &str) -> Vec<BuildSummary> {
+    let mut res: Vec<BuildSummary> = Vec::new();
+    for tag in tags(xml) {
+        match tag.name.as_str() {
+            "result" if !tag.closing => res.push(BuildSummary {
+                repository: tag.attr("repository"),
+                arch: tag.attr("arch"),
+                state: tag.attr("state"),
+                counts: Vec::new(),
+            }),
+            "statuscount" => {
+                if let Some(summary) = res.last_mut() {
+                    let count = tag.attr("count").parse().unwrap_or(0);
+                    summary.counts.push((tag.attr("code"), count));
+                }
+            }
+            _ => {}
+        }
+    }
+    res
+}
+
+fn parse_request(id: &str, xml: &str) -> RequestInfo {
+    let mut res = RequestInfo {
+        id: id.to_string(),
+        creator: String::new(),
+        state: String::new(),
+        who: String::new(),
+        when: String::new(),
+        actions: Vec::new(),
+        reviews: Vec::new(),
+        description: String::new(),
+    };
+    // Source and target belong to the action before them
+    for tag in tags(xml).into_iter().filter(|x| !x.closing) {
+        match tag.name.as_str() {
+            "request" => res.creator = tag.attr("creator"),
+            "action" => res.actions.push(RequestAction {
+                action_type: tag.attr("type"),
+                source: None,
+                target: None,
+            }),
+            "source" | "target" => {
+                let name = match (tag.attrs.get("project"), tag.attrs.get("package")) {
+                    (Some(project), Some(package)) => format!("{}/{}", project, package),
+                    (Some(project), None) => project.to_string(),
+                    _ => continue,
+                };
+                if let Some(action) = res.actions.last_mut() {
+                    if tag.name == "source" {
+                        action.source = Some(name);
+                    } else {
+                        action.target = Some(name);
+                    }
+                }
+            }
+            "state" => {
+                res.state = tag.attr("name");
+                res.who = tag.attr("who");
+                res.when = tag.attr("when");
+            }
+            "review" => {
+                let by = ["by_user", "by_group", "by_project", "by_package"]
+                    .iter()
+                    .filter_map(|x| tag.attrs.get(*x))
+                    .next_back()
+                    .cloned()
+                    .unwrap_or_default();
+                res.reviews.push(Review {
+                    state: tag.attr("state"),
+                    by,
+                });
+            }
+            // History entries have descriptions as well
+            "description" if res.description.is_empty() => {
+                res.description = tag.text.trim().to_string()
+            }
+            _ => {}
+        }
+    }
+    res
+}
+
+/// An XML tag and the text directly following it
+#[derive(Debug, Clone)]
+struct Tag {
+    name: String,
+    closing: bool,
+    attrs: HashMap<String, String>,
+    text: String,
+}
+
+impl Tag {
+    fn attr(&self, name: &str) -> String {
+        self.attrs.get(name).cloned().unwrap_or_default()
+    }
+}
+
+/// All tags of a document in order. The OBS API answers are flat and simple
+/// enough to not need a full XML parser.
+fn tags(xml: &str) -> Vec<Tag> {
+    let mut res = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        if let Some(comment) = rest.strip_prefix("!--") {
+            rest = comment.split_once("-->").map(|(_, x)| x).unwrap_or("");
+            continue;
+        }
+        let end = match rest.find('>') {
+            Some(x) => x,
+            None => break,
+        };
+        let inner = &rest[..end];
+        rest = &rest[end + 1..];
+        if inner.starts_with('?') || inner.starts_with('!') {
+            continue;
+        }
+
+        let closing = inner.starts_with('/');
+        let inner = inner.trim_start_matches('/').trim_end_matches('/');
+        let (name, attrs) = inner.split_once(char::is_whitespace).unwrap_or((inner, ""));
+        let text = rest.split('<').next().unwrap_or("");
+        res.push(Tag {
+            name: name.to_string(),
+            closing,
+            attrs: attributes(attrs),
+            text: unescape(text),
+        });
+    }
+    res
+}
+
+/// name="value" pairs, with single or double quotes
+fn attributes(mut text: &str) -> HashMap<String, String> {
+    let mut res = HashMap::new();
+    while let Some((name, value)) = text.split_once('=') {
+        let value = value.trim_start();
+        let quote = match value.chars().next() {
+            Some(x) if x == '"' || x == '\'' => x,
+            _ => break,
+        };
+        let (value, tail) = match value[1..].split_once(quote) {
+            Some(x) => x,
+            None => break,
+        };
+        res.insert(name.trim().to_string(), unescape(value));
+        text = tail;
+    }
+    res
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As answered by /build/PROJECT/_result?package=PACKAGE&multibuild=1&locallink=1
+    const RESULTS: &str = r#"<resultlist state="c3d8a1b2e7f9d0c4b5a6978812345678">
+  <result project="home:me" repository="openSUSE_Tumbleweed" arch="x86_64" code="published" state="published">
+    <status package="gtk+" code="succeeded"/>
+    <status package="gtk+:docs" code="failed" details="exit status 1 &amp; &quot;more&quot;"/>
+  </result>
+  <!-- <result repository="commented_out"> -->
+  <result project="home:me" repository="openSUSE_Leap_15.5" arch="aarch64" code="building" state="building" dirty="true">
+    <status package='gtk+' code='scheduled'>
+      <details>waiting for 4 compliant workers</details>
+    </status>
+  </result>
+</resultlist>
+"#;
+
+    #[test]
+    fn build_results_of_all_flavors() {
+        let res = parse_build_results(RESULTS);
+        let res: Vec<_> = res
+            .iter()
+            .map(|x| {
+                (
+                    x.repository.as_str(),
+                    x.arch.as_str(),
+                    x.package.as_str(),
+                    x.code.as_str(),
+                    x.details.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            res,
+            vec![
+                ("openSUSE_Tumbleweed", "x86_64", "gtk+", "succeeded", None),
+                (
+                    "openSUSE_Tumbleweed",
+                    "x86_64",
+                    "gtk+:docs",
+                    "failed",
+                    Some("exit status 1 & \"more\"")
+                ),
+                ("openSUSE_Leap_15.5", "aarch64", "gtk+", "scheduled", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn build_summary_counts() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<resultlist state="00f4bd1b3b6d4bd0e5ac7cd6dc0f2a3f">
+  <result project="openSUSE:Factory" repository="standard" arch="x86_64" code="building" state="building">
+    <summary>
+      <statuscount code="succeeded" count="14210"/>
+      <statuscount code="failed" count="23"/>
+    </summary>
+  </result>
+  <result project="openSUSE:Factory" repository="standard" arch="i586" code="published" state="published">
+    <summary>
+      <statuscount code="excluded" count="3"/>
+    </summary>
+  </result>
+</resultlist>
+"#;
+        let res = parse_build_summary(xml);
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].arch, "x86_64");
+        assert_eq!(res[0].state, "building");
+        assert_eq!(
+            res[0].counts,
+            vec![("succeeded".to_string(), 14210), ("failed".to_string(), 23)]
+        );
+        assert_eq!(res[1].repository, "standard");
+        assert_eq!(res[1].counts, vec![("excluded".to_string(), 3)]);
+    }
+
+    #[test]
+    fn request_with_actions_and_reviews() {
+        let xml = r#"<request id="1101234" creator="alice">
+  <action type="submit">
+    <source project="home:alice:branches:GNOME:Factory" package="libsigc++2" rev="3"/>
+    <target project="GNOME:Factory" package="libsigc++2"/>
+  </action>
+  <action type="delete">
+    <target project="GNOME:Factory"/>
+  </action>
+  <state name="review" who="bob" when="2023-08-01T10:11:12">
+    <comment/>
+  </state>
+  <review state="accepted" when="2023-08-01T10:00:00" who="bob" by_group="factory-auto"/>
+  <review state="new" by_project="GNOME:Factory" by_package="libsigc++2"/>
+  <description>Update to 3.4.0 &lt;see changes&gt;</description>
+</request>
+"#;
+        let res = parse_request("1101234", xml);
+        assert_eq!(res.creator, "alice");
+        assert_eq!(res.state, "review");
+        assert_eq!(res.who, "bob");
+        assert_eq!(res.actions.len(), 2);
+        assert_eq!(res.actions[0].action_type, "submit");
+        assert_eq!(
+            res.actions[0].source.as_deref(),
+            Some("home:alice:branches:GNOME:Factory/libsigc++2")
+        );
+        assert_eq!(
+            res.actions[0].target.as_deref(),
+            Some("GNOME:Factory/libsigc++2")
+        );
+        assert_eq!(res.actions[1].source, None);
+        assert_eq!(res.actions[1].target.as_deref(), Some("GNOME:Factory"));
+        assert_eq!(res.reviews[0].by, "factory-auto");
+        assert_eq!(res.reviews[1].by, "libsigc++2");
+        assert_eq!(res.reviews[1].state, "new");
+        assert_eq!(res.description, "Update to 3.4.0 <see changes>");
+    }
+
+    #[test]
+    fn log_tail_offsets() {
+        let entry = r#"<directory>
+  <entry name="_log" size="70000" mtime="1690884672"/>
+</directory>
+"#;
+        assert_eq!(tail_start(entry, 65536), 70000 - 65536);
+        assert_eq!(tail_start(entry, 70000), 0);
+        assert_eq!(tail_start(entry, 100000), 0);
+        assert_eq!(tail_start(entry, 0), 70000);
+
+        // No log yet, or an unexpected answer: Everything there is
+        assert_eq!(
+            tail_start(
+                r#"<directory><entry name="_log" size="0"/></directory>"#,
+                10
+            ),
+            0
+        );
+        assert_eq!(tail_start("<directory/>", 10), 0);
+        assert_eq!(
+            tail_start(
+                r#"<directory><entry name="_log" size="-1"/></directory>"#,
+                10
+            ),
+            0
+        );
+    }
+
+    #[test]
+    fn urls_are_encoded() {
+        let url = api_url(
+            "https://api.opensuse.org",
+            &["build", "home:me", "_result"],
+            &[("package", "libsigc++2"), ("multibuild", "1")],
+        )
+        .unwrap();
+        assert_eq!(
+            url,
+            "https://api.opensuse.org/build/home:me/_result?package=libsigc%2B%2B2&multibuild=1"
+        );
+
+        let url = api_url(
+            "http://localhost:3000/obs/",
+            &[
+                "build",
+                "home:me",
+                "openSUSE Tumbleweed",
+                "x86_64",
+                "gtk+#1",
+                "_log",
+            ],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            url,
+            "http://localhost:3000/obs/build/home:me/openSUSE%20Tumbleweed/x86_64/gtk+%231/_log"
+        );
+    }
+}
@@ -1,13 +1,18 @@
-use crate::common::{prepend_prefix, ConnectionDetails, MessageParseResult, Subscriber};
+use crate::command::{looks_like_url, message_text, short_test_id, url_segments};
+use crate::common::{
+    escape_html, prepend_prefix, Activate, ConnectionDetails, Frontends, MessageParseResult,
+    Notifier, RoomSettings, Subscriber, SubscriptionKey,
+};
+use crate::glob::GlobIndex;
+use crate::openqa_api::{Job, OpenQAApi};
 use anyhow::Result;
 use lapin::{
     message::{Delivery, DeliveryResult},
-    options::*,
     Connection, ConsumerDelegate,
 };
 use matrix_bot_api::handlers::{HandleResult, MessageHandler};
-use matrix_bot_api::{ActiveBot, MatrixBot, Message, MessageType};
-use serde::Deserialize;
+use matrix_bot_api::{ActiveBot, Message};
+use serde::{Deserialize, Serialize};
 use std::collections::hash_map::HashMap;
 use std::convert::TryFrom;
 use std::sync::{Arc, Mutex};
@@ -21,6 +26,10 @@ pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
             "OPENQA_TEST_URL",
             "Subscribe to a test. Get notification if test-status changes.",
         ),
+        (
+            "t#ID",
+            "Same as OPENQA_TEST_URL. Uses the backend of the room.",
+        ),
         (
             "unsub OPENQA_TEST_URL",
             "Unsubscribe from a test. Get no more notifications.",
@@ -42,6 +51,8 @@ impl std::fmt::Display for QAKey {
     }
 }
 
+impl SubscriptionKey for QAKey {}
+
 impl TryFrom<String> for QAKey {
     type Error = ();
 
@@ -51,24 +62,22 @@ impl TryFrom<String> for QAKey {
             return Err(());
         }
 
-        let parts: Vec<_> = line.split('/').collect();
-        if parts.len() < 3 {
-            return Err(());
+        if !looks_like_url(line) {
+            let id = short_test_id(line).ok_or(())?;
+            return Ok(QAKey { id });
         }
 
-        let mut iter = parts.iter().rev();
-        // These unwraps cannot fail, as there have to be at least 2 parts
-        let id = iter
-            .next()
-            .unwrap()
-            .trim()
-            .trim_end_matches('#')
-            .to_string();
-        Ok(QAKey { id })
+        // Fragments like "#step/boot/1" are dropped with the URL segments
+        match url_segments(line).as_slice() {
+            ["tests", id, ..] if id.chars().all(|c| c.is_ascii_digit()) => {
+                Ok(QAKey { id: id.to_string() })
+            }
+            _ => Err(()),
+        }
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 struct QATestInfo {
     id: i32,
     #[serde(rename = "TEST")]
@@ -80,21 +89,23 @@ struct QATestInfo {
 
 impl MessageHandler for Subscriber<QAKey> {
     /// Will be called for every text message send to a room the bot is in
-    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
-        let res = self.handle_message_helper(bot, &message.body, &message.room);
+    fn handle_message(&mut self, _bot: &ActiveBot, message: &Message) -> HandleResult {
+        let res =
+            self.handle_message_helper(&message_text(message), &message.room, &message.sender);
 
         if res == MessageParseResult::SomethingForMe {
-            match self.register() {
-                Err(x) => {
-                    println!("Error while registering: {:?}", x);
-                }
-                Ok(consumer) => consumer.set_delegate(Box::new(self.clone())),
-            }
+            self.activate();
         }
         HandleResult::ContinueHandling
     }
 }
 
+impl Activate for Subscriber<QAKey> {
+    fn activate(&self) {
+        self.register_delegate();
+    }
+}
+
 impl Subscriber<QAKey> {
     fn generate_messages(&self, jsondata: QATestInfo) -> (String, String) {
         let reason = match jsondata.reason {
@@ -103,8 +114,8 @@ impl Subscriber<QAKey> {
         };
 
         let html_result = match &jsondata.result {
-            x if x == "passed" => x.clone(),
-            x => format!("<u>{}</u>", x),
+            x if x == "passed" => escape_html(x),
+            x => format!("<u>{}</u>", escape_html(x)),
         };
 
         let plain = format!(
@@ -113,14 +124,14 @@ impl Subscriber<QAKey> {
         );
 
         let html = format!(
-            "<strong>Test {}:</strong> Test {} (<a href={}>{}</a>){}",
+            "<strong>Test {}:</strong> Test {} (<a href={}/{}>{}</a>){}",
             html_result,
-            jsondata.testname,
-            format!("{}/{}", self.get_base_url(), jsondata.id,),
+            escape_html(&jsondata.testname),
+            self.get_base_url(),
+            jsondata.id,
             jsondata.id,
-            reason
+            escape_html(&reason)
         );
-
         (plain, html)
     }
 
@@ -146,25 +157,129 @@ impl Subscriber<QAKey> {
 
         println!("Test {}: {}", jsondata.result, jsondata.id);
 
-        if let Ok(bot) = self.bot.lock() {
-            let (plain, html) = self.generate_messages(jsondata);
-            for room in &rooms {
-                bot.send_html_message(&plain, &html, room, MessageType::TextMessage);
-            }
+        let document = self.event_document(delivery.routing_key.as_str(), &key, &jsondata)?;
+        let failed = !matches!(jsondata.result.as_str(), "passed" | "softfailed");
+        let (id, testname) = (jsondata.id, jsondata.testname.clone());
+        let (plain, html) = self.generate_messages(jsondata);
+        for room in &rooms {
+            self.notify(room, &plain, &html, &document);
+        }
+        if let (Some(api), true) = (self.openqa_api.clone(), failed) {
+            let base_url = self.get_base_url();
+            self.follow_up(&rooms, move || {
+                failure_messages(&api, &base_url, id, &testname)
+            });
         }
 
         Ok(())
     }
 }
 
+/// What broke, according to the openQA API, as (plain, html) to follow the failure.
+/// None if the API can't tell.
+fn failure_messages(
+    api: &OpenQAApi,
+    base_url: &str,
+    id: i32,
+    testname: &str,
+) -> Option<(String, String)> {
+    let job = match api.details(&id.to_string()) {
+        Ok(x) => x,
+        Err(x) => {
+            println!("Could not get details of test {}: {}", id, x);
+            return None;
+        }
+    };
+    let previous = api.previous(&job).unwrap_or_else(|x| {
+        println!("Could not get previous test of {}: {}", id, x);
+        None
+    });
+    Some(details_messages(
+        base_url,
+        testname,
+        &job,
+        previous.as_ref(),
+    ))
+}
+
+fn details_messages(
+    base_url: &str,
+    testname: &str,
+    job: &Job,
+    previous: Option<&Job>,
+) -> (String, String) {
+    let url = format!("{}/{}", base_url, job.id);
+    // Links into the job, escaped as they contain module names
+    let link = |fragment: &str, text: &str| {
+        format!(
+            "<a href=\"{}\">{}</a>",
+            escape_html(&format!("{}{}", url, fragment)),
+            escape_html(text)
+        )
+    };
+    let mut plain = format!("Details of test {} ({}):", testname, job.id);
+    let mut html = format!(
+        "Details of test {} ({}):",
+        escape_html(testname),
+        link("", &job.id.to_string())
+    );
+
+    let failed: Vec<_> = job
+        .failed_modules()
+        .iter()
+        .map(|x| x.name.clone())
+        .collect();
+    if !failed.is_empty() {
+        plain += &format!("\nFailed modules: {}", failed.join(", "));
+        html += &format!(
+            "<br>Failed modules: {}",
+            failed
+                .iter()
+                .map(|x| link(&format!("#step/{}/1", x), x))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    if let Some((module, step)) = job.first_failed_step() {
+        let num = step.num.unwrap_or(1);
+        let title = step
+            .title
+            .as_ref()
+            .map(|x| format!(" ({})", x))
+            .unwrap_or_default();
+        plain += &format!("\nFirst failing step: {} #{}{}", module.name, num, title);
+        html += &format!(
+            "<br>First failing step: {}{}",
+            link(
+                &format!("#step/{}/{}", module.name, num),
+                &format!("{} #{}", module.name, num)
+            ),
+            escape_html(&title)
+        );
+    }
+
+    if let Some(previous) = previous {
+        let result = previous.result.as_deref().unwrap_or("unknown");
+        plain += &format!("\nPrevious result: {} ({})", result, previous.id);
+        html += &format!(
+            "<br>Previous result: {} (<a href=\"{}/{}\">{}</a>)",
+            escape_html(result),
+            escape_html(base_url),
+            previous.id,
+            previous.id
+        );
+    }
+
+    plain += &format!("\nInvestigate: {}#investigation", url);
+    html += &format!("<br>{}", link("#investigation", "Investigate"));
+    (plain, html)
+}
+
 impl ConsumerDelegate for Subscriber<QAKey> {
     fn on_new_delivery(&self, delivery: DeliveryResult) {
         if let Ok(Some(delivery)) = delivery {
-            if let Some(channel) = &self.channel {
-                let _ = channel
-                    .basic_ack(delivery.delivery_tag, BasicAckOptions::default())
-                    .wait();
-            }
+            self.ack_delivery(delivery.delivery_tag);
             match self.delivery_wrapper(delivery) {
                 Ok(_) => {}
                 Err(x) => println!("Error while getting Event: {:?}. Skipping to continue", x),
@@ -178,25 +293,32 @@ impl ConsumerDelegate for Subscriber<QAKey> {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn init(
-    bot: &mut MatrixBot,
+    frontends: &mut Frontends,
+    notifier: &Notifier,
+    rooms: &RoomSettings,
     details: &ConnectionDetails,
     conn: Connection,
+    api: OpenQAApi,
     prefix: Option<String>,
     default_subs: &Option<Vec<(String, String)>>,
 ) -> Result<()> {
-    let activebot = bot.get_activebot_clone();
     let mut server_details = *details;
     server_details.buildprefix = "openqa";
     let mut sub: Subscriber<QAKey> = Subscriber {
         subtype: "tests".to_string(),
         server_details,
-        connection: conn,
-        channel: None,
+        connection: Some(conn),
+        channel: Arc::new(Mutex::new(None)),
         subnames: SUBNAMES.to_vec(),
-        bot: Arc::new(Mutex::new(activebot)),
+        notifier: Arc::new(Mutex::new(notifier.clone())),
         subscriptions: Arc::new(Mutex::new(HashMap::new())),
+        patterns: Arc::new(Mutex::new(GlobIndex::default())),
         prefix,
+        rooms: rooms.clone(),
+        api: None,
+        openqa_api: Some(api),
     };
 
     match default_subs {
@@ -206,12 +328,92 @@ pub fn init(
             Ok(consumer) => {
                 consumer.set_delegate(Box::new(sub.clone()));
                 for (room, url) in subs {
-                    sub.subscribe_to_defaults(&url, &room);
+                    sub.subscribe_to_defaults(url, room);
                 }
             }
         },
     }
-    bot.add_handler(sub);
+    frontends.add_subscriber(sub);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openqa_api::{Module, Step};
+
+    fn module(name: &str, result: &str, steps: &[(u32, &str, Option<&str>)]) -> Module {
+        Module {
+            name: name.to_string(),
+            result: result.to_string(),
+            details: steps
+                .iter()
+                .map(|(num, result, title)| Step {
+                    num: Some(*num),
+                    result: Some(result.to_string()),
+                    title: title.map(|x| x.to_string()),
+                })
+                .collect(),
+        }
+    }
+
+    fn job(id: u64, result: &str, testresults: Vec<Module>) -> Job {
+        Job {
+            id,
+            result: Some(result.to_string()),
+            settings: HashMap::new(),
+            testresults,
+        }
+    }
+
+    #[test]
+    fn details_of_failure() {
+        let failed = job(
+            4711,
+            "failed",
+            vec![
+                module("boot", "passed", &[(1, "ok", None)]),
+                module(
+                    "zypper_in",
+                    "failed",
+                    &[(1, "ok", None), (2, "fail", Some("<timeout> & more"))],
+                ),
+                module("<b>x</b>", "failed", &[]),
+            ],
+        );
+        let previous = job(4705, "passed", Vec::new());
+        let (plain, html) = details_messages(
+            "https://openqa.example.org/tests",
+            "textmode",
+            &failed,
+            Some(&previous),
+        );
+        assert_eq!(
+            plain,
+            "Details of test textmode (4711):\n\
+             Failed modules: zypper_in, <b>x</b>\n\
+             First failing step: zypper_in #2 (<timeout> & more)\n\
+             Previous result: passed (4705)\n\
+             Investigate: https://openqa.example.org/tests/4711#investigation"
+        );
+        assert_eq!(
+            html,
+            "Details of test textmode (<a href=\"https://openqa.example.org/tests/4711\">4711</a>):<br>\
+             Failed modules: <a href=\"https://openqa.example.org/tests/4711#step/zypper_in/1\">zypper_in</a>, \
+             <a href=\"https://openqa.example.org/tests/4711#step/&lt;b&gt;x&lt;/b&gt;/1\">&lt;b&gt;x&lt;/b&gt;</a><br>\
+             First failing step: <a href=\"https://openqa.example.org/tests/4711#step/zypper_in/2\">zypper_in #2</a> \
+             (&lt;timeout&gt; &amp; more)<br>\
+             Previous result: passed (<a href=\"https://openqa.example.org/tests/4705\">4705</a>)<br>\
+             <a href=\"https://openqa.example.org/tests/4711#investigation\">Investigate</a>"
+        );
+
+        // A job without modules has nothing but the links
+        let (plain, _) =
+            details_messages("https://o", "t", &job(1, "incomplete", Vec::new()), None);
+        assert_eq!(
+            plain,
+            "Details of test t (1):\nInvestigate: https://o/1#investigation"
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! Client for the openQA API, to tell what broke in a failed test
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OpenQAApiDetails {
+    /// Which backend ("opensuse.org", "suse.de") the instance belongs to
+    pub backend: String,
+    pub url: String,
+}
+
+#[derive(Clone)]
+pub struct OpenQAApi {
+    url: String,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Step {
+    pub num: Option<u32>,
+    pub result: Option<String>,
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Module {
+    pub name: String,
+    pub result: String,
+    #[serde(default)]
+    pub details: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub result: Option<String>,
+    #[serde(default)]
+    pub settings: HashMap<String, String>,
+    #[serde(default)]
+    pub testresults: Vec<Module>,
+}
+
+#[derive(Deserialize)]
+struct JobResponse {
+    job: Job,
+}
+
+#[derive(Deserialize)]
+struct JobsResponse {
+    jobs: Vec<Job>,
+}
+
+// Settings that make up a scenario
+const SCENARIO: [&str; 6] = ["DISTRI", "VERSION", "FLAVOR", "ARCH", "TEST", "MACHINE"];
+
+impl Job {
+    pub fn failed_modules(&self) -> Vec<&Module> {
+        self.testresults
+            .iter()
+            .filter(|x| x.result == "failed")
+            .collect()
+    }
+
+    /// Module and step number of the first failed step
+    pub fn first_failed_step(&self) -> Option<(&Module, &Step)> {
+        self.failed_modules().into_iter().find_map(|module| {
+            module
+                .details
+                .iter()
+                .find(|x| x.result.as_deref() == Some("fail"))
+                .map(|step| (module, step))
+        })
+    }
+}
+
+impl OpenQAApi {
+    pub fn new(url: &str) -> OpenQAApi {
+        OpenQAApi {
+            url: url.trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn get(&self, path: &str) -> Result<String> {
+        let mut res = self.client.get(&format!("{}{}", self.url, path)).send()?;
+        if !res.status().is_success() {
+            return Err(anyhow!("{} answered with HTTP {}", self.url, res.status()));
+        }
+        Ok(res.text()?)
+    }
+
+    /// Job with its test modules and their steps
+    pub fn details(&self, id: &str) -> Result<Job> {
+        parse_details(&self.get(&format!("/api/v1/jobs/{}/details", id))?)
+    }
+
+    /// The last finished job of the same scenario before this one
+    pub fn previous(&self, job: &Job) -> Result<Option<Job>> {
+        let jobs = parse_jobs(&self.get(&format!("/api/v1/jobs?{}", scenario_query(job)))?)?;
+        Ok(previous_job(jobs, job))
+    }
+}
+
+/// Finished jobs of the scenario of a job
+fn scenario_query(job: &Job) -> String {
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    for name in SCENARIO.iter() {
+        if let Some(value) = job.settings.get(*name) {
+            query.append_pair(&name.to_lowercase(), value);
+        }
+    }
+    query.append_pair("state", "done");
+    query.append_pair("limit", "20");
+    query.finish()
+}
+
+/// The latest of the jobs that ran before the job
+fn previous_job(jobs: Vec<Job>, job: &Job) -> Option<Job> {
+    jobs.into_iter()
+        .filter(|x| x.id < job.id)
+        .max_by_key(|x| x.id)
+}
+
+/// The answer of /api/v1/jobs/ID/details
+fn parse_details(json: &str) -> Result<Job> {
+    let res: JobResponse = serde_json::from_str(json)?;
+    Ok(res.job)
+}
+
+/// The answer of /api/v1/jobs
+fn parse_jobs(json: &str) -> Result<Vec<Job>> {
+    let res: JobsResponse = serde_json::from_str(json)?;
+    Ok(res.jobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Shortened answers of openqa.opensuse.org
+    const DETAILS: &str = r#"{"job": {
+        "id": 4711,
+        "name": "opensuse-Tumbleweed-DVD-x86_64-Build20240101-textmode@64bit",
+        "result": "failed",
+        "state": "done",
+        "settings": {"ARCH": "x86_64", "DISTRI": "opensuse", "FLAVOR": "DVD", "MACHINE": "64bit",
+                     "TEST": "textmode", "VERSION": "Tumbleweed", "BUILD": "20240101"},
+        "testresults": [
+            {"name": "boot_to_desktop", "result": "passed", "category": "boot", "details": [
+                {"num": 1, "result": "ok", "title": "wait_serial"}
+            ]},
+            {"name": "zypper_in", "result": "failed", "category": "console", "details": [
+                {"num": 1, "result": "ok", "title": "wait_serial"},
+                {"num": 2, "result": "fail", "title": "Soft Failed", "text": "zypper_in-2.txt"},
+                {"num": 3, "result": "fail"}
+            ]},
+            {"name": "yast2_lan", "result": "failed", "category": "console"},
+            {"name": "shutdown", "result": "none", "details": []}
+        ]
+    }}"#;
+
+    const JOBS: &str = r#"{"jobs": [
+        {"id": 4712, "result": "passed", "state": "done"},
+        {"id": 4698, "result": "passed", "state": "done", "settings": {"TEST": "textmode"}},
+        {"id": 4705, "result": "softfailed", "state": "done"},
+        {"id": 4711, "result": "failed", "state": "done"}
+    ]}"#;
+
+    #[test]
+    fn details_of_failed_job() {
+        let job = parse_details(DETAILS).unwrap();
+        assert_eq!(job.id, 4711);
+        assert_eq!(job.result.as_deref(), Some("failed"));
+        let failed: Vec<_> = job.failed_modules().iter().map(|x| &x.name).collect();
+        assert_eq!(failed, ["zypper_in", "yast2_lan"]);
+        let (module, step) = job.first_failed_step().unwrap();
+        assert_eq!(module.name, "zypper_in");
+        assert_eq!(step.num, Some(2));
+        assert_eq!(step.title.as_deref(), Some("Soft Failed"));
+
+        assert!(parse_details(r#"{"error": "Job does not exist"}"#).is_err());
+    }
+
+    #[test]
+    fn previous_job_of_scenario() {
+        let job = parse_details(DETAILS).unwrap();
+        let query = scenario_query(&job);
+        assert_eq!(
+            query,
+            "distri=opensuse&version=Tumbleweed&flavor=DVD&arch=x86_64&test=textmode&machine=64bit&state=done&limit=20"
+        );
+
+        let previous = previous_job(parse_jobs(JOBS).unwrap(), &job).unwrap();
+        assert_eq!(previous.id, 4705);
+        assert_eq!(previous.result.as_deref(), Some("softfailed"));
+        assert!(previous_job(parse_jobs(r#"{"jobs": []}"#).unwrap(), &job).is_none());
+    }
+}
@@ -0,0 +1,211 @@
+//! Subscription commands are answered with one summary per message. Every handler
+//! records its outcomes here and the ReplyHandler, added after all others, sends them.
+
+use crate::common::{escape_html, Notifier};
+
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Subscribed,
+    AlreadySubscribed,
+    Unsubscribed,
+    NotSubscribed,
+    /// Pasted in a room in preview mode
+    Preview,
+    Failed,
+}
+
+impl Outcome {
+    /// Sentence for a single item
+    pub fn describe(&self, item: &str) -> String {
+        match self {
+            Outcome::Subscribed => format!("Subscribing to {}", item),
+            Outcome::AlreadySubscribed => format!("Already subscribed to {}", item),
+            Outcome::Unsubscribed => format!("Unsubscribing room from {}", item),
+            Outcome::NotSubscribed => format!("Was not subscribed to {}", item),
+            Outcome::Preview => format!("{} (preview mode, use sub to subscribe)", item),
+            Outcome::Failed => format!("Sorry, I could not handle {}", item),
+        }
+    }
+
+    /// Heading in summaries
+    fn heading(&self) -> &'static str {
+        match self {
+            Outcome::Subscribed => "Subscribed",
+            Outcome::AlreadySubscribed => "Already subscribed",
+            Outcome::Unsubscribed => "Unsubscribed",
+            Outcome::NotSubscribed => "Was not subscribed",
+            Outcome::Preview => "Not subscribed (preview mode, use sub to subscribe)",
+            Outcome::Failed => "Failed",
+        }
+    }
+}
+
+const ORDER: [Outcome; 6] = [
+    Outcome::Subscribed,
+    Outcome::AlreadySubscribed,
+    Outcome::Unsubscribed,
+    Outcome::NotSubscribed,
+    Outcome::Preview,
+    Outcome::Failed,
+];
+
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub outcome: Outcome,
+    pub item: String,
+    /// Why it failed
+    pub reason: Option<String>,
+}
+
+/// Pending replies per room, until the message is handled completely
+#[derive(Clone, Default)]
+pub struct Replies {
+    pending: Arc<Mutex<HashMap<String, Vec<Reply>>>>,
+}
+
+impl Replies {
+    pub fn add(&self, room: &str, outcome: Outcome, item: &str) {
+        self.push(
+            room,
+            Reply {
+                outcome,
+                item: item.to_string(),
+                reason: None,
+            },
+        );
+    }
+
+    pub fn failed(&self, room: &str, item: &str, reason: &str) {
+        self.push(
+            room,
+            Reply {
+                outcome: Outcome::Failed,
+                item: item.to_string(),
+                reason: Some(reason.to_string()),
+            },
+        );
+    }
+
+    fn push(&self, room: &str, reply: Reply) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.entry(room.to_string()).or_default().push(reply);
+        }
+    }
+
+    pub fn take(&self, room: &str) -> Vec<Reply> {
+        self.pending
+            .lock()
+            .ok()
+            .and_then(|mut x| x.remove(room))
+            .unwrap_or_default()
+    }
+}
+
+fn single(reply: &Reply) -> String {
+    match &reply.reason {
+        Some(reason) => format!("Sorry, {}: {}", reply.item, reason),
+        None => reply.outcome.describe(&reply.item),
+    }
+}
+
+/// One sentence for a single reply, otherwise items grouped by outcome, as (plain, html)
+pub fn summary(replies: &[Reply]) -> (String, String) {
+    if let [reply] = replies {
+        let msg = single(reply);
+        let html = escape_html(&msg);
+        return (msg, html);
+    }
+
+    let mut plain = Vec::new();
+    let mut html = Vec::new();
+    for outcome in ORDER.iter() {
+        let group: Vec<_> = replies.iter().filter(|x| x.outcome == *outcome).collect();
+        if group.is_empty() {
+            continue;
+        }
+        let heading = format!("{} ({})", outcome.heading(), group.len());
+        if *outcome == Outcome::Failed {
+            let items: Vec<_> = group
+                .iter()
+                .map(|x| match &x.reason {
+                    Some(reason) => format!("{}: {}", x.item, reason),
+                    None => x.item.clone(),
+                })
+                .collect();
+            plain.push(format!("{}:\n- {}", heading, items.join("\n- ")));
+            let items: Vec<_> = items.iter().map(|x| escape_html(x)).collect();
+            html.push(format!(
+                "<strong>{}</strong>:<ul><li>{}</li></ul>",
+                heading,
+                items.join("</li><li>")
+            ));
+        } else {
+            let items: Vec<_> = group.iter().map(|x| x.item.as_str()).collect();
+            plain.push(format!("{}: {}", heading, items.join(", ")));
+            html.push(format!(
+                "<strong>{}</strong>: {}",
+                heading,
+                escape_html(&items.join(", "))
+            ));
+        }
+    }
+    (plain.join("\n"), html.join("<br>"))
+}
+
+#[derive(Clone)]
+pub struct ReplyHandler {
+    pub notifier: Notifier,
+}
+
+impl MessageHandler for ReplyHandler {
+    /// Sends what all handlers before collected for this message
+    fn handle_message(&mut self, _bot: &ActiveBot, message: &Message) -> HandleResult {
+        self.notifier.attachments.clear(&message.room);
+        let replies = self.notifier.replies.take(&message.room);
+        if !replies.is_empty() {
+            let (plain, html) = summary(&replies);
+            self.notifier
+                .send_html_message(&plain, &html, &message.room, MessageType::TextMessage);
+        }
+        HandleResult::ContinueHandling
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summaries_are_escaped() {
+        let reply = |outcome, item: &str, reason: Option<&str>| Reply {
+            outcome,
+            item: item.to_string(),
+            reason: reason.map(str::to_string),
+        };
+
+        let (plain, html) = summary(&[reply(Outcome::Failed, "<b>x", Some("no <i>"))]);
+        assert_eq!(plain, "Sorry, <b>x: no <i>");
+        assert_eq!(html, "Sorry, &lt;b&gt;x: no &lt;i&gt;");
+
+        let (plain, html) = summary(&[
+            reply(Outcome::Subscribed, "home:me/<a>", None),
+            reply(Outcome::Subscribed, "home:me/b", None),
+            reply(Outcome::Failed, "<b>x", Some("no <i>")),
+        ]);
+        assert_eq!(
+            plain,
+            "Subscribed (2): home:me/<a>, home:me/b\nFailed (1):\n- <b>x: no <i>"
+        );
+        assert_eq!(
+            html,
+            "<strong>Subscribed (2)</strong>: home:me/&lt;a&gt;, home:me/b<br>\
+             <strong>Failed (1)</strong>:<ul><li>&lt;b&gt;x: no &lt;i&gt;</li></ul>"
+        );
+    }
+}
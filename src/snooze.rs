@@ -0,0 +1,586 @@
+//! Muting rooms for a while (snooze) or every day (quiet hours). Notifications
+//! arriving meanwhile are held and sent as one digest when the mute ends.
+
+use crate::command::{self, Command};
+use crate::common::{prepend_prefix, Notifier, RoomSettings, RoomState};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, NaiveTime, Utc};
+use chrono_tz::Tz;
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+// How often ended mutes are looked for
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+const SNOOZE_USAGE: &str = "snooze [DURATION|until HH:MM|off]";
+const QUIET_USAGE: &str = "quiet [HH:MM-HH:MM|off]";
+const TIMEZONE_USAGE: &str = "timezone [NAME]";
+/// Longer snoozes are rather forgotten subscriptions
+const MAX_SNOOZE_MINUTES: i64 = 30 * 24 * 60;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuietHoursDetails {
+    pub room: String,
+    /// "HH:MM"
+    pub from: String,
+    pub to: String,
+    /// IANA name like "Europe/Berlin". Defaults to the time zone of the bot.
+    pub timezone: Option<String>,
+}
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [
+        (
+            SNOOZE_USAGE,
+            "Hold notifications for a while, e.g. \"snooze 2h\", and get them as one digest afterwards.",
+        ),
+        (
+            QUIET_USAGE,
+            "Show or set daily quiet hours, e.g. \"quiet 22:00-07:00\". Notifications are held like with snooze.",
+        ),
+        (
+            TIMEZONE_USAGE,
+            "Show or set the time zone of this room (e.g. Europe/Berlin), for quiet hours, snooze and the digest.",
+        ),
+    ];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+/// Makes the quiet hours of the config the settings of their rooms
+pub fn set_defaults(rooms: &RoomSettings, details: &[QuietHoursDetails]) -> Result<()> {
+    for x in details {
+        let hours = format!("{}-{}", x.from, x.to);
+        parse_quiet_hours(&hours)?;
+        rooms
+            .set_quiet_hours(&x.room, Some(&hours))
+            .map_err(|x| anyhow!(x))?;
+        if let Some(timezone) = &x.timezone {
+            parse_timezone(timezone)?;
+            rooms
+                .set_timezone(&x.room, timezone)
+                .map_err(|x| anyhow!(x))?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct QuietHours {
+    from: NaiveTime,
+    to: NaiveTime,
+}
+
+impl QuietHours {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            // Over midnight
+            time >= self.from || time < self.to
+        }
+    }
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| anyhow!("{} is no time (HH:MM)", time))
+}
+
+/// "HH:MM-HH:MM"
+fn parse_quiet_hours(text: &str) -> Result<QuietHours> {
+    let (from, to) = text
+        .split_once('-')
+        .ok_or_else(|| anyhow!("{} are no quiet hours (HH:MM-HH:MM)", text))?;
+    let hours = QuietHours {
+        from: parse_time(from.trim())?,
+        to: parse_time(to.trim())?,
+    };
+    if hours.from == hours.to {
+        return Err(anyhow!(
+            "{} are no quiet hours, they end when they start",
+            text
+        ));
+    }
+    Ok(hours)
+}
+
+fn parse_timezone(timezone: &str) -> Result<Tz> {
+    timezone
+        .parse()
+        .map_err(|_| anyhow!("{} is no time zone (like Europe/Berlin or UTC)", timezone))
+}
+
+/// The time zone of the bot: $TZ, or what /etc/localtime links to. UTC otherwise.
+fn local_timezone() -> Tz {
+    let name = std::env::var("TZ").ok().or_else(|| {
+        let link = std::fs::read_link("/etc/localtime").ok()?;
+        let link = link.to_string_lossy();
+        link.split_once("zoneinfo/").map(|(_, x)| x.to_string())
+    });
+    name.and_then(|x| x.trim_start_matches(':').parse().ok())
+        .unwrap_or(Tz::UTC)
+}
+
+/// Durations like "30m", "2h", "1d" or "1h30m", up to MAX_SNOOZE_MINUTES.
+/// Counted in minutes, as chrono panics on Durations out of its range.
+fn parse_duration(text: &str) -> Option<Duration> {
+    let mut minutes: i64 = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let n: i64 = number.parse().ok()?;
+        number.clear();
+        let factor = match c {
+            'd' => 24 * 60,
+            'h' => 60,
+            'm' => 1,
+            _ => return None,
+        };
+        minutes = minutes.checked_add(n.checked_mul(factor)?)?;
+    }
+    (number.is_empty() && minutes > 0 && minutes <= MAX_SNOOZE_MINUTES)
+        .then(|| Duration::minutes(minutes))
+}
+
+/// A notification as (plain, html)
+type Held = (String, String);
+
+/// What is saved of the mutes, so a restart doesn't lose held notifications
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct MuteState {
+    snoozed: HashMap<String, DateTime<Utc>>,
+    /// Held notifications per room
+    held: HashMap<String, Vec<Held>>,
+}
+
+/// Which rooms are muted and what they missed. Quiet hours and time zones are
+/// room settings. Changes are written to the state file, if there is one, and
+/// read back at startup. Cheap to clone.
+#[derive(Clone, Default)]
+pub struct Mutes {
+    rooms: RoomSettings,
+    path: Option<PathBuf>,
+    state: Arc<Mutex<MuteState>>,
+}
+
+impl Mutes {
+    pub fn new(rooms: RoomSettings) -> Mutes {
+        Mutes {
+            rooms,
+            ..Mutes::default()
+        }
+    }
+
+    /// Reads the mutes saved in path (if it exists yet) and saves all changes there
+    pub fn load(&mut self, path: PathBuf) -> Result<()> {
+        if path.exists() {
+            let saved: MuteState = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+            if let Ok(mut state) = self.state.lock() {
+                state.snoozed.extend(saved.snoozed);
+                state.held.extend(saved.held);
+            }
+        }
+        self.path = Some(path);
+        Ok(())
+    }
+
+    fn save(&self, state: &MuteState) {
+        let path = match &self.path {
+            Some(x) => x,
+            None => return,
+        };
+        let res = serde_json::to_string_pretty(state)
+            .map_err(anyhow::Error::from)
+            .and_then(|x| std::fs::write(path, x).map_err(anyhow::Error::from));
+        if let Err(x) = res {
+            println!("Could not save mutes to {:?}: {}", path, x);
+        }
+    }
+
+    fn quiet_hours(&self, room: &str) -> Option<QuietHours> {
+        self.rooms
+            .quiet_hours(room)
+            .and_then(|x| parse_quiet_hours(&x).ok())
+    }
+
+    fn is_muted(&self, state: &MuteState, room: &str, now: DateTime<Utc>) -> bool {
+        let snoozed = state.snoozed.get(room).is_some_and(|until| now < *until);
+        snoozed
+            || self
+                .quiet_hours(room)
+                .is_some_and(|x| x.contains(now.with_timezone(&self.timezone(room)).time()))
+    }
+
+    /// Keeps the notification for the digest, if the room is muted right now
+    pub fn hold(&self, room: &str, plain: &str, html: &str) -> bool {
+        let mut state = match self.state.lock() {
+            Ok(x) => x,
+            Err(_) => return false,
+        };
+        if !self.is_muted(&state, room, Utc::now()) {
+            return false;
+        }
+        state
+            .held
+            .entry(room.to_string())
+            .or_default()
+            .push((plain.to_string(), html.to_string()));
+        self.save(&state);
+        true
+    }
+
+    /// Time zone of the room: The one set for it, or the bot's
+    pub fn timezone(&self, room: &str) -> Tz {
+        self.rooms
+            .timezone(room)
+            .and_then(|x| parse_timezone(&x).ok())
+            .unwrap_or_else(local_timezone)
+    }
+
+    fn snooze(&self, room: &str, until: DateTime<Utc>) {
+        if let Ok(mut state) = self.state.lock() {
+            state.snoozed.insert(room.to_string(), until);
+            self.save(&state);
+        }
+    }
+
+    fn unsnooze(&self, room: &str) -> bool {
+        match self.state.lock() {
+            Ok(mut state) => {
+                let removed = state.snoozed.remove(room).is_some();
+                if removed {
+                    self.save(&state);
+                }
+                removed
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Held notifications of rooms that are not muted anymore. Ended snoozes are forgotten.
+    fn take_due(&self, now: DateTime<Utc>) -> Vec<(String, Vec<Held>)> {
+        let mut state = match self.state.lock() {
+            Ok(x) => x,
+            Err(_) => return Vec::new(),
+        };
+        let rooms: Vec<String> = state
+            .held
+            .keys()
+            .filter(|x| !self.is_muted(&state, x, now))
+            .cloned()
+            .collect();
+        let ended = state.snoozed.len();
+        state.snoozed.retain(|_, until| now < *until);
+        if rooms.is_empty() && ended == state.snoozed.len() {
+            return Vec::new();
+        }
+        let res = rooms
+            .into_iter()
+            .filter_map(|room| state.held.remove(&room).map(|x| (room, x)))
+            .collect();
+        self.save(&state);
+        res
+    }
+
+    /// Sends the digests of ended mutes in a separate thread
+    pub fn run(&self, notifier: Notifier) {
+        let mutes = self.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(CHECK_INTERVAL);
+            for (room, notifications) in mutes.take_due(Utc::now()) {
+                send_digest(&notifier, &room, notifications);
+            }
+        });
+    }
+
+    fn describe(&self, room: &str) -> String {
+        let now = Utc::now();
+        let timezone = self.timezone(room);
+        let snoozed = self
+            .state
+            .lock()
+            .ok()
+            .and_then(|x| x.snoozed.get(room).copied())
+            .filter(|until| now < *until);
+        let mut res = match snoozed {
+            Some(until) => format!(
+                "Snoozed until {}",
+                until.with_timezone(&timezone).format("%Y-%m-%d %H:%M %Z")
+            ),
+            None => "Not snoozed".to_string(),
+        };
+        if let Some(x) = self.quiet_hours(room) {
+            res += &format!(
+                ", quiet hours are {}-{} ({})",
+                x.from.format("%H:%M"),
+                x.to.format("%H:%M"),
+                timezone
+            );
+        }
+        res
+    }
+}
+
+impl RoomState for Mutes {
+    fn purge(&self, room: &str) {
+        if let Ok(mut state) = self.state.lock() {
+            let snoozed = state.snoozed.remove(room).is_some();
+            if state.held.remove(room).is_some() || snoozed {
+                self.save(&state);
+            }
+        }
+    }
+
+    fn migrate(&self, from: &str, to: &str) {
+        if let Ok(mut state) = self.state.lock() {
+            if let Some(until) = state.snoozed.remove(from) {
+                state.snoozed.insert(to.to_string(), until);
+            }
+            if let Some(notifications) = state.held.remove(from) {
+                state
+                    .held
+                    .entry(to.to_string())
+                    .or_default()
+                    .extend(notifications);
+            }
+            self.save(&state);
+        }
+    }
+
+    fn rooms(&self) -> Vec<String> {
+        match self.state.lock() {
+            Ok(state) => state
+                .snoozed
+                .keys()
+                .chain(state.held.keys())
+                .cloned()
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+fn send_digest(notifier: &Notifier, room: &str, notifications: Vec<Held>) {
+    println!(
+        "Sending {} held notification(s) to {}",
+        notifications.len(),
+        room
+    );
+    let heading = format!("While muted: {} notification(s)", notifications.len());
+    let plain = std::iter::once(heading.clone())
+        .chain(notifications.iter().map(|(plain, _)| plain.clone()))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let html = format!(
+        "<strong>{}</strong><ul>{}</ul>",
+        heading,
+        notifications
+            .iter()
+            .map(|(_, html)| format!("<li>{}</li>", html))
+            .collect::<String>()
+    );
+    notifier.send_html_message(&plain, &html, room, MessageType::TextMessage);
+}
+
+/// Answers "snooze", "quiet" and "timezone", everybody in a room may mute it
+#[derive(Clone)]
+pub struct SnoozeHandler {
+    pub prefix: Option<String>,
+    pub notifier: Notifier,
+    pub mutes: Mutes,
+    pub rooms: RoomSettings,
+}
+
+impl MessageHandler for SnoozeHandler {
+    fn handle_message(&mut self, _bot: &ActiveBot, message: &Message) -> HandleResult {
+        for command in command::parse(&command::message_text(message), self.prefix.as_deref())
+            .into_iter()
+            .flatten()
+        {
+            let answer = match command {
+                Command::Snooze(args) => self.snooze(args.as_deref(), &message.room),
+                Command::Quiet(args) => self.quiet(args.as_deref(), &message.room),
+                Command::Timezone(args) => self.timezone(args.as_deref(), &message.room),
+                _ => continue,
+            };
+            self.notifier
+                .send_message(&answer, &message.room, MessageType::TextMessage);
+        }
+        HandleResult::ContinueHandling
+    }
+}
+
+impl SnoozeHandler {
+    fn snooze(&self, args: Option<&str>, room: &str) -> String {
+        let now = Utc::now();
+        let timezone = self.mutes.timezone(room);
+        let until = match args {
+            None => return self.mutes.describe(room),
+            Some("off") => {
+                if !self.mutes.unsnooze(room) {
+                    return "This room is not snoozed".to_string();
+                }
+                // The digest follows right away, unless quiet hours go on
+                for (room, notifications) in self.mutes.take_due(now) {
+                    send_digest(&self.notifier, &room, notifications);
+                }
+                return "Not snoozed anymore".to_string();
+            }
+            Some(x) => match x.strip_prefix("until ") {
+                Some(time) => match parse_time(time.trim()) {
+                    Ok(time) => {
+                        let local = now.with_timezone(&timezone);
+                        let mut until = local.date().and_time(time).unwrap_or(local);
+                        // The next time it is that late
+                        if until <= local {
+                            until = until + Duration::days(1);
+                        }
+                        until.with_timezone(&Utc)
+                    }
+                    Err(x) => return format!("Sorry, {}. Usage: {}", x, SNOOZE_USAGE),
+                },
+                None => match parse_duration(x).and_then(|x| now.checked_add_signed(x)) {
+                    Some(until) => until,
+                    None => {
+                        return format!(
+                            "Sorry, {} is no duration (like 30m, 2h or 1d, at most 30d). Usage: {}",
+                            x, SNOOZE_USAGE
+                        )
+                    }
+                },
+            },
+        };
+        self.mutes.snooze(room, until);
+        format!(
+            "Snoozed until {}. Notifications are held until then and sent as one digest.",
+            until.with_timezone(&timezone).format("%Y-%m-%d %H:%M %Z")
+        )
+    }
+
+    fn quiet(&self, args: Option<&str>, room: &str) -> String {
+        let res = match args {
+            None => {
+                return match self.rooms.quiet_hours(room) {
+                    Some(x) => format!(
+                        "The quiet hours of this room are {} ({})",
+                        x,
+                        self.mutes.timezone(room)
+                    ),
+                    None => "This room has no quiet hours".to_string(),
+                }
+            }
+            Some("off") => self.rooms.set_quiet_hours(room, None),
+            Some(x) => match parse_quiet_hours(x) {
+                Ok(hours) => {
+                    let hours = format!(
+                        "{}-{}",
+                        hours.from.format("%H:%M"),
+                        hours.to.format("%H:%M")
+                    );
+                    self.rooms.set_quiet_hours(room, Some(&hours))
+                }
+                Err(x) => return format!("Sorry, {}. Usage: {}", x, QUIET_USAGE),
+            },
+        };
+        // Held notifications follow right away, if the room is not muted anymore
+        for (room, notifications) in self.mutes.take_due(Utc::now()) {
+            send_digest(&self.notifier, &room, notifications);
+        }
+        match res {
+            Ok(x) | Err(x) => x,
+        }
+    }
+
+    fn timezone(&self, args: Option<&str>, room: &str) -> String {
+        let timezone = match args {
+            None => return format!("This room uses the time zone {}", self.mutes.timezone(room)),
+            Some(x) => match parse_timezone(x) {
+                Ok(x) => x,
+                Err(x) => return format!("Sorry, {}. Usage: {}", x, TIMEZONE_USAGE),
+            },
+        };
+        match self.rooms.set_timezone(room, timezone.name()) {
+            Ok(x) | Err(x) => x,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30m"), Some(Duration::minutes(30)));
+        assert_eq!(parse_duration("2h"), Some(Duration::hours(2)));
+        assert_eq!(parse_duration("1d"), Some(Duration::days(1)));
+        assert_eq!(
+            parse_duration("1h30m"),
+            Some(Duration::hours(1) + Duration::minutes(30))
+        );
+        assert_eq!(parse_duration("1d2h3m"), Some(Duration::minutes(1563)));
+        assert_eq!(parse_duration("90m"), Some(Duration::minutes(90)));
+        assert_eq!(parse_duration("30d"), Some(Duration::days(30)));
+
+        for text in [
+            "",
+            "0m",
+            "30",
+            "h",
+            "2x",
+            "-1h",
+            "1.5h",
+            "2 h",
+            "m30",
+            "1hh",
+            "31d",
+            "29d24h1m",
+            "99999999d",
+            "9223372036854775807m",
+            "99999999999999999999d",
+        ] {
+            assert_eq!(parse_duration(text), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn quiet_hours() {
+        let hours = parse_quiet_hours("22:00-07:00").unwrap();
+        let at = |x| NaiveTime::parse_from_str(x, "%H:%M").unwrap();
+        assert!(hours.contains(at("22:00")));
+        assert!(hours.contains(at("03:00")));
+        assert!(!hours.contains(at("07:00")));
+        assert!(!hours.contains(at("12:00")));
+
+        let hours = parse_quiet_hours("12:00 - 13:30").unwrap();
+        assert!(hours.contains(at("12:59")));
+        assert!(!hours.contains(at("13:30")));
+
+        assert!(parse_quiet_hours("22:00").is_err());
+        assert!(parse_quiet_hours("08:00-08:00").is_err());
+        assert!(parse_quiet_hours("25:00-07:00").is_err());
+    }
+
+    #[test]
+    fn timezones() {
+        assert_eq!(parse_timezone("Europe/Berlin").unwrap(), Tz::Europe__Berlin);
+        assert_eq!(parse_timezone("UTC").unwrap(), Tz::UTC);
+        assert!(parse_timezone("+01:00").is_err());
+        assert!(parse_timezone("Mars/Olympus").is_err());
+
+        // Quiet hours follow daylight saving time
+        let hours = parse_quiet_hours("22:00-07:00").unwrap();
+        let winter: DateTime<Utc> = "2024-01-15T20:30:00Z".parse().unwrap();
+        let summer: DateTime<Utc> = "2024-07-15T20:30:00Z".parse().unwrap();
+        let berlin = Tz::Europe__Berlin;
+        assert!(!hours.contains(winter.with_timezone(&berlin).time()));
+        assert!(hours.contains(summer.with_timezone(&berlin).time()));
+    }
+}
@@ -0,0 +1,133 @@
+use crate::build_res;
+use crate::command::{self, Command, UrlArgs};
+use crate::common::{prepend_prefix, Notifier, Registry, RoomMode, RoomSettings};
+use crate::obs_api::ObsApi;
+use crate::submitrequests;
+
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+
+/// Looks up the status of a URL, as (plain, html)
+type StatusFn = fn(&ObsApi, &str) -> anyhow::Result<(String, String)>;
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [(
+        "status URL",
+        "Show the current build results of a package or project, or the state and reviews of a request.",
+    )];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+/// Answers "status URL" with what the OBS API currently says. Added after all
+/// subscribers, so it comes after their replies.
+#[derive(Clone)]
+pub struct StatusHandler {
+    pub prefix: Option<String>,
+    pub notifier: Notifier,
+    pub registry: Registry,
+    pub rooms: RoomSettings,
+    pub apis: Vec<ObsApi>,
+    /// Also show the status when subscribing
+    pub on_subscribe: bool,
+}
+
+impl MessageHandler for StatusHandler {
+    fn handle_message(&mut self, _bot: &ActiveBot, message: &Message) -> HandleResult {
+        let room = &message.room;
+        for command in command::parse(&command::message_text(message), self.prefix.as_deref())
+            .into_iter()
+            .flatten()
+        {
+            match command {
+                Command::Status(args) => self.send_status(&args, room, true),
+                Command::Subscribe(args) => match (self.rooms.mode(room), args.explicit) {
+                    // Preview is all a pasted URL gets
+                    (RoomMode::Preview, false) => self.send_status(&args, room, false),
+                    (RoomMode::Explicit, false) => {}
+                    _ if self.on_subscribe && args.target.is_none() => {
+                        self.send_status(&args, room, false)
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+        HandleResult::ContinueHandling
+    }
+}
+
+impl StatusHandler {
+    /// Queries in the background, to not block the chat. Errors are only reported
+    /// if the status was asked for.
+    fn send_status(&self, args: &UrlArgs, room: &str, report_errors: bool) {
+        if self.apis.is_empty() {
+            if report_errors {
+                self.notifier.send_message(
+                    "Sorry, status needs obs_api configured",
+                    room,
+                    MessageType::TextMessage,
+                );
+            }
+            return;
+        }
+        let backend = self.rooms.backend(room);
+        let resources = self.registry.resolve(&args.url, backend.as_deref());
+        if resources.is_empty() && report_errors {
+            self.notifier.send_message(
+                &format!("Sorry, none of my backends handles {}", args.url),
+                room,
+                MessageType::TextMessage,
+            );
+        }
+
+        for (backend, event_type, url) in resources {
+            let status: StatusFn = match event_type.as_str() {
+                "package" => build_res::status,
+                "request" => submitrequests::status,
+                _ => {
+                    if report_errors {
+                        let msg = format!(
+                            "Sorry, I can only show the status of packages and requests, not of {}",
+                            url
+                        );
+                        self.notifier
+                            .send_message(&msg, room, MessageType::TextMessage);
+                    }
+                    continue;
+                }
+            };
+            let api = match self.apis.iter().find(|x| x.backend() == backend) {
+                Some(x) => x.clone(),
+                None => {
+                    if report_errors {
+                        self.notifier.send_message(
+                            &format!("Sorry, I have no API access to {} configured", backend),
+                            room,
+                            MessageType::TextMessage,
+                        );
+                    }
+                    continue;
+                }
+            };
+
+            let notifier = self.notifier.clone();
+            let room = room.to_string();
+            std::thread::spawn(move || match status(&api, &url) {
+                Ok((plain, html)) => {
+                    notifier.send_html_message(&plain, &html, &room, MessageType::TextMessage)
+                }
+                Err(x) => {
+                    println!("Status of {}: {}", url, x);
+                    if report_errors {
+                        notifier.send_message(
+                            &format!("Sorry, no status for {}: {}", url, x),
+                            &room,
+                            MessageType::TextMessage,
+                        );
+                    }
+                }
+            });
+        }
+    }
+}
@@ -1,23 +1,33 @@
-use crate::common::{prepend_prefix, ConnectionDetails, MessageParseResult, Subscriber};
+use crate::command::{
+    looks_like_url, message_text, short_request_id, short_requests_by, url_segments,
+};
+use crate::common::{
+    escape_html, prepend_prefix, Activate, ConnectionDetails, Frontends, MessageParseResult,
+    Notifier, RoomSettings, Subscriber, SubscriptionKey,
+};
+use crate::glob::GlobIndex;
+use crate::obs_api::{ObsApi, RequestInfo};
 use anyhow::{anyhow, Result};
 use lapin::{
     message::{Delivery, DeliveryResult},
-    options::*,
     Connection, ConsumerDelegate,
 };
 use matrix_bot_api::handlers::{HandleResult, MessageHandler};
-use matrix_bot_api::{ActiveBot, MatrixBot, Message, MessageType};
-use serde::Deserialize;
+use matrix_bot_api::{ActiveBot, Message};
+use serde::{Deserialize, Serialize};
 
 use std::collections::hash_map::HashMap;
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::sync::{Arc, Mutex};
 
+const KEY_REQUEST_CREATE: &str = "obs.request.create";
 const KEY_REQUEST_CHANGE: &str = "obs.request.change";
 const KEY_REQUEST_STATECHANGE: &str = "obs.request.state_change";
 const KEY_REQUEST_DELETE: &str = "obs.request.delete";
 const KEY_REQUEST_COMMENT: &str = "obs.request.comment";
-const SUBNAMES: [&str; 4] = [
+const SUBNAMES: [&str; 5] = [
+    KEY_REQUEST_CREATE,
     KEY_REQUEST_CHANGE,
     KEY_REQUEST_STATECHANGE,
     KEY_REQUEST_DELETE,
@@ -26,12 +36,81 @@ const SUBNAMES: [&str; 4] = [
 
 #[derive(Debug, Clone, std::cmp::PartialEq, std::cmp::Eq, Hash)]
 struct RequestKey {
+    /// The request number, or the OBS login for all requests by a user
     id: String,
+    /// All requests created or handled by the login in id, including future ones
+    by_user: bool,
+}
+
+impl RequestKey {
+    fn request(id: &str) -> RequestKey {
+        RequestKey {
+            id: id.to_string(),
+            by_user: false,
+        }
+    }
+
+    fn by_user(login: &str) -> RequestKey {
+        RequestKey {
+            id: login.to_string(),
+            by_user: true,
+        }
+    }
+
+    /// All keys that are interested in an event of the request, the request itself first
+    fn matching(jsondata: &SubmitRequestInfo, changetype: &str) -> Vec<RequestKey> {
+        let mut keys = vec![RequestKey::request(&format!("{}", jsondata.number))];
+        // Requests by a user are new ones and state changes, made by or for them
+        if changetype == "created" || changetype == "changed" {
+            for login in [jsondata.author.as_deref(), jsondata.who.as_deref()]
+                .iter()
+                .flatten()
+            {
+                keys.push(RequestKey::by_user(login));
+            }
+        }
+        keys
+    }
+}
+
+/// How the request changed, by the routing key of the event
+fn change_type(routing_key: &str) -> Option<&'static str> {
+    if routing_key.contains(KEY_REQUEST_CREATE) {
+        Some("created")
+    } else if routing_key.contains(KEY_REQUEST_CHANGE) {
+        Some("changed by admin")
+    } else if routing_key.contains(KEY_REQUEST_STATECHANGE) {
+        Some("changed")
+    } else if routing_key.contains(KEY_REQUEST_DELETE) {
+        Some("deleted")
+    } else if routing_key.contains(KEY_REQUEST_COMMENT) {
+        Some("commented")
+    } else {
+        None
+    }
 }
 
 impl std::fmt::Display for RequestKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.id)
+        if self.by_user {
+            write!(f, "requests by {}", self.id)
+        } else {
+            write!(f, "{}", self.id)
+        }
+    }
+}
+
+impl SubscriptionKey for RequestKey {
+    fn url(&self, base_url: &str) -> String {
+        if self.by_user {
+            // The requests tab of the user page
+            return format!(
+                "{}/users/{}/requests",
+                base_url.trim_end_matches("/request/show"),
+                self.id
+            );
+        }
+        format!("{}/{}", base_url, self.id)
     }
 }
 
@@ -44,15 +123,22 @@ impl TryFrom<String> for RequestKey {
             return Err(());
         }
 
-        let parts: Vec<_> = line.split('/').collect();
-        if parts.len() < 3 {
-            return Err(());
+        if !looks_like_url(line) {
+            if let Some(login) = short_requests_by(line) {
+                return Ok(RequestKey::by_user(&login));
+            }
+            let id = short_request_id(line).ok_or(())?;
+            return Ok(RequestKey::request(&id));
         }
 
-        let mut iter = parts.iter().rev();
-        // These unwraps cannot fail, as there have to be at least 2 parts
-        let id = iter.next().unwrap().trim().to_string();
-        Ok(RequestKey { id })
+        // Also matches ".../request/show/ID/changes" and the like
+        match url_segments(line).as_slice() {
+            ["request", "show", id, ..] if id.chars().all(|c| c.is_ascii_digit()) => {
+                Ok(RequestKey::request(id))
+            }
+            ["users", login, "requests"] => Ok(RequestKey::by_user(login)),
+            _ => Err(()),
+        }
     }
 }
 
@@ -62,6 +148,14 @@ pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
             "OBS_REQUEST_URL",
             "Subscribe to a SR/MR. Get notification if state changes.",
         ),
+        (
+            "SR#ID",
+            "Same as OBS_REQUEST_URL, also as 'sr ID' or 'osc rq show ID'. Uses the backend of the room.",
+        ),
+        (
+            "sub requests by LOGIN",
+            "Subscribe to all requests an OBS user creates or changes the state of, also future ones.",
+        ),
         (
             "unsub OBS_REQUEST_URL",
             "Unsubscribe from a SR/MR. Get no more notifications.",
@@ -75,7 +169,7 @@ pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
     prepend_prefix(prefix, &without_prefix)
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 struct SubmitRequestInfo {
     state: String,
     number: i32,
@@ -92,53 +186,84 @@ struct SubmitRequestInfo {
 
 impl MessageHandler for Subscriber<RequestKey> {
     /// Will be called for every text message send to a room the bot is in
-    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
-        let res = self.handle_message_helper(bot, &message.body, &message.room);
+    fn handle_message(&mut self, _bot: &ActiveBot, message: &Message) -> HandleResult {
+        let res =
+            self.handle_message_helper(&message_text(message), &message.room, &message.sender);
 
         if res == MessageParseResult::SomethingForMe {
-            match self.register() {
-                Err(x) => {
-                    println!("Error while registering: {:?}", x);
-                }
-                Ok(consumer) => consumer.set_delegate(Box::new(self.clone())),
-            }
+            self.activate();
         }
         HandleResult::ContinueHandling
     }
 }
 
+impl Activate for Subscriber<RequestKey> {
+    fn activate(&self) {
+        self.register_delegate();
+    }
+}
+
 impl Subscriber<RequestKey> {
     fn generate_messages(&self, jsondata: SubmitRequestInfo, changetype: &str) -> (String, String) {
-        let mut commentfield = String::new();
+        // Linked OBS logins are mentioned, to ping them
+        let users = self.users();
+        let mut comment_plain = String::new();
+        let mut comment_html = String::new();
         if changetype == "commented" {
-            if jsondata.commenter.is_some() {
-                commentfield += jsondata.commenter.as_ref().unwrap();
-                commentfield += ": ";
+            if let Some(commenter) = &jsondata.commenter {
+                let (plain, html) = users.mention(commenter);
+                comment_plain += &plain;
+                comment_plain += ": ";
+                comment_html += &html;
+                comment_html += ": ";
+            }
+
+            if let Some(comment_body) = &jsondata.comment_body {
+                comment_plain += comment_body;
+                comment_html += comment_body;
             }
+        } else if let Some(comment) = &jsondata.comment {
+            comment_plain += comment;
+            comment_html += comment;
+        }
 
-            if jsondata.comment_body.is_some() {
-                commentfield += jsondata.comment_body.as_ref().unwrap();
+        // Who made the change (the commenter is already named) and whose request it is
+        let who = jsondata
+            .who
+            .as_deref()
+            .filter(|_| changetype != "commented");
+        let author = jsondata.author.as_deref().filter(|x| Some(*x) != who);
+        let mut people_plain = Vec::new();
+        let mut people_html = Vec::new();
+        for (label, login) in [("Author", author), ("Action by", who)] {
+            if let Some(login) = login {
+                let (plain, html) = users.mention(login);
+                people_plain.push(format!("{}: {}", label, plain));
+                people_html.push(format!("{}: {}", label, html));
             }
-        } else if jsondata.comment.is_some() {
-            commentfield += jsondata.comment.as_ref().unwrap();
         }
 
-        let plain = format!(
+        let mut plain = format!(
             "Request {} was {}. Status: {} ({})",
-            jsondata.number, changetype, jsondata.state, commentfield,
+            jsondata.number, changetype, jsondata.state, comment_plain,
         );
-        let html = format!(
-            "<a href={}>Request {}</a> was {}. Status <strong>{}</strong> {}",
-            format!("{}/{}", self.get_base_url(), jsondata.number,),
+        let mut html = format!(
+            "<a href={}/{}>Request {}</a> was {}. Status <strong>{}</strong> {}",
+            self.get_base_url(),
+            jsondata.number,
             jsondata.number,
             changetype,
             jsondata.state,
-            if commentfield.is_empty() {
+            if comment_html.is_empty() {
                 String::new()
             } else {
-                format!("<br>{}", commentfield)
+                format!("<br>{}", comment_html)
             }
         );
+        if !people_plain.is_empty() {
+            plain += &format!("\n{}", people_plain.join(", "));
+            html += &format!("<br>{}", people_html.join(", "));
+        }
 
         (plain, html)
     }
@@ -146,49 +271,35 @@ impl Subscriber<RequestKey> {
     fn delivery_wrapper(&self, delivery: Delivery) -> Result<()> {
         let data = std::str::from_utf8(&delivery.data)?;
         let jsondata: SubmitRequestInfo = serde_json::from_str(data)?;
-        let changetype;
-        if delivery.routing_key.as_str().contains(KEY_REQUEST_CHANGE) {
-            changetype = "changed by admin";
-        } else if delivery
-            .routing_key
-            .as_str()
-            .contains(KEY_REQUEST_STATECHANGE)
-        {
-            changetype = "changed";
-        } else if delivery.routing_key.as_str().contains(KEY_REQUEST_DELETE) {
-            changetype = "deleted";
-        } else if delivery.routing_key.as_str().contains(KEY_REQUEST_COMMENT) {
-            changetype = "commented";
-        } else {
-            return Err(anyhow!(
+        let changetype = change_type(delivery.routing_key.as_str()).ok_or_else(|| {
+            anyhow!(
                 "Changetype of SR event unknown: {}",
                 delivery.routing_key.as_str()
-            ));
-        }
+            )
+        })?;
 
-        let key = RequestKey {
-            id: format!("{}", jsondata.number),
-        };
+        let keys = RequestKey::matching(&jsondata, changetype);
+        let key = keys[0].clone();
 
-        let rooms;
+        let mut rooms = HashSet::new();
         if let Ok(subscriptions) = self.subscriptions.lock() {
-            // This is a message we are not subscribed to
-            if !subscriptions.contains_key(&key) {
-                return Ok(());
+            for key in &keys {
+                if let Some(x) = subscriptions.get(key) {
+                    rooms.extend(x.iter().cloned());
+                }
             }
-
-            rooms = subscriptions[&key].clone();
-        } else {
+        }
+        // This is a message we are not subscribed to
+        if rooms.is_empty() {
             return Ok(());
         }
 
         println!("Request got {}: {}", changetype, jsondata.number);
 
-        if let Ok(bot) = self.bot.lock() {
-            let (plain, html) = self.generate_messages(jsondata, changetype);
-            for room in &rooms {
-                bot.send_html_message(&plain, &html, room, MessageType::TextMessage);
-            }
+        let document = self.event_document(delivery.routing_key.as_str(), &key, &jsondata)?;
+        let (plain, html) = self.generate_messages(jsondata, changetype);
+        for room in &rooms {
+            self.notify(room, &plain, &html, &document);
         }
 
         Ok(())
@@ -198,11 +309,7 @@ impl Subscriber<RequestKey> {
 impl ConsumerDelegate for Subscriber<RequestKey> {
     fn on_new_delivery(&self, delivery: DeliveryResult) {
         if let Ok(Some(delivery)) = delivery {
-            if let Some(channel) = &self.channel {
-                let _ = channel
-                    .basic_ack(delivery.delivery_tag, BasicAckOptions::default())
-                    .wait();
-            }
+            self.ack_delivery(delivery.delivery_tag);
             match self.delivery_wrapper(delivery) {
                 Ok(_) => {}
                 Err(x) => println!("Error while getting Event: {:?}. Skipping to continue", x),
@@ -216,23 +323,87 @@ impl ConsumerDelegate for Subscriber<RequestKey> {
     }
 }
 
+/// Current state, actions and reviews of a request URL, as (plain, html)
+pub fn status(api: &ObsApi, url: &str) -> Result<(String, String)> {
+    let key = RequestKey::try_from(url.to_string()).map_err(|_| anyhow!("could not parse it"))?;
+    if key.by_user {
+        return Err(anyhow!("only single requests have a status"));
+    }
+    let request = api.request(&key.id)?;
+    Ok(status_messages(api.backend(), &request))
+}
+
+/// Everything of the request comes from the API, so all of it is escaped in the HTML
+fn status_messages(backend: &str, request: &RequestInfo) -> (String, String) {
+    let mut plain = vec![format!(
+        "Request {} on {} by {}: {} (by {} at {})",
+        request.id, backend, request.creator, request.state, request.who, request.when
+    )];
+    let mut html = format!(
+        "<strong>Request {} on {}</strong> by {}: <strong>{}</strong> (by {} at {})",
+        escape_html(&request.id),
+        escape_html(backend),
+        escape_html(&request.creator),
+        escape_html(&request.state),
+        escape_html(&request.who),
+        escape_html(&request.when)
+    );
+
+    // Only the first line, descriptions can be long
+    if let Some(description) = request.description.lines().next() {
+        plain.push(description.to_string());
+        html += &format!("<br><em>{}</em>", escape_html(description));
+    }
+
+    html += "<ul>";
+    for action in &request.actions {
+        let line = match (&action.source, &action.target) {
+            (Some(source), Some(target)) => {
+                format!("{} {} -> {}", action.action_type, source, target)
+            }
+            (None, Some(target)) => format!("{} {}", action.action_type, target),
+            _ => action.action_type.clone(),
+        };
+        plain.push(format!("- {}", line));
+        html += &format!("<li>{}</li>", escape_html(&line));
+    }
+    html += "</ul>";
+
+    if !request.reviews.is_empty() {
+        let reviews: Vec<_> = request
+            .reviews
+            .iter()
+            .map(|x| format!("{} ({})", x.by, x.state))
+            .collect();
+        plain.push(format!("Reviews: {}", reviews.join(", ")));
+        html += &format!("Reviews: {}", escape_html(&reviews.join(", ")));
+    }
+
+    (plain.join("\n"), html)
+}
+
 pub fn init(
-    bot: &mut MatrixBot,
+    frontends: &mut Frontends,
+    notifier: &Notifier,
+    rooms: &RoomSettings,
     details: &ConnectionDetails,
     conn: Connection,
     prefix: Option<String>,
     default_subs: &Option<Vec<(String, String)>>,
 ) -> Result<()> {
-    let activebot = bot.get_activebot_clone();
     let mut sub: Subscriber<RequestKey> = Subscriber {
         subtype: "request".to_string(),
         server_details: *details,
-        connection: conn,
-        channel: None,
+        connection: Some(conn),
+        channel: Arc::new(Mutex::new(None)),
         subnames: SUBNAMES.to_vec(),
-        bot: Arc::new(Mutex::new(activebot)),
+        notifier: Arc::new(Mutex::new(notifier.clone())),
         subscriptions: Arc::new(Mutex::new(HashMap::new())),
+        patterns: Arc::new(Mutex::new(GlobIndex::default())),
         prefix,
+        rooms: rooms.clone(),
+        api: None,
+        openqa_api: None,
     };
 
     match default_subs {
@@ -242,12 +413,101 @@ pub fn init(
             Ok(consumer) => {
                 consumer.set_delegate(Box::new(sub.clone()));
                 for (room, url) in subs {
-                    sub.subscribe_to_defaults(&url, &room);
+                    sub.subscribe_to_defaults(url, room);
                 }
             }
         },
     }
-    bot.add_handler(sub);
+    frontends.add_subscriber(sub);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::obs_api::{RequestAction, Review};
+
+    #[test]
+    fn requests_by_user() {
+        let subscription = RequestKey::try_from("requests by alice".to_string()).unwrap();
+        assert_eq!(
+            RequestKey::try_from("https://build.opensuse.org/users/alice/requests".to_string()),
+            Ok(subscription.clone())
+        );
+
+        // Requests created after subscribing have numbers nobody subscribed to
+        let event = |routing_key: &str, data: serde_json::Value| {
+            let jsondata: SubmitRequestInfo = serde_json::from_value(data).unwrap();
+            RequestKey::matching(&jsondata, change_type(routing_key).unwrap())
+        };
+        let created = event(
+            "opensuse.obs.request.create",
+            serde_json::json!({"number": 1001, "state": "new", "author": "alice"}),
+        );
+        assert!(created.contains(&subscription));
+        assert!(created.contains(&RequestKey::request("1001")));
+        // Accepted by them, or their own request accepted by somebody else
+        let accepted = event(
+            "opensuse.obs.request.state_change",
+            serde_json::json!({"number": 1002, "state": "accepted", "author": "bob", "who": "alice"}),
+        );
+        assert!(accepted.contains(&subscription));
+        let accepted = event(
+            "opensuse.obs.request.state_change",
+            serde_json::json!({"number": 1001, "state": "accepted", "author": "alice", "who": "bob"}),
+        );
+        assert!(accepted.contains(&subscription));
+
+        // Not comments, nor requests of others
+        let commented = event(
+            "opensuse.obs.request.comment",
+            serde_json::json!({"number": 1001, "state": "new", "author": "alice", "commenter": "alice"}),
+        );
+        assert!(!commented.contains(&subscription));
+        assert!(commented.contains(&RequestKey::request("1001")));
+        let others = event(
+            "opensuse.obs.request.create",
+            serde_json::json!({"number": 1003, "state": "new", "author": "bob"}),
+        );
+        assert!(!others.contains(&subscription));
+
+        assert_eq!(change_type("opensuse.obs.request.frobnicate"), None);
+    }
+
+    #[test]
+    fn status_is_escaped() {
+        let request = RequestInfo {
+            id: "1234".to_string(),
+            creator: "<me>".to_string(),
+            state: "review".to_string(),
+            who: "a&b".to_string(),
+            when: "2024-01-01T08:00:00".to_string(),
+            actions: vec![RequestAction {
+                action_type: "submit".to_string(),
+                source: Some("home:me/<pkg>".to_string()),
+                target: Some("openSUSE:Factory/pkg".to_string()),
+            }],
+            reviews: vec![Review {
+                state: "new".to_string(),
+                by: "\"group\"".to_string(),
+            }],
+            description: "Fix <script> & more\nSecond line".to_string(),
+        };
+        let (plain, html) = status_messages("build.example.org", &request);
+        assert_eq!(
+            plain,
+            "Request 1234 on build.example.org by <me>: review (by a&b at 2024-01-01T08:00:00)\n\
+             Fix <script> & more\n\
+             - submit home:me/<pkg> -> openSUSE:Factory/pkg\n\
+             Reviews: \"group\" (new)"
+        );
+        assert_eq!(
+            html,
+            "<strong>Request 1234 on build.example.org</strong> by &lt;me&gt;: <strong>review</strong> \
+             (by a&amp;b at 2024-01-01T08:00:00)<br><em>Fix &lt;script&gt; &amp; more</em>\
+             <ul><li>submit home:me/&lt;pkg&gt; -&gt; openSUSE:Factory/pkg</li></ul>\
+             Reviews: &quot;group&quot; (new)"
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use crate::command::{self, is_same_kind, BulkArgs, Command};
+use crate::common::{prepend_prefix, Notifier, Registry, RoomSettings, SubscriptionInfo};
+use crate::dm::{resolve_target, user_target, ME};
+use crate::list::{matches_filter, plural};
+use crate::reply::Outcome;
+
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How long "confirm" is accepted after asking
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+// Subscriptions named when asking, the rest is counted
+const SHOWN: usize = 10;
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [
+        (
+            "unsub all [TYPE] [FILTER]",
+            "Unsubscribe from everything (of a type, or matching the filter) on all backends.",
+        ),
+        (
+            "unsub PATTERN",
+            "Unsubscribe from everything matching, e.g. \"unsub home:me*\".",
+        ),
+        (
+            "confirm",
+            "Carry out the last unsub all or unsub PATTERN of yours.",
+        ),
+    ];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+/// A bulk unsubscription waiting for "confirm"
+#[derive(Clone)]
+struct Pending {
+    target: String,
+    subscriptions: Vec<SubscriptionInfo>,
+    asked: Instant,
+}
+
+/// Unsubscribes from many subscriptions at once, after asking for confirmation.
+/// The outcomes are part of the ReplyHandler's summary.
+#[derive(Clone)]
+pub struct UnsubscribeAllHandler {
+    pub prefix: Option<String>,
+    pub notifier: Notifier,
+    pub registry: Registry,
+    pub rooms: RoomSettings,
+    /// Per room and sender
+    pending: Arc<Mutex<HashMap<(String, String), Pending>>>,
+}
+
+/// The subscriptions of the type that match the filter, each of them if not given
+fn select(subscriptions: Vec<SubscriptionInfo>, args: &BulkArgs) -> Vec<SubscriptionInfo> {
+    subscriptions
+        .into_iter()
+        .filter(|x| {
+            args.kind
+                .as_deref()
+                .is_none_or(|kind| is_same_kind(kind, &x.event_type))
+        })
+        .filter(|x| {
+            args.filter
+                .as_deref()
+                .is_none_or(|filter| matches_filter(filter, &x.key))
+        })
+        .collect()
+}
+
+impl MessageHandler for UnsubscribeAllHandler {
+    fn handle_message(&mut self, _bot: &ActiveBot, message: &Message) -> HandleResult {
+        for command in command::parse(&command::message_text(message), self.prefix.as_deref())
+            .into_iter()
+            .flatten()
+        {
+            let answer = match command {
+                Command::UnsubscribeAll(args) => self.ask(&args, message),
+                Command::Confirm => match self.confirm(message) {
+                    Ok(()) => continue,
+                    Err(x) => x,
+                },
+                _ => continue,
+            };
+            self.notifier
+                .send_message(&answer, &message.room, MessageType::TextMessage);
+        }
+        HandleResult::ContinueHandling
+    }
+}
+
+impl UnsubscribeAllHandler {
+    pub fn new(
+        prefix: Option<String>,
+        notifier: Notifier,
+        registry: Registry,
+        rooms: RoomSettings,
+    ) -> Self {
+        UnsubscribeAllHandler {
+            prefix,
+            notifier,
+            registry,
+            rooms,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Remembers what would be removed and asks to confirm it
+    fn ask(&self, args: &BulkArgs, message: &Message) -> String {
+        let kinds = self.registry.event_types();
+        if let Some(kind) = &args.kind {
+            if !kinds.iter().any(|x| is_same_kind(kind, x)) {
+                return format!(
+                    "Sorry, I can only unsubscribe from all, {}",
+                    kinds
+                        .iter()
+                        .map(|x| plural(x))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+        }
+        if let Some(target) = &args.target {
+            if !self.notifier.is_known_target(target) {
+                return format!("Sorry, {} is not configured", target);
+            }
+            if let Err(x) = self
+                .rooms
+                .may_use_target(&message.room, &message.sender, target)
+            {
+                return format!("Sorry, {}", x);
+            }
+        }
+
+        let target = match &args.target {
+            Some(target) => resolve_target(target, &message.sender),
+            None => message.room.clone(),
+        };
+        let subscriptions = select(self.registry.list(&target), args);
+        if subscriptions.is_empty() {
+            return match &args.filter {
+                Some(filter) => format!("No subscriptions found matching {}", filter),
+                None => "No subscriptions found".to_string(),
+            };
+        }
+
+        let mut shown: Vec<_> = subscriptions
+            .iter()
+            .take(SHOWN)
+            .map(|x| format!("{} on {}", x.key, x.backend))
+            .collect();
+        if subscriptions.len() > SHOWN {
+            shown.push(format!("and {} more", subscriptions.len() - SHOWN));
+        }
+        let answer = format!(
+            "This unsubscribes {} from {} subscriptions: {}. Say \"{}confirm\" within {} minutes to do it.",
+            match args.target.as_deref() {
+                Some(ME) => "you",
+                Some(target) => target,
+                None => "this room",
+            },
+            subscriptions.len(),
+            shown.join(", "),
+            self.prefix.as_deref().unwrap_or(""),
+            CONFIRM_TIMEOUT.as_secs() / 60
+        );
+
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.insert(
+                (message.room.clone(), message.sender.clone()),
+                Pending {
+                    target,
+                    subscriptions,
+                    asked: Instant::now(),
+                },
+            );
+        }
+        answer
+    }
+
+    /// Unsubscribes from what was asked for, if it was the same user
+    fn confirm(&self, message: &Message) -> Result<(), String> {
+        let pending = {
+            let mut pending = self
+                .pending
+                .lock()
+                .map_err(|_| "Sorry, there was an internal error".to_string())?;
+            pending.remove(&(message.room.clone(), message.sender.clone()))
+        };
+        let pending = match pending {
+            Some(x) if x.asked.elapsed() <= CONFIRM_TIMEOUT => x,
+            _ => return Err("There is nothing of yours to confirm".to_string()),
+        };
+
+        for subscription in &pending.subscriptions {
+            let mut item = format!("{} on {}", subscription.key, subscription.backend);
+            if pending.target == user_target(&message.sender) {
+                item += " for you";
+            } else if pending.target != message.room {
+                item += &format!(" for {}", pending.target);
+            }
+            match self.registry.unsubscribe(subscription, &pending.target) {
+                Ok(_) => self
+                    .notifier
+                    .replies
+                    .add(&message.room, Outcome::Unsubscribed, &item),
+                Err(reason) => self.notifier.replies.failed(&message.room, &item, &reason),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selection() {
+        let subscription = |event_type: &str, key: &str| SubscriptionInfo {
+            backend: "opensuse.org".to_string(),
+            event_type: event_type.to_string(),
+            key: key.to_string(),
+            url: String::new(),
+            digest: false,
+        };
+        let subscriptions = vec![
+            subscription("package", "home:me/foo"),
+            subscription("package", "home:me:branches/bar"),
+            subscription("package", "openSUSE:Factory/foo"),
+            subscription("request", "1234"),
+        ];
+        let selected = |kind: Option<&str>, filter: Option<&str>| {
+            let args = BulkArgs {
+                kind: kind.map(str::to_string),
+                filter: filter.map(str::to_string),
+                target: None,
+            };
+            select(subscriptions.clone(), &args)
+                .into_iter()
+                .map(|x| x.key)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(selected(None, None).len(), 4);
+        assert_eq!(selected(Some("requests"), None), ["1234"]);
+        assert_eq!(
+            selected(Some("package"), Some("foo")),
+            ["home:me/foo", "openSUSE:Factory/foo"]
+        );
+        assert_eq!(
+            selected(None, Some("home:me*")),
+            ["home:me/foo", "home:me:branches/bar"]
+        );
+        assert_eq!(selected(None, Some("home:me")).len(), 2);
+        assert!(selected(Some("requests"), Some("home:me*")).is_empty());
+        assert!(selected(Some("projects"), None).is_empty());
+    }
+}
@@ -0,0 +1,334 @@
+//! Which Matrix user is behind an OBS login, to mention the people responsible
+//! for a request or a failing package in its notifications.
+
+use crate::command::{self, Command};
+use crate::common::{escape_html, prepend_prefix, Notifier, RoomSettings};
+use crate::glob::glob_match;
+use anyhow::Result;
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+use serde::Deserialize;
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MaintainerDetails {
+    /// "PROJECT/PACKAGE", '*' and '?' work like in patterns
+    pub package: String,
+    /// OBS logins or Matrix IDs
+    pub users: Vec<String>,
+}
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [
+        (
+            "link [obs:LOGIN @user:server]",
+            "Show the linked OBS logins, or link one to a Matrix user, who is then mentioned in its notifications.",
+        ),
+        ("unlink obs:LOGIN", "Mention the OBS login by name again."),
+    ];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+fn is_matrix_id(user: &str) -> bool {
+    user.starts_with('@') && user.contains(':')
+}
+
+/// Plain text and HTML mentioning a Matrix user
+fn pill(user: &str) -> (String, String) {
+    (
+        user.to_string(),
+        format!(
+            "<a href=\"https://matrix.to/#/{}\">{}</a>",
+            escape_html(user),
+            escape_html(user)
+        ),
+    )
+}
+
+/// OBS logins linked to Matrix IDs and the maintainers of packages. Links are
+/// written to the state file, if there is one, and read back at startup.
+/// Cheap to clone.
+#[derive(Clone, Default)]
+pub struct UserLinks {
+    maintainers: Arc<Vec<MaintainerDetails>>,
+    path: Option<PathBuf>,
+    links: Arc<Mutex<BTreeMap<String, String>>>,
+}
+
+impl UserLinks {
+    pub fn new(maintainers: Vec<MaintainerDetails>) -> UserLinks {
+        UserLinks {
+            maintainers: Arc::new(maintainers),
+            ..UserLinks::default()
+        }
+    }
+
+    /// Reads the links saved in path (if it exists yet) and saves all changes there
+    pub fn load(&mut self, path: PathBuf) -> Result<()> {
+        if path.exists() {
+            let saved: BTreeMap<String, String> =
+                serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+            if let Ok(mut links) = self.links.lock() {
+                links.extend(saved);
+            }
+        }
+        self.path = Some(path);
+        Ok(())
+    }
+
+    fn save(&self, links: &BTreeMap<String, String>) {
+        let path = match &self.path {
+            Some(x) => x,
+            None => return,
+        };
+        let res = serde_json::to_string_pretty(links)
+            .map_err(anyhow::Error::from)
+            .and_then(|x| std::fs::write(path, x).map_err(anyhow::Error::from));
+        if let Err(x) = res {
+            println!("Could not save user links to {:?}: {}", path, x);
+        }
+    }
+
+    fn link(&self, login: &str, user: &str) {
+        if let Ok(mut links) = self.links.lock() {
+            links.insert(login.to_string(), user.to_string());
+            self.save(&links);
+        }
+    }
+
+    fn unlink(&self, login: &str) {
+        if let Ok(mut links) = self.links.lock() {
+            if links.remove(login).is_some() {
+                self.save(&links);
+            }
+        }
+    }
+
+    /// The Matrix user the login is linked to
+    fn linked(&self, login: &str) -> Option<String> {
+        self.links.lock().ok().and_then(|x| x.get(login).cloned())
+    }
+
+    fn list(&self) -> Vec<(String, String)> {
+        self.links
+            .lock()
+            .map(|x| x.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+            .unwrap_or_default()
+    }
+
+    /// A pill for linked logins and Matrix IDs, the plain login otherwise
+    pub fn mention(&self, login: &str) -> (String, String) {
+        if is_matrix_id(login) {
+            return pill(login);
+        }
+        match self.linked(login) {
+            Some(user) => pill(&user),
+            None => (login.to_string(), escape_html(login)),
+        }
+    }
+
+    /// All of them, comma separated. None, if there are none.
+    pub fn mentions(&self, logins: &[String]) -> Option<(String, String)> {
+        if logins.is_empty() {
+            return None;
+        }
+        let (plain, html): (Vec<_>, Vec<_>) = logins.iter().map(|x| self.mention(x)).unzip();
+        Some((plain.join(", "), html.join(", ")))
+    }
+
+    /// The configured maintainers of a package, without duplicates
+    pub fn maintainers(&self, project: &str, package: &str) -> Vec<String> {
+        let name = format!("{}/{}", project, package);
+        let mut res: Vec<String> = Vec::new();
+        for x in self.maintainers.iter() {
+            if x.package == name || glob_match(&x.package, &name) {
+                for user in &x.users {
+                    if !res.contains(user) {
+                        res.push(user.clone());
+                    }
+                }
+            }
+        }
+        res
+    }
+}
+
+/// Everybody may link logins to their own Matrix ID, as long as they aren't
+/// linked to somebody else yet. Admins may change all links.
+fn check_allowed(
+    sender: &str,
+    admin: bool,
+    user: &str,
+    linked: Option<&str>,
+) -> Result<(), String> {
+    if admin {
+        return Ok(());
+    }
+    if user != sender {
+        return Err("Sorry, only admins can link OBS logins to other users".to_string());
+    }
+    match linked {
+        Some(x) if x != sender => Err(format!(
+            "Sorry, that OBS login is linked to {}, only admins can change that",
+            x
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Answers "link" and "unlink"
+#[derive(Clone)]
+pub struct LinkHandler {
+    pub prefix: Option<String>,
+    pub notifier: Notifier,
+    pub rooms: RoomSettings,
+}
+
+impl MessageHandler for LinkHandler {
+    fn handle_message(&mut self, _bot: &ActiveBot, message: &Message) -> HandleResult {
+        for command in command::parse(&command::message_text(message), self.prefix.as_deref())
+            .into_iter()
+            .flatten()
+        {
+            let answer = match command {
+                Command::Link(None) => self.describe(),
+                Command::Link(Some((login, user))) => match self.link(&login, &user, message) {
+                    Ok(x) | Err(x) => x,
+                },
+                Command::Unlink(login) => match self.unlink(&login, message) {
+                    Ok(x) | Err(x) => x,
+                },
+                _ => continue,
+            };
+            self.notifier
+                .send_message(&answer, &message.room, MessageType::TextMessage);
+        }
+        HandleResult::ContinueHandling
+    }
+}
+
+impl LinkHandler {
+    fn describe(&self) -> String {
+        let links = self.notifier.users.list();
+        if links.is_empty() {
+            return "No OBS logins are linked to Matrix users".to_string();
+        }
+        let links: Vec<_> = links
+            .iter()
+            .map(|(login, user)| format!("obs:{} is {}", login, user))
+            .collect();
+        format!("Linked OBS logins: {}", links.join(", "))
+    }
+
+    fn link(&self, login: &str, user: &str, message: &Message) -> Result<String, String> {
+        // Clients send mentions with the display name as text
+        let user = if is_matrix_id(user) {
+            user.to_string()
+        } else {
+            command::mentioned_users(message)
+                .pop()
+                .ok_or_else(|| format!("Sorry, {} is no Matrix user (@user:server)", user))?
+        };
+        let sender = &message.sender;
+        let linked = self.notifier.users.linked(login);
+        check_allowed(
+            sender,
+            self.rooms.is_admin(sender),
+            &user,
+            linked.as_deref(),
+        )?;
+        self.notifier.users.link(login, &user);
+        Ok(format!("OBS login {} is now linked to {}", login, user))
+    }
+
+    fn unlink(&self, login: &str, message: &Message) -> Result<String, String> {
+        let linked = self
+            .notifier
+            .users
+            .linked(login)
+            .ok_or_else(|| format!("OBS login {} is not linked", login))?;
+        let sender = &message.sender;
+        check_allowed(sender, self.rooms.is_admin(sender), &linked, None)?;
+        self.notifier.users.unlink(login);
+        Ok(format!("OBS login {} is not linked anymore", login))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mentions_of_logins() {
+        let users = UserLinks::default();
+        users.link("alice", "@alice:example.org");
+
+        assert_eq!(
+            users.mention("alice"),
+            (
+                "@alice:example.org".to_string(),
+                "<a href=\"https://matrix.to/#/@alice:example.org\">@alice:example.org</a>"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            users.mention("<bob>"),
+            ("<bob>".to_string(), "&lt;bob&gt;".to_string())
+        );
+        assert_eq!(users.mention("@carol:example.org").0, "@carol:example.org");
+
+        assert_eq!(users.mentions(&[]), None);
+        let (plain, _) = users
+            .mentions(&["alice".to_string(), "bob".to_string()])
+            .unwrap();
+        assert_eq!(plain, "@alice:example.org, bob");
+
+        users.unlink("alice");
+        assert_eq!(users.mention("alice").0, "alice");
+    }
+
+    #[test]
+    fn maintainers_of_packages() {
+        let users = UserLinks::new(vec![
+            MaintainerDetails {
+                package: "openSUSE:Factory/kernel-*".to_string(),
+                users: vec!["alice".to_string(), "@bob:example.org".to_string()],
+            },
+            MaintainerDetails {
+                package: "openSUSE:Factory/kernel-default".to_string(),
+                users: vec!["alice".to_string(), "carol".to_string()],
+            },
+        ]);
+        assert_eq!(
+            users.maintainers("openSUSE:Factory", "kernel-default"),
+            ["alice", "@bob:example.org", "carol"]
+        );
+        assert_eq!(
+            users.maintainers("openSUSE:Factory", "kernel-source"),
+            ["alice", "@bob:example.org"]
+        );
+        assert!(users.maintainers("openSUSE:Factory", "gcc").is_empty());
+        assert!(users.maintainers("home:me", "kernel-default").is_empty());
+    }
+
+    #[test]
+    fn who_may_link() {
+        let me = "@me:example.org";
+        let other = "@other:example.org";
+
+        // Their own ID, unless somebody else has the login
+        assert!(check_allowed(me, false, me, None).is_ok());
+        assert!(check_allowed(me, false, me, Some(me)).is_ok());
+        assert!(check_allowed(me, false, me, Some(other)).is_err());
+        // Never somebody else's
+        assert!(check_allowed(me, false, other, None).is_err());
+        assert!(check_allowed(me, false, other, Some(me)).is_err());
+        // Admins may do everything
+        assert!(check_allowed(me, true, other, Some(other)).is_ok());
+        assert!(check_allowed(me, true, me, Some(other)).is_ok());
+    }
+}
@@ -0,0 +1,302 @@
+use crate::command::{self, Command};
+use crate::common::{escape_html, prepend_prefix, Notifier, RoomSettings};
+use chrono::{DateTime, Local};
+use hmac::{Hmac, Mac};
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const TARGET_PREFIX: &str = "webhook:";
+const SIGNATURE_HEADER: &str = "X-ObsBot-Signature";
+const EVENT_HEADER: &str = "X-ObsBot-Event";
+// Number of deliveries kept per webhook
+const LOG_SIZE: usize = 50;
+
+fn default_retries() -> u32 {
+    3
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookDetails {
+    pub name: String,
+    pub url: String,
+    pub secret: Option<String>,
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+/// Subscription targets of the form "webhook:NAME" get events as JSON instead of chat messages
+pub fn is_webhook(room: &str) -> bool {
+    room.starts_with(TARGET_PREFIX)
+}
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [
+        (
+            "URL webhook:NAME",
+            "Subscribe a configured webhook instead of this room (works with unsub and list, too).",
+        ),
+        ("webhooks", "List all configured webhooks."),
+        (
+            "webhook log NAME",
+            "Show the latest deliveries to a webhook.",
+        ),
+    ];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+#[derive(Debug, Clone)]
+pub struct DeliveryLogEntry {
+    pub time: DateTime<Local>,
+    pub event: String,
+    pub key: String,
+    pub attempts: u32,
+    pub result: Result<u16, String>,
+}
+
+impl std::fmt::Display for DeliveryLogEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let result = match &self.result {
+            Ok(status) => format!("delivered (HTTP {})", status),
+            Err(x) => format!("FAILED ({})", x),
+        };
+        write!(
+            f,
+            "{} {} {}: {} after {} attempt(s)",
+            self.time.format("%Y-%m-%d %H:%M:%S"),
+            self.event,
+            self.key,
+            result,
+            self.attempts
+        )
+    }
+}
+
+type DeliveryLog = Arc<Mutex<HashMap<String, VecDeque<DeliveryLogEntry>>>>;
+
+/// All configured webhooks. Each one gets its own delivery thread, so a
+/// slow or dead endpoint does not hold up the others.
+#[derive(Clone)]
+pub struct Webhooks {
+    senders: HashMap<String, Sender<serde_json::Value>>,
+    log: DeliveryLog,
+}
+
+impl Webhooks {
+    pub fn new(details: Vec<WebhookDetails>) -> Webhooks {
+        let log: DeliveryLog = Arc::new(Mutex::new(HashMap::new()));
+        let mut senders = HashMap::new();
+
+        for hook in details {
+            let (tx, rx) = channel::<serde_json::Value>();
+            let hook_log = log.clone();
+            senders.insert(hook.name.clone(), tx);
+            std::thread::spawn(move || {
+                let client = reqwest::Client::new();
+                for document in rx {
+                    let entry = deliver(&client, &hook, &document);
+                    println!("Webhook {}: {}", hook.name, entry);
+                    if let Ok(mut log) = hook_log.lock() {
+                        let entries = log.entry(hook.name.clone()).or_insert_with(VecDeque::new);
+                        if entries.len() >= LOG_SIZE {
+                            entries.pop_front();
+                        }
+                        entries.push_back(entry);
+                    }
+                }
+            });
+        }
+
+        Webhooks { senders, log }
+    }
+
+    pub fn contains(&self, target: &str) -> bool {
+        self.senders
+            .contains_key(target.trim_start_matches(TARGET_PREFIX))
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.senders.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Queues the event document for delivery to the given "webhook:NAME" target
+    pub fn send(&self, target: &str, document: &serde_json::Value) {
+        let name = target.trim_start_matches(TARGET_PREFIX);
+        match self.senders.get(name) {
+            Some(tx) => {
+                if tx.send(document.clone()).is_err() {
+                    println!("Delivery thread of webhook {} is gone", name);
+                }
+            }
+            None => println!("Webhook {} is not configured, dropping event", name),
+        }
+    }
+
+    pub fn delivery_log(&self, name: &str) -> Vec<DeliveryLogEntry> {
+        match self.log.lock() {
+            Ok(log) => log
+                .get(name)
+                .map(|x| x.iter().cloned().collect())
+                .unwrap_or_default(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+fn signature(secret: &str, payload: &str) -> String {
+    // HMAC accepts keys of any length, so this can't fail
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(payload.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+fn deliver(
+    client: &reqwest::Client,
+    hook: &WebhookDetails,
+    document: &serde_json::Value,
+) -> DeliveryLogEntry {
+    let event = document["event"].as_str().unwrap_or("").to_string();
+    let key = document["key"].as_str().unwrap_or("").to_string();
+    let payload = document.to_string();
+
+    let mut attempts = 0;
+    let result = loop {
+        attempts += 1;
+        let mut request = client
+            .post(&hook.url)
+            .header("Content-Type", "application/json")
+            .header(EVENT_HEADER, event.as_str())
+            .body(payload.clone());
+        if let Some(secret) = &hook.secret {
+            request = request.header(SIGNATURE_HEADER, signature(secret, &payload));
+        }
+
+        let result = match request.send() {
+            Ok(response) if response.status().is_success() => Ok(response.status().as_u16()),
+            Ok(response) => Err(format!("HTTP {}", response.status())),
+            Err(x) => Err(x.to_string()),
+        };
+
+        if result.is_ok() || attempts > hook.retries {
+            break result;
+        }
+        // Back off a bit more with every failed attempt: 1s, 2s, 4s, ...
+        std::thread::sleep(Duration::from_secs(1 << (attempts - 1).min(6)));
+    };
+
+    DeliveryLogEntry {
+        time: Local::now(),
+        event,
+        key,
+        attempts,
+        result,
+    }
+}
+
+#[derive(Clone)]
+pub struct WebhookHandler {
+    pub prefix: Option<String>,
+    pub notifier: Notifier,
+    pub webhooks: Webhooks,
+    pub rooms: RoomSettings,
+}
+
+impl WebhookHandler {
+    /// Deliveries are only shown to the room the webhook belongs to
+    fn list_webhooks(&self, room: &str, sender: &str) -> (String, String) {
+        let names = self.webhooks.names();
+        if names.is_empty() {
+            let answer = "No webhooks configured".to_string();
+            return (answer.clone(), answer);
+        }
+
+        let mut plain = Vec::new();
+        let mut html = Vec::new();
+        for name in &names {
+            let target = format!("{}{}", TARGET_PREFIX, name);
+            let last = match self.rooms.may_use_target(room, sender, &target) {
+                Ok(()) => match self.webhooks.delivery_log(name).last() {
+                    Some(entry) => entry.to_string(),
+                    None => "no deliveries yet".to_string(),
+                },
+                Err(x) => x,
+            };
+            plain.push(format!("{}: {}", name, last));
+            html.push(format!(
+                "<tr> <td>{}</td> <td>{}</td></tr>",
+                escape_html(name),
+                escape_html(&last)
+            ));
+        }
+        (
+            format!("Configured webhooks:\n{}", plain.join("\n")),
+            format!(
+                "<strong>Configured webhooks:</strong><br><table>{}</table>",
+                html.join("\n")
+            ),
+        )
+    }
+
+    fn show_log(&self, name: &str, room: &str, sender: &str) -> (String, String) {
+        if !self.webhooks.contains(name) {
+            let answer = format!("Webhook {} is not configured", name);
+            let html = escape_html(&answer);
+            return (answer, html);
+        }
+        let target = format!("{}{}", TARGET_PREFIX, name);
+        if let Err(x) = self.rooms.may_use_target(room, sender, &target) {
+            let answer = format!("Sorry, {}", x);
+            let html = escape_html(&answer);
+            return (answer, html);
+        }
+
+        let entries = self.webhooks.delivery_log(name);
+        if entries.is_empty() {
+            let answer = format!("No deliveries to webhook {} yet", name);
+            let html = escape_html(&answer);
+            return (answer, html);
+        }
+
+        let lines: Vec<_> = entries.iter().rev().map(|x| x.to_string()).collect();
+        (
+            format!("Deliveries to webhook {}:\n{}", name, lines.join("\n")),
+            format!(
+                "<strong>Deliveries to webhook {}:</strong><br>{}",
+                escape_html(name),
+                lines
+                    .iter()
+                    .map(|x| escape_html(x))
+                    .collect::<Vec<_>>()
+                    .join("<br>")
+            ),
+        )
+    }
+}
+
+impl MessageHandler for WebhookHandler {
+    fn handle_message(&mut self, _bot: &ActiveBot, message: &Message) -> HandleResult {
+        for command in command::parse(&command::message_text(message), self.prefix.as_deref()) {
+            let (plain, html) = match command {
+                Ok(Command::Webhooks) => self.list_webhooks(&message.room, &message.sender),
+                Ok(Command::WebhookLog(name)) => {
+                    self.show_log(&name, &message.room, &message.sender)
+                }
+                // Usage errors are reported by the HelpHandler
+                _ => continue,
+            };
+
+            self.notifier
+                .send_html_message(&plain, &html, &message.room, MessageType::TextMessage);
+        }
+        HandleResult::ContinueHandling
+    }
+}